@@ -0,0 +1,462 @@
+//! Types for copying files into and out of containers as `tar` archives.
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+
+use anyhow::{anyhow, Context};
+use bytes::{Buf, Bytes};
+use futures_util::future;
+use futures_util::stream::{self, Stream, StreamExt, TryStreamExt};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio_util::codec::{BytesCodec, FramedRead};
+use tracing::{debug, warn};
+
+use crate::error::Error;
+
+/// The `tar` binary used for packing and unpacking archives.
+pub const TAR_BIN: &str = "tar";
+
+/// Maximum number of symbolic links followed while resolving a path inside a root filesystem.
+const MAX_SYMLINKS: usize = 40;
+
+/// A stream of bytes making up a `tar` archive.
+pub type ArchiveStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + 'static>>;
+
+/// A spawned `tar` process which extracts an archive written to its `stdin`.
+#[derive(Debug)]
+pub struct ArchiveSink {
+    child: Child,
+    stdin: ChildStdin,
+    staging: Option<Staging>,
+}
+
+/// A directory an archive is extracted into first, before its entries are moved into a root
+/// filesystem.
+#[derive(Debug)]
+struct Staging {
+    dir: TempDir,
+    rootfs: PathBuf,
+    dest_dir: PathBuf,
+}
+
+impl ArchiveSink {
+    /// Writes the incoming `tar` archive `stream` to the extracting process and waits for it to
+    /// complete.
+    ///
+    /// Returns `Err` if the stream yielded an error, an I/O error occurred, or if `tar` exited
+    /// with a non-zero status.
+    pub async fn write_from<S, B, E>(mut self, mut stream: S) -> anyhow::Result<()>
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: Buf,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        while let Some(mut buf) = stream.next().await.transpose()? {
+            let chunk = buf.to_bytes();
+            self.stdin.write_all(&chunk).await?;
+        }
+
+        // Close `stdin` so `tar` observes the end of the archive.
+        drop(self.stdin);
+
+        let output = self.child.wait_with_output().await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8(output.stderr)?;
+            return Err(anyhow!("failed to extract archive: [{}]", stderr));
+        }
+
+        if let Some(staging) = self.staging {
+            tokio::task::spawn_blocking(move || {
+                merge_into_root(staging.dir.path(), &staging.rootfs, &staging.dest_dir)
+            })
+            .await??;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the incoming archive `stream` to a new file at `path`, e.g. to hand it to a tool which
+/// cannot read from a pipe.
+///
+/// Returns `Err` if the stream yielded an error or more than `max_size` bytes, or if an I/O
+/// error occurred.
+pub async fn spool<S, B, E>(mut stream: S, path: &Path, max_size: u64) -> anyhow::Result<()>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: Buf,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut size = 0;
+    while let Some(mut buf) = stream.next().await.transpose()? {
+        let chunk = buf.to_bytes();
+        size += chunk.len() as u64;
+        if size > max_size {
+            let msg = format!("archive is larger than {} bytes", max_size);
+            return Err(Error::InvalidSpec(msg).into());
+        }
+
+        file.write_all(&chunk).await?;
+    }
+
+    file.flush().await?;
+    Ok(())
+}
+
+/// Spawns the given `tar` command and streams the archive it writes to `stdout`.
+///
+/// If `tar` fails, the stream ends with an error carrying its `stderr` once the archive it has
+/// written so far has been streamed, since the response has already begun by then.
+///
+/// Returns `Err` if the process could not be spawned.
+pub fn pack(mut cmd: Command) -> anyhow::Result<ArchiveStream> {
+    debug!("spawning archive command: {:?}", cmd);
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .context("`tar` stdout was not captured")?;
+    let mut stderr = child
+        .stderr
+        .take()
+        .context("`tar` stderr was not captured")?;
+
+    // Drain `stderr` and reap the child in the background, so neither pipe can fill up.
+    let status = tokio::spawn(async move {
+        let mut message = String::new();
+        let _ = stderr.read_to_string(&mut message).await;
+        match child.await {
+            Ok(status) if status.success() => None,
+            Ok(status) => {
+                warn!("`tar` exited with status {}: {}", status, message.trim());
+                let msg = format!("`tar` exited with status {}: {}", status, message.trim());
+                Some(io::Error::new(io::ErrorKind::Other, msg))
+            }
+            Err(e) => {
+                warn!("failed to wait on `tar`: {}", e);
+                Some(e)
+            }
+        }
+    });
+
+    let failure = stream::once(status).filter_map(|result| {
+        future::ready(match result {
+            Ok(error) => error.map(Err),
+            Err(e) => Some(Err(io::Error::new(io::ErrorKind::Other, e.to_string()))),
+        })
+    });
+
+    let stream = FramedRead::new(stdout, BytesCodec::new()).map_ok(|bytes| bytes.freeze());
+    Ok(Box::pin(stream.chain(failure)))
+}
+
+/// Spawns the given `tar` command and returns a sink for writing the archive to its `stdin`.
+///
+/// Returns `Err` if the process could not be spawned.
+pub fn unpack(mut cmd: Command) -> anyhow::Result<ArchiveSink> {
+    debug!("spawning extract command: {:?}", cmd);
+
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdin = child.stdin.take().context("`tar` stdin was not captured")?;
+    Ok(ArchiveSink {
+        child,
+        stdin,
+        staging: None,
+    })
+}
+
+/// Spawns `tar` to extract an archive into the directory `dest_dir` inside the host directory
+/// `rootfs`, which has already been resolved with [`resolve_in_root`].
+///
+/// The archive is extracted into a new directory in `staging_dir` first, which must be on the
+/// same filesystem as `rootfs`. Its entries are then moved into place without following any
+/// symbolic links out of `rootfs`, which `tar` would do for links already present there.
+///
+/// Returns `Err` if the staging directory could not be created, or if `tar` could not be spawned.
+pub fn unpack_into_root(
+    tar: impl AsRef<OsStr>,
+    rootfs: &Path,
+    dest_dir: &Path,
+    staging_dir: &Path,
+) -> anyhow::Result<ArchiveSink> {
+    let dir = tempfile::tempdir_in(staging_dir)?;
+    let mut sink = unpack(unpack_command(tar, dir.path()))?;
+    sink.staging = Some(Staging {
+        dir,
+        rootfs: rootfs.to_owned(),
+        dest_dir: dest_dir.to_owned(),
+    });
+    Ok(sink)
+}
+
+/// Returns a `tar` command which archives `target` relative to the `base_dir` directory.
+pub fn pack_command(tar: impl AsRef<OsStr>, base_dir: &Path, target: &Path) -> Command {
+    let mut cmd = Command::new(tar);
+    cmd.arg("--create")
+        .args(&["--file", "-"])
+        .arg("--directory")
+        .arg(base_dir)
+        .arg("--")
+        .arg(target);
+    cmd
+}
+
+/// Returns a `tar` command which extracts an archive from `stdin` into the `dest_dir` directory.
+pub fn unpack_command(tar: impl AsRef<OsStr>, dest_dir: &Path) -> Command {
+    let mut cmd = Command::new(tar);
+    cmd.arg("--extract")
+        .args(&["--file", "-"])
+        .arg("--no-same-owner")
+        .arg("--directory")
+        .arg(dest_dir);
+    cmd
+}
+
+/// Lexically normalizes a container path into a path relative to the container root.
+///
+/// Any `..` components are resolved against the root, so the result never escapes it.
+///
+/// Returns `Err` if the path is empty or not absolute.
+pub fn normalize(path: &str) -> anyhow::Result<PathBuf> {
+    let path = Path::new(path);
+    if !path.has_root() {
+        let msg = format!("path `{}` must be absolute", path.display());
+        return Err(Error::InvalidSpec(msg).into());
+    }
+
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        normalized.push(".");
+    }
+
+    Ok(normalized)
+}
+
+/// Splits a path relative to the container root into its parent directory and final component.
+pub fn split(path: &Path) -> (&Path, &OsStr) {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _ => (Path::new(""), OsStr::new(".")),
+    }
+}
+
+/// Resolves the container path `path` against the host directory `rootfs`, treating `rootfs` as
+/// if it were `/`.
+///
+/// Symbolic links are followed and resolved relative to `rootfs` rather than the host, so neither
+/// `..` components nor malicious links inside the container can escape it. The final path
+/// component is only followed if `follow_last` is `true`, e.g. when it names a target directory.
+///
+/// Returns `Err` if the path is not absolute, too many links were encountered, or an I/O error
+/// occurred.
+pub fn resolve_in_root(rootfs: &Path, path: &str, follow_last: bool) -> anyhow::Result<PathBuf> {
+    let mut pending: Vec<PathBuf> = normalize(path)?
+        .components()
+        .rev()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .collect();
+
+    let mut resolved = PathBuf::new();
+    let mut links_followed = 0;
+
+    while let Some(component) = pending.pop() {
+        if component == Path::new(".") {
+            continue;
+        } else if component == Path::new("..") {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&component);
+        let is_last = pending.is_empty();
+
+        match std::fs::symlink_metadata(rootfs.join(&candidate)) {
+            Ok(meta) if meta.file_type().is_symlink() && (follow_last || !is_last) => {
+                links_followed += 1;
+                if links_followed > MAX_SYMLINKS {
+                    let msg = format!("too many levels of symbolic links in `{}`", path);
+                    return Err(Error::InvalidSpec(msg).into());
+                }
+
+                let target = std::fs::read_link(rootfs.join(&candidate))?;
+                if target.has_root() {
+                    resolved = PathBuf::new();
+                }
+
+                pending.extend(
+                    target
+                        .components()
+                        .rev()
+                        .filter(|c| !matches!(c, Component::RootDir | Component::Prefix(_)))
+                        .map(|c| PathBuf::from(c.as_os_str())),
+                );
+            }
+            Ok(_) => resolved = candidate,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => resolved = candidate,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(rootfs.join(resolved))
+}
+
+/// Moves the extracted entries in `src_dir` into the directory `dest_dir` inside the host
+/// directory `rootfs`.
+///
+/// Existing directories are merged into, following symbolic links to directories relative to
+/// `rootfs` like [`resolve_in_root`], while any other existing entries are replaced.
+///
+/// Returns `Err` if a directory would be replaced by a non-directory, or if an I/O error occurred.
+fn merge_into_root(src_dir: &Path, rootfs: &Path, dest_dir: &Path) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(src_dir)? {
+        let entry = entry?;
+        let src = entry.path();
+        let dest = dest_dir.join(entry.file_name());
+        let src_is_dir = entry.file_type()?.is_dir();
+
+        match std::fs::symlink_metadata(&dest) {
+            Ok(meta) if src_is_dir && meta.is_dir() => {
+                merge_into_root(&src, rootfs, &dest)?;
+                continue;
+            }
+            Ok(meta) if src_is_dir && meta.file_type().is_symlink() => {
+                let rel = dest.strip_prefix(rootfs)?;
+                let path = Path::new("/").join(rel);
+                let path = path
+                    .to_str()
+                    .ok_or_else(|| anyhow!("path `{}` is not valid UTF-8", path.display()))?;
+
+                let resolved = resolve_in_root(rootfs, path, true)?;
+                match std::fs::symlink_metadata(&resolved) {
+                    Ok(meta) if meta.is_dir() => {
+                        merge_into_root(&src, rootfs, &resolved)?;
+                        continue;
+                    }
+                    _ => std::fs::remove_file(&dest)?,
+                }
+            }
+            Ok(meta) if meta.is_dir() => {
+                let msg = format!(
+                    "cannot replace directory `{}` with a non-directory",
+                    dest.strip_prefix(rootfs).unwrap_or(&dest).display()
+                );
+                return Err(Error::InvalidSpec(msg).into());
+            }
+            Ok(_) => std::fs::remove_file(&dest)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        std::fs::rename(&src, &dest)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn normalizes_absolute_paths() {
+        assert_eq!(normalize("/").unwrap(), Path::new("."));
+        assert_eq!(normalize("/etc/hosts").unwrap(), Path::new("etc/hosts"));
+        assert_eq!(normalize("/etc/./hosts").unwrap(), Path::new("etc/hosts"));
+        assert_eq!(normalize("/../../etc").unwrap(), Path::new("etc"));
+        assert_eq!(normalize("/var/../etc/..").unwrap(), Path::new("."));
+    }
+
+    #[test]
+    fn rejects_relative_paths() {
+        assert!(normalize("etc/hosts").is_err());
+        assert!(normalize("").is_err());
+    }
+
+    #[test]
+    fn resolves_symlinks_inside_root() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("usr/lib")).unwrap();
+        symlink("usr/lib", root.path().join("lib")).unwrap();
+        symlink("/etc", root.path().join("escape")).unwrap();
+        symlink("../../..", root.path().join("usr/lib/up")).unwrap();
+
+        let resolved = resolve_in_root(root.path(), "/lib/libc.so", false).unwrap();
+        assert_eq!(resolved, root.path().join("usr/lib/libc.so"));
+
+        let resolved = resolve_in_root(root.path(), "/escape/passwd", false).unwrap();
+        assert_eq!(resolved, root.path().join("etc/passwd"));
+
+        let resolved = resolve_in_root(root.path(), "/usr/lib/up/etc/shadow", false).unwrap();
+        assert_eq!(resolved, root.path().join("etc/shadow"));
+    }
+
+    #[test]
+    fn follows_final_symlink_only_if_requested() {
+        let root = tempfile::tempdir().unwrap();
+        symlink("/etc", root.path().join("escape")).unwrap();
+
+        let resolved = resolve_in_root(root.path(), "/escape", false).unwrap();
+        assert_eq!(resolved, root.path().join("escape"));
+
+        let resolved = resolve_in_root(root.path(), "/escape", true).unwrap();
+        assert_eq!(resolved, root.path().join("etc"));
+    }
+
+    #[test]
+    fn splits_relative_paths() {
+        assert_eq!(
+            split(Path::new("etc/hosts")),
+            (Path::new("etc"), OsStr::new("hosts"))
+        );
+        assert_eq!(split(Path::new("etc")), (Path::new(""), OsStr::new("etc")));
+        assert_eq!(split(Path::new(".")), (Path::new(""), OsStr::new(".")));
+    }
+
+    #[tokio::test]
+    async fn ends_stream_with_tar_errors() {
+        let root = tempfile::tempdir().unwrap();
+        let cmd = pack_command(TAR_BIN, root.path(), Path::new("missing"));
+        let results: Vec<_> = pack(cmd).unwrap().collect().await;
+
+        let e = results.last().unwrap().as_ref().unwrap_err();
+        assert!(e.to_string().contains("missing"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_symlink_loops() {
+        let root = tempfile::tempdir().unwrap();
+        symlink("b", root.path().join("a")).unwrap();
+        symlink("a", root.path().join("b")).unwrap();
+
+        assert!(resolve_in_root(root.path(), "/a/file", false).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! Authorization of REST API clients.
+
+use std::fmt::{self, Debug, Display, Formatter};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use warp::{Filter, Rejection};
+
+/// Prefix of the `Authorization` header value carrying a bearer token.
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// A permission granted to an API client.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+pub enum Scope {
+    /// Inspect containers, stored images and engine metrics.
+    #[serde(rename = "containers:read")]
+    ContainersRead,
+    /// Create, modify and delete containers.
+    #[serde(rename = "containers:write")]
+    ContainersWrite,
+    /// Pull images from remote registries, import them, and remove them from the image store.
+    #[serde(rename = "images:pull")]
+    ImagesPull,
+    /// List, store and remove credentials for remote registries.
+    #[serde(rename = "registries:auth")]
+    RegistriesAuth,
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Scope::ContainersRead => f.write_str("containers:read"),
+            Scope::ContainersWrite => f.write_str("containers:write"),
+            Scope::ImagesPull => f.write_str("images:pull"),
+            Scope::RegistriesAuth => f.write_str("registries:auth"),
+        }
+    }
+}
+
+/// A coarse access level granted to an API client, expanding to a set of [`Scope`]s.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    /// Inspect containers and engine metrics only.
+    ReadOnly,
+    /// Full control over containers and images.
+    Full,
+}
+
+impl Access {
+    /// Returns the scopes granted by this access level.
+    pub fn scopes(self) -> &'static [Scope] {
+        match self {
+            Access::ReadOnly => &[Scope::ContainersRead],
+            Access::Full => &[
+                Scope::ContainersRead,
+                Scope::ContainersWrite,
+                Scope::ImagesPull,
+                Scope::RegistriesAuth,
+            ],
+        }
+    }
+}
+
+/// An authenticated API client, attached to the requests it makes.
+///
+/// Requests without a principal, e.g. those received over a Unix domain socket, are not
+/// restricted.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Principal {
+    /// A human-readable name identifying the client.
+    pub name: String,
+    /// The scopes granted to the client.
+    pub scopes: Vec<Scope>,
+}
+
+impl Principal {
+    /// Returns whether the client has been granted `scope`.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// A set of bearer tokens accepted by the REST API, loaded from a token file.
+///
+/// The token file is a TOML document listing each token with a name and its granted scopes:
+///
+/// ```toml
+/// [[tokens]]
+/// name = "ci"
+/// token = "3f9c2b7e51d84a0c"
+/// scopes = ["containers:read", "images:pull"]
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TokenStore {
+    #[serde(default)]
+    tokens: Vec<Token>,
+}
+
+impl TokenStore {
+    /// Loads the tokens from the TOML file at `path`.
+    ///
+    /// Returns `Err` if the file could not be read or parsed, or contains an empty or duplicate
+    /// token.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read token file `{}`", path.display()))?;
+        let store: TokenStore = toml::from_str(&text)
+            .with_context(|| format!("failed to parse token file `{}`", path.display()))?;
+
+        for (i, token) in store.tokens.iter().enumerate() {
+            if token.token.is_empty() {
+                return Err(anyhow!("token `{}` is empty", token.name));
+            } else if store.tokens[..i].iter().any(|t| t.token == token.token) {
+                return Err(anyhow!("token `{}` is a duplicate", token.name));
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// Returns the principal for the given `Authorization` header value.
+    ///
+    /// Returns `Err` if the header is missing, is not a bearer token, or the token is unknown.
+    fn authenticate(&self, header: Option<&str>) -> Result<Principal, Unauthorized> {
+        let token = header
+            .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+            .ok_or(Unauthorized::MissingToken)?;
+
+        // Compare against every token to avoid leaking which one matched through timing.
+        let mut found = None;
+        for entry in &self.tokens {
+            if constant_time_eq(entry.token.as_bytes(), token.trim().as_bytes()) {
+                found = Some(entry);
+            }
+        }
+
+        found
+            .map(|entry| Principal {
+                name: entry.name.clone(),
+                scopes: entry.scopes.clone(),
+            })
+            .ok_or(Unauthorized::InvalidToken)
+    }
+}
+
+impl Debug for TokenStore {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let names = self.tokens.iter().map(|token| &token.name);
+        f.debug_struct("TokenStore")
+            .field("tokens", &names.collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// A bearer token entry in the token file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Token {
+    /// A human-readable name identifying the token holder.
+    name: String,
+    /// The secret token value.
+    token: String,
+    /// The scopes granted to the token holder.
+    scopes: Vec<Scope>,
+}
+
+/// Compares two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Rejects the request unless its principal has been granted all `required` scopes.
+///
+/// The principal is either attached by the transport, e.g. from a TLS client certificate, or
+/// authenticated by the bearer token in the `Authorization` header if `tokens` is set. Requests
+/// are not restricted if neither is available.
+pub(crate) fn authorize(
+    tokens: Option<Arc<TokenStore>>,
+    required: &'static [Scope],
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::ext::optional::<Principal>()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |principal: Option<Principal>, header: Option<String>| {
+                let tokens = tokens.clone();
+                async move {
+                    let principal = match (principal, tokens) {
+                        (Some(principal), _) => principal,
+                        (None, Some(tokens)) => tokens
+                            .authenticate(header.as_deref())
+                            .map_err(warp::reject::custom)?,
+                        (None, None) => return Ok(()),
+                    };
+
+                    match required.iter().find(|&&scope| !principal.has_scope(scope)) {
+                        Some(&scope) => Err(warp::reject::custom(Forbidden {
+                            principal: principal.name,
+                            scope,
+                        })),
+                        None => Ok(()),
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Custom `warp` rejection for a request without valid credentials.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Unauthorized {
+    /// The request carries no bearer token.
+    MissingToken,
+    /// The bearer token is not known.
+    InvalidToken,
+}
+
+impl Display for Unauthorized {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Unauthorized::MissingToken => f.write_str("missing bearer token"),
+            Unauthorized::InvalidToken => f.write_str("invalid bearer token"),
+        }
+    }
+}
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Custom `warp` rejection for a client lacking a required scope.
+#[derive(Debug)]
+pub(crate) struct Forbidden {
+    /// Name of the rejected client.
+    pub principal: String,
+    /// The first missing scope.
+    pub scope: Scope,
+}
+
+impl warp::reject::Reject for Forbidden {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scopes_and_access() {
+        let scopes: Vec<Scope> =
+            serde_json::from_str(r#"["containers:read", "images:pull"]"#).unwrap();
+        assert_eq!(scopes, [Scope::ContainersRead, Scope::ImagesPull]);
+        assert_eq!(Scope::ContainersWrite.to_string(), "containers:write");
+
+        let access: Access = serde_json::from_str(r#""read-only""#).unwrap();
+        assert_eq!(access.scopes(), [Scope::ContainersRead]);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_scopes() {
+        let filter = authorize(None, &[Scope::ContainersWrite]);
+        let read_only = Principal {
+            name: "dashboard".into(),
+            scopes: Access::ReadOnly.scopes().to_vec(),
+        };
+
+        assert!(warp::test::request().filter(&filter).await.is_ok());
+
+        let rejection = warp::test::request()
+            .extension(read_only)
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        let forbidden = rejection.find::<Forbidden>().unwrap();
+        assert_eq!(forbidden.principal, "dashboard");
+        assert_eq!(forbidden.scope, Scope::ContainersWrite);
+    }
+
+    #[tokio::test]
+    async fn authenticates_bearer_tokens() {
+        let store: TokenStore = toml::from_str(
+            r#"
+            [[tokens]]
+            name = "ci"
+            token = "ci-secret"
+            scopes = ["containers:read", "images:pull"]
+            "#,
+        )
+        .unwrap();
+        let filter = authorize(Some(Arc::new(store)), &[Scope::ContainersRead]);
+
+        let rejection = warp::test::request().filter(&filter).await.unwrap_err();
+        assert_eq!(
+            rejection.find::<Unauthorized>(),
+            Some(&Unauthorized::MissingToken)
+        );
+
+        let rejection = warp::test::request()
+            .header("authorization", "Bearer wrong-secret")
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            rejection.find::<Unauthorized>(),
+            Some(&Unauthorized::InvalidToken)
+        );
+
+        let authorized = warp::test::request()
+            .header("authorization", "Bearer ci-secret")
+            .filter(&filter)
+            .await;
+        assert!(authorized.is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.toml");
+        let tokens = r#"
+            [[tokens]]
+            name = "a"
+            token = "secret"
+            scopes = []
+
+            [[tokens]]
+            name = "b"
+            token = "secret"
+            scopes = []
+        "#;
+        std::fs::write(&path, tokens).unwrap();
+
+        let e = TokenStore::load(&path).unwrap_err();
+        assert!(e.to_string().contains("duplicate"));
+    }
+}
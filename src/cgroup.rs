@@ -0,0 +1,670 @@
+//! Types for managing `cgroup` V2 subtrees and reading container resource statistics.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use fallible_collections::tryformat;
+use libc::pid_t;
+use serde::Serialize;
+use tokio::process::Command;
+use tokio::runtime::Handle;
+use tracing::{debug, info, instrument, warn};
+
+use crate::error::Error;
+
+/// Mount point of the unified `cgroup` V2 hierarchy.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Controllers delegated to each container, if available.
+const CONTROLLERS: [&str; 4] = ["cpu", "io", "memory", "pids"];
+
+/// Leaf `cgroup` containing the engine process itself.
+const ENGINE_LEAF: &str = "engine";
+
+/// Subtree containing one scope per container.
+const CONTAINERS_SUBTREE: &str = "containers";
+
+/// Leaf `cgroup` inside each container scope for the monitor process, e.g. `conmon`.
+const CONMON_LEAF: &str = "conmon";
+
+/// Leaf `cgroup` inside each container scope for the container processes.
+const CONTAINER_LEAF: &str = "container";
+
+/// Number of attempts made to remove a busy `cgroup` directory.
+const REMOVE_ATTEMPTS: u32 = 10;
+
+/// A `cgroup` V2 subtree owned by the engine.
+///
+/// The subtree is laid out as follows, which satisfies the "no internal processes" rule:
+///
+/// ```text
+/// <root>/
+/// ├── engine/                  # The engine process itself.
+/// └── containers/
+///     └── <name>.scope/        # One scope per container.
+///         ├── conmon/          # The `conmon` process monitoring the container.
+///         └── container/       # The container processes, created by the OCI runtime.
+/// ```
+#[derive(Debug)]
+pub struct CgroupTree {
+    /// Path to the root of the subtree, relative to the `cgroup` V2 mount point.
+    root: PathBuf,
+}
+
+impl CgroupTree {
+    /// Sets up the engine subtree at `root`, moving the current process into it.
+    ///
+    /// If `root` is `None`, the `cgroup` of the current process is used, e.g. the `cgroup`
+    /// delegated to the engine by `systemd`. Relative paths are resolved against the `cgroup` V2
+    /// mount point.
+    ///
+    /// Returns `Err` if the system does not support `cgroup` V2, the subtree is not writable, or
+    /// an I/O error occurred.
+    #[instrument(err)]
+    pub fn init(root: Option<&Path>) -> anyhow::Result<Self> {
+        let root = match root {
+            Some(path) => {
+                let relative = path.strip_prefix(CGROUP_ROOT).unwrap_or(path);
+                Path::new("/").join(relative)
+            }
+            None => {
+                let contents = std::fs::read_to_string("/proc/self/cgroup")?;
+                let own = parse_unified_path(&contents)
+                    .ok_or_else(|| anyhow!("engine is not in a cgroup V2 hierarchy"))?;
+
+                // Don't nest deeper if the engine was already moved into its leaf previously.
+                let own = Path::new(own);
+                match own.file_name() {
+                    Some(name) if name == ENGINE_LEAF => own.parent().unwrap_or(own).to_owned(),
+                    _ => own.to_owned(),
+                }
+            }
+        };
+
+        let tree = CgroupTree { root };
+        let root_dir = tree.absolute(Path::new(""));
+
+        let engine_dir = root_dir.join(ENGINE_LEAF);
+        std::fs::create_dir_all(&engine_dir)
+            .with_context(|| format!("failed to create {}", engine_dir.display()))?;
+        std::fs::write(engine_dir.join("cgroup.procs"), "0")
+            .context("failed to move engine into its own cgroup")?;
+
+        enable_controllers(&root_dir)?;
+
+        let containers_dir = root_dir.join(CONTAINERS_SUBTREE);
+        std::fs::create_dir_all(&containers_dir)?;
+        enable_controllers(&containers_dir)?;
+
+        info!("managing cgroup subtree at {}", root_dir.display());
+        Ok(tree)
+    }
+
+    /// Creates a new scope for the container `name`, containing separate leaves for `conmon` and
+    /// for the container itself.
+    ///
+    /// An empty scope left behind by a previous run of the engine is replaced.
+    ///
+    /// Returns `Err` if `name` is not a valid directory name, a scope with processes still exists
+    /// for `name`, or if an I/O error occurred.
+    #[instrument(level = "debug", skip(self), err)]
+    pub fn create_scope(&self, name: &str) -> anyhow::Result<ContainerCgroup> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            let msg = format!("invalid container name `{}`", name);
+            return Err(Error::InvalidSpec(msg).into());
+        }
+
+        let scope_name = tryformat!(72, "{}.scope", name).map_err(Error::Oom)?;
+        let scope = Path::new(CONTAINERS_SUBTREE).join(scope_name);
+        let scope_dir = self.absolute(&scope);
+
+        // Clean up any stale scope left behind by a previous run of the engine, but never kill
+        // processes which may still belong to a live container.
+        if scope_dir.exists() {
+            let events = std::fs::read_to_string(scope_dir.join("cgroup.events"))?;
+            if parse_keyed(&events, "populated").unwrap_or(0) != 0 {
+                let msg = format!("cgroup scope {} is still in use", scope_dir.display());
+                return Err(Error::AlreadyExists(msg).into());
+            }
+
+            warn!("removing stale cgroup scope {}", scope_dir.display());
+            remove_scope(&scope_dir);
+        }
+
+        std::fs::create_dir(&scope_dir)?;
+        enable_controllers(&scope_dir)?;
+        std::fs::create_dir(scope_dir.join(CONMON_LEAF))?;
+
+        Ok(ContainerCgroup {
+            scope_dir,
+            container_path: self.root.join(scope).join(CONTAINER_LEAF),
+        })
+    }
+
+    /// Returns the absolute path of `relative` under the root of the subtree.
+    fn absolute(&self, relative: &Path) -> PathBuf {
+        let root = self.root.strip_prefix("/").unwrap_or(&self.root);
+        Path::new(CGROUP_ROOT).join(root).join(relative)
+    }
+}
+
+/// A `cgroup` V2 scope dedicated to a single container and its monitor process.
+///
+/// The scope will remove itself automatically when the object is dropped.
+#[derive(Debug)]
+pub struct ContainerCgroup {
+    scope_dir: PathBuf,
+    container_path: PathBuf,
+}
+
+impl ContainerCgroup {
+    /// Returns the path of the container leaf, relative to the `cgroup` V2 mount point.
+    ///
+    /// This value is suitable for use as `linux.cgroupsPath` in the OCI runtime configuration.
+    pub fn container_path(&self) -> &Path {
+        &self.container_path
+    }
+
+    /// Returns a handle for reading resource statistics from the container leaf.
+    pub fn container_cgroup(&self) -> Cgroup {
+        Cgroup {
+            path: self.scope_dir.join(CONTAINER_LEAF),
+        }
+    }
+
+    /// Returns the number of processes in the scope killed by the OOM killer.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    pub async fn oom_kills(&self) -> anyhow::Result<u64> {
+        let events = read_optional(&self.scope_dir.join("memory.events")).await?;
+        Ok(parse_keyed(&events, "oom_kill").unwrap_or(0))
+    }
+
+    /// Configures `cmd` such that the spawned process joins the `conmon` leaf of this scope
+    /// before it begins executing.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    pub fn attach_conmon(&self, cmd: &mut Command) -> io::Result<()> {
+        let procs = self.open_monitor_procs()?;
+        unsafe {
+            cmd.pre_exec(move || join_cgroup(&procs));
+        }
+
+        Ok(())
+    }
+
+    /// Opens the `cgroup.procs` file of the `conmon` leaf of this scope, which is shared by the
+    /// native monitor.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    pub fn open_monitor_procs(&self) -> io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.scope_dir.join(CONMON_LEAF).join("cgroup.procs"))
+    }
+}
+
+impl ContainerCgroup {
+    /// Kills any processes left in the scope and removes it, waiting for them to exit without
+    /// blocking the executor.
+    pub async fn remove(mut self) {
+        let scope_dir = std::mem::take(&mut self.scope_dir);
+        if let Err(e) = tokio::task::spawn_blocking(move || remove_scope(&scope_dir)).await {
+            warn!("failed to remove cgroup scope: {}", e);
+        }
+    }
+}
+
+impl Drop for ContainerCgroup {
+    fn drop(&mut self) {
+        // Already removed explicitly.
+        if self.scope_dir.as_os_str().is_empty() {
+            return;
+        }
+
+        // Removing the scope may wait for its processes to exit, so keep that off the executor.
+        let scope_dir = std::mem::take(&mut self.scope_dir);
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || remove_scope(&scope_dir));
+            }
+            Err(_) => remove_scope(&scope_dir),
+        }
+    }
+}
+
+// Writes `0` to the given `cgroup.procs` file, moving the calling process into that `cgroup`.
+//
+// This is called in between `fork()` and `exec()`, so it must only make async-signal-safe calls.
+pub(crate) fn join_cgroup(procs: &File) -> io::Result<()> {
+    let written = unsafe { libc::write(procs.as_raw_fd(), b"0".as_ptr() as *const _, 1) };
+    if written == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Enables all available delegated controllers for the children of the `cgroup` at `dir`.
+fn enable_controllers(dir: &Path) -> anyhow::Result<()> {
+    let available = std::fs::read_to_string(dir.join("cgroup.controllers"))?;
+
+    for controller in CONTROLLERS.iter() {
+        if !available.split_whitespace().any(|c| c == *controller) {
+            debug!(
+                "controller `{}` not available in {}",
+                controller,
+                dir.display()
+            );
+            continue;
+        }
+
+        let enable = tryformat!(16, "+{}", controller).map_err(Error::Oom)?;
+        std::fs::write(dir.join("cgroup.subtree_control"), enable).with_context(|| {
+            format!(
+                "failed to enable `{}` controller in {}",
+                controller,
+                dir.display()
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Kills any processes left in the container scope at `scope_dir` and removes it.
+fn remove_scope(scope_dir: &Path) {
+    for leaf in [CONMON_LEAF, CONTAINER_LEAF].iter() {
+        remove_leaf(&scope_dir.join(leaf));
+    }
+
+    if let Err(e) = std::fs::remove_dir(scope_dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("failed to remove cgroup {}: {}", scope_dir.display(), e);
+        }
+    }
+}
+
+/// Kills any processes left in the leaf `cgroup` at `dir` and removes it, retrying while the
+/// processes exit.
+fn remove_leaf(dir: &Path) {
+    for attempt in 1..=REMOVE_ATTEMPTS {
+        match std::fs::remove_dir(dir) {
+            Ok(()) => return,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) if attempt == REMOVE_ATTEMPTS => {
+                warn!("failed to remove cgroup {}: {}", dir.display(), e);
+            }
+            Err(_) => {
+                let procs = std::fs::read_to_string(dir.join("cgroup.procs")).unwrap_or_default();
+                for pid in procs
+                    .lines()
+                    .filter_map(|line| line.trim().parse::<pid_t>().ok())
+                {
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                }
+
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+/// A `cgroup` V2 directory containing one or more processes, used for reading statistics.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Looks up the `cgroup` V2 which the process identified by `pid` belongs to.
+    ///
+    /// Returns `Err` if the process does not exist, or is not in a `cgroup` V2 hierarchy.
+    #[instrument]
+    pub async fn for_pid(pid: pid_t) -> anyhow::Result<Self> {
+        let proc_file = tryformat!(32, "/proc/{}/cgroup", pid).map_err(Error::Oom)?;
+        let contents = tokio::fs::read_to_string(&proc_file)
+            .await
+            .map_err(|e| anyhow!("process {} is not running: {}", pid, e))?;
+
+        let relative = parse_unified_path(&contents)
+            .ok_or_else(|| anyhow!("process {} is not in a cgroup V2 hierarchy", pid))?;
+
+        let path = Path::new(CGROUP_ROOT).join(relative.trim_start_matches('/'));
+        debug!("found cgroup for PID {}: {}", pid, path.display());
+        Ok(Cgroup { path })
+    }
+
+    /// Reads the current resource usage statistics of the `cgroup`.
+    ///
+    /// Network counters are read from the network namespace of the process identified by `pid`.
+    /// Any controllers which are not enabled for this `cgroup` are reported as zero.
+    ///
+    /// Returns `Err` if the `cgroup` no longer exists, or if an I/O error occurred.
+    pub async fn stats(&self, pid: pid_t) -> anyhow::Result<Stats> {
+        if tokio::fs::metadata(&self.path).await.is_err() {
+            return Err(anyhow!("cgroup {} no longer exists", self.path.display()));
+        }
+
+        let cpu_stat = self.read("cpu.stat").await?;
+        let memory_current = self.read("memory.current").await?;
+        let memory_peak = self.read("memory.peak").await?;
+        let memory_max = self.read("memory.max").await?;
+        let pids_current = self.read("pids.current").await?;
+        let pids_max = self.read("pids.max").await?;
+        let io_stat = self.read("io.stat").await?;
+
+        let net_file = tryformat!(32, "/proc/{}/net/dev", pid).map_err(Error::Oom)?;
+        let net_dev = read_optional(Path::new(&net_file)).await?;
+
+        Ok(Stats {
+            cpu: parse_cpu_stat(&cpu_stat),
+            memory: MemoryStats {
+                current: parse_value(&memory_current).unwrap_or(0),
+                peak: parse_value(&memory_peak),
+                limit: parse_value(&memory_max),
+            },
+            pids: PidsStats {
+                current: parse_value(&pids_current).unwrap_or(0),
+                limit: parse_value(&pids_max),
+            },
+            io: parse_io_stat(&io_stat),
+            network: parse_net_dev(&net_dev),
+        })
+    }
+
+    /// Reads the given interface file, returning an empty string if it does not exist.
+    async fn read(&self, file_name: &str) -> io::Result<String> {
+        read_optional(&self.path.join(file_name)).await
+    }
+}
+
+async fn read_optional(path: &Path) -> io::Result<String> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Resource usage statistics of a container.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// CPU usage statistics.
+    pub cpu: CpuStats,
+    /// Memory usage statistics.
+    pub memory: MemoryStats,
+    /// Process count statistics.
+    pub pids: PidsStats,
+    /// Block I/O statistics, summed over all devices.
+    pub io: IoStats,
+    /// Network statistics, summed over all interfaces except loopback.
+    pub network: NetworkStats,
+}
+
+/// CPU usage statistics, read from `cpu.stat`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct CpuStats {
+    /// Total CPU time consumed, in microseconds.
+    pub usage_usec: u64,
+    /// CPU time consumed in user mode, in microseconds.
+    pub user_usec: u64,
+    /// CPU time consumed in kernel mode, in microseconds.
+    pub system_usec: u64,
+    /// Number of elapsed enforcement periods.
+    pub nr_periods: u64,
+    /// Number of enforcement periods in which the `cgroup` was throttled.
+    pub nr_throttled: u64,
+    /// Total time spent throttled, in microseconds.
+    pub throttled_usec: u64,
+}
+
+/// Memory usage statistics, in bytes.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct MemoryStats {
+    /// Current memory usage.
+    pub current: u64,
+    /// Peak memory usage, if supported by the kernel.
+    pub peak: Option<u64>,
+    /// Memory usage limit, if any.
+    pub limit: Option<u64>,
+}
+
+/// Process count statistics.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct PidsStats {
+    /// Current number of processes.
+    pub current: u64,
+    /// Maximum number of processes, if any.
+    pub limit: Option<u64>,
+}
+
+/// Block I/O statistics, read from `io.stat`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct IoStats {
+    /// Number of bytes read.
+    pub read_bytes: u64,
+    /// Number of bytes written.
+    pub write_bytes: u64,
+    /// Number of read operations.
+    pub read_ops: u64,
+    /// Number of write operations.
+    pub write_ops: u64,
+}
+
+/// Network statistics, read from `/proc/<pid>/net/dev`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct NetworkStats {
+    /// Number of bytes received.
+    pub rx_bytes: u64,
+    /// Number of packets received.
+    pub rx_packets: u64,
+    /// Number of bytes transmitted.
+    pub tx_bytes: u64,
+    /// Number of packets transmitted.
+    pub tx_packets: u64,
+}
+
+/// Extracts the unified hierarchy path from the contents of `/proc/<pid>/cgroup`.
+fn parse_unified_path(contents: &str) -> Option<&str> {
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(str::trim)
+}
+
+/// Parses a single-value interface file, returning `None` if it is empty or `max`.
+fn parse_value(contents: &str) -> Option<u64> {
+    contents.trim().parse().ok()
+}
+
+/// Looks up the value of `key` in a flat keyed interface file, e.g. `memory.events`.
+fn parse_keyed(contents: &str, key: &str) -> Option<u64> {
+    contents.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(k), Some(value)) if k == key => value.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Parses a flat keyed interface file, e.g. `cpu.stat`.
+fn parse_cpu_stat(contents: &str) -> CpuStats {
+    let mut stats = CpuStats::default();
+
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        let (key, value) = match (fields.next(), fields.next().and_then(|v| v.parse().ok())) {
+            (Some(key), Some(value)) => (key, value),
+            _ => continue,
+        };
+
+        match key {
+            "usage_usec" => stats.usage_usec = value,
+            "user_usec" => stats.user_usec = value,
+            "system_usec" => stats.system_usec = value,
+            "nr_periods" => stats.nr_periods = value,
+            "nr_throttled" => stats.nr_throttled = value,
+            "throttled_usec" => stats.throttled_usec = value,
+            _ => {}
+        }
+    }
+
+    stats
+}
+
+/// Parses a nested keyed `io.stat` file, summing the counters of all devices.
+fn parse_io_stat(contents: &str) -> IoStats {
+    let mut stats = IoStats::default();
+
+    for line in contents.lines() {
+        // The first field is the `major:minor` device number.
+        for field in line.split_whitespace().skip(1) {
+            let (key, value) = match field.find('=') {
+                Some(idx) => (&field[..idx], field[idx + 1..].parse().unwrap_or(0)),
+                None => continue,
+            };
+
+            match key {
+                "rbytes" => stats.read_bytes += value,
+                "wbytes" => stats.write_bytes += value,
+                "rios" => stats.read_ops += value,
+                "wios" => stats.write_ops += value,
+                _ => {}
+            }
+        }
+    }
+
+    stats
+}
+
+/// Parses `/proc/<pid>/net/dev`, summing the counters of all non-loopback interfaces.
+fn parse_net_dev(contents: &str) -> NetworkStats {
+    let mut stats = NetworkStats::default();
+
+    // The first two lines are column headers.
+    for line in contents.lines().skip(2) {
+        let idx = match line.find(':') {
+            Some(idx) => idx,
+            None => continue,
+        };
+
+        if line[..idx].trim() == "lo" {
+            continue;
+        }
+
+        let mut fields = line[idx + 1..]
+            .split_whitespace()
+            .map(|f| f.parse::<u64>().unwrap_or(0));
+
+        let rx_bytes = fields.next();
+        let rx_packets = fields.next();
+        let tx_bytes = fields.nth(6);
+        let tx_packets = fields.next();
+
+        if let (Some(rx_bytes), Some(rx_packets), Some(tx_bytes), Some(tx_packets)) =
+            (rx_bytes, rx_packets, tx_bytes, tx_packets)
+        {
+            stats.rx_bytes += rx_bytes;
+            stats.rx_packets += rx_packets;
+            stats.tx_bytes += tx_bytes;
+            stats.tx_packets += tx_packets;
+        }
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_unified_path() {
+        let contents = "0::/user.slice/user-1000.slice/container-busybox.scope\n";
+        let path = parse_unified_path(contents).unwrap();
+        assert_eq!(path, "/user.slice/user-1000.slice/container-busybox.scope");
+
+        let hybrid = "12:pids:/user.slice\n1:name=systemd:/user.slice\n0::/user.slice\n";
+        assert_eq!(parse_unified_path(hybrid), Some("/user.slice"));
+
+        assert_eq!(parse_unified_path("12:pids:/user.slice\n"), None);
+    }
+
+    #[test]
+    fn parses_single_values() {
+        assert_eq!(parse_value("4096\n"), Some(4096));
+        assert_eq!(parse_value("max\n"), None);
+        assert_eq!(parse_value(""), None);
+    }
+
+    #[test]
+    fn parses_keyed_values() {
+        let contents = "low 0\nhigh 0\nmax 12\noom 2\noom_kill 1\noom_group_kill 0\n";
+        assert_eq!(parse_keyed(contents, "oom_kill"), Some(1));
+        assert_eq!(parse_keyed(contents, "oom"), Some(2));
+        assert_eq!(parse_keyed(contents, "missing"), None);
+    }
+
+    #[test]
+    fn parses_cpu_stat() {
+        let contents = "usage_usec 71238\n\
+                        user_usec 42371\n\
+                        system_usec 28867\n\
+                        nr_periods 12\n\
+                        nr_throttled 3\n\
+                        throttled_usec 1520\n";
+
+        let expected = CpuStats {
+            usage_usec: 71238,
+            user_usec: 42371,
+            system_usec: 28867,
+            nr_periods: 12,
+            nr_throttled: 3,
+            throttled_usec: 1520,
+        };
+
+        assert_eq!(parse_cpu_stat(contents), expected);
+        assert_eq!(parse_cpu_stat(""), CpuStats::default());
+    }
+
+    #[test]
+    fn parses_io_stat() {
+        let contents = "259:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n\
+                        8:0 rbytes=1024 wbytes=0 rios=3 wios=0 dbytes=0 dios=0\n";
+
+        let expected = IoStats {
+            read_bytes: 5120,
+            write_bytes: 8192,
+            read_ops: 4,
+            write_ops: 2,
+        };
+
+        assert_eq!(parse_io_stat(contents), expected);
+    }
+
+    #[test]
+    fn parses_net_dev() {
+        let contents = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:    1000      10    0    0    0     0          0         0     1000      10    0    0    0     0       0          0
+  eth0:    2048      16    0    0    0     0          0         0      512       4    0    0    0     0       0          0
+  tap0:     100       1    0    0    0     0          0         0      200       2    0    0    0     0       0          0
+";
+
+        let expected = NetworkStats {
+            rx_bytes: 2148,
+            rx_packets: 17,
+            tx_bytes: 712,
+            tx_packets: 6,
+        };
+
+        assert_eq!(parse_net_dev(contents), expected);
+    }
+}
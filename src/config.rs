@@ -0,0 +1,413 @@
+//! Types for configuring the container engine.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::credentials::AUTH_FILE;
+use crate::image::{SKOPEO_BIN, UMOCI_BIN};
+use crate::listener::{ListenAddr, SocketPermissions};
+use crate::monitor::CONMON_BIN;
+use crate::platform::Platform;
+use crate::runtime::{CRUN_BIN, RUNC_BIN};
+use crate::tls::TlsConfig;
+
+/// Period of the CPU bandwidth limit derived from [`ResourceLimits::cpus`], in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Default size limit of image archives uploaded for import, in bytes.
+const MAX_IMPORT_SIZE: u64 = 1 << 30;
+
+/// The container engine configuration.
+///
+/// This is typically loaded from a TOML file with [`EngineConfig::load()`]. Any missing fields
+/// take on their default values.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    /// Directory for persistent engine state, such as container checkpoints.
+    pub state_dir: PathBuf,
+    /// Directory in which images are fetched and unpacked into bundles.
+    pub storage_dir: PathBuf,
+    /// Directory of OCI image layouts imported into the image store at startup, e.g. for devices
+    /// without registry access.
+    pub preload_dir: Option<PathBuf>,
+    /// The size limit of image archives uploaded for import, in bytes, which are spooled into
+    /// [`EngineConfig::storage_dir`] first.
+    pub max_import_size: u64,
+    /// The `cgroup` V2 subtree to place containers in, or the engine's own `cgroup` if `None`.
+    pub cgroup_root: Option<PathBuf>,
+    /// TCP socket addresses and Unix socket paths to serve the REST API on.
+    ///
+    /// This is ignored if the engine is started by `systemd` socket activation.
+    pub listen: Vec<ListenAddr>,
+    /// Ownership and permissions of the Unix sockets in [`EngineConfig::listen`].
+    pub socket: SocketPermissions,
+    /// TLS settings for the TCP addresses in [`EngineConfig::listen`], which are served over
+    /// plain HTTP if `None`.
+    pub tls: Option<TlsConfig>,
+    /// TOML file listing the bearer tokens accepted by the REST API, which requires no token if
+    /// `None`.
+    pub token_file: Option<PathBuf>,
+    /// Log filter directives used if `RUST_LOG` is not set, e.g. `light_containerd=info`.
+    pub log_level: String,
+    /// Name of the default OCI runtime, either `crun` or `runc`.
+    pub runtime: String,
+    /// The container monitor to supervise containers with.
+    pub monitor: MonitorKind,
+    /// Paths to the external binaries used by the engine.
+    pub binaries: Binaries,
+    /// Resource limits applied to every container.
+    pub resources: ResourceLimits,
+    /// Settings for fetching images from remote registries.
+    pub registry: RegistryConfig,
+}
+
+impl EngineConfig {
+    /// Loads the configuration from the TOML file at `path`.
+    ///
+    /// Returns `Err` if the file could not be read or is not a valid configuration.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file `{}`", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("failed to parse config file `{}`", path.display()))
+    }
+
+    /// Returns the path of the auth file holding registry credentials.
+    pub fn auth_file(&self) -> PathBuf {
+        match self.registry.auth_file {
+            Some(ref path) => path.clone(),
+            None => self.state_dir.join(AUTH_FILE),
+        }
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            state_dir: crate::default_state_dir(),
+            storage_dir: std::env::temp_dir(),
+            preload_dir: None,
+            max_import_size: MAX_IMPORT_SIZE,
+            cgroup_root: None,
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 8080)).into()],
+            socket: SocketPermissions::default(),
+            tls: None,
+            token_file: None,
+            log_level: "error".into(),
+            runtime: "crun".into(),
+            monitor: MonitorKind::Conmon,
+            binaries: Binaries::default(),
+            resources: ResourceLimits::default(),
+            registry: RegistryConfig::default(),
+        }
+    }
+}
+
+/// A list of available container monitors.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MonitorKind {
+    /// Supervise each container with a separate `conmon` process.
+    Conmon,
+    /// Supervise each container with a process forked from the engine.
+    Native,
+}
+
+/// Paths to the external binaries used by the engine.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Binaries {
+    /// The `conmon` container monitor.
+    pub conmon: PathBuf,
+    /// The `crun` OCI runtime.
+    pub crun: PathBuf,
+    /// The `runc` OCI runtime.
+    pub runc: PathBuf,
+    /// The `skopeo` image fetcher.
+    pub skopeo: PathBuf,
+    /// The `umoci` image unpacker.
+    pub umoci: PathBuf,
+}
+
+impl Default for Binaries {
+    fn default() -> Self {
+        Binaries {
+            conmon: CONMON_BIN.into(),
+            crun: CRUN_BIN.into(),
+            runc: RUNC_BIN.into(),
+            skopeo: SKOPEO_BIN.into(),
+            umoci: UMOCI_BIN.into(),
+        }
+    }
+}
+
+/// Default resource limits applied to every container.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// Maximum memory usage, in bytes.
+    pub memory: Option<i64>,
+    /// Maximum number of CPUs worth of CPU time, e.g. `0.5`.
+    pub cpus: Option<f64>,
+    /// Maximum number of processes.
+    pub pids: Option<i64>,
+}
+
+impl ResourceLimits {
+    /// Returns whether no limits are set.
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.cpus.is_none() && self.pids.is_none()
+    }
+
+    /// Merges these limits into the `linux.resources` object of an OCI runtime `config`.
+    pub fn apply_to(&self, config: &mut serde_json::Value) {
+        let resources = &mut config["linux"]["resources"];
+
+        if let Some(memory) = self.memory {
+            resources["memory"]["limit"] = json!(memory);
+        }
+
+        if let Some(cpus) = self.cpus {
+            resources["cpu"]["quota"] = json!((cpus * CPU_PERIOD as f64) as i64);
+            resources["cpu"]["period"] = json!(CPU_PERIOD);
+        }
+
+        if let Some(pids) = self.pids {
+            resources["pids"]["limit"] = json!(pids);
+        }
+    }
+}
+
+/// Settings for fetching images from remote registries, similar to `registries.conf`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// The registries searched in order for images given by a short name, e.g. `alpine:3.12`.
+    ///
+    /// Images qualified with a registry, e.g. `quay.io/coreos/etcd`, are only fetched from there.
+    pub search: Vec<String>,
+    /// Deprecated: the single registry images were fetched from before `search` was added, which
+    /// is now searched before all others.
+    pub default: Option<String>,
+    /// Whether to verify the TLS certificates of all registries.
+    pub tls_verify: bool,
+    /// Registries and mirrors which may be contacted over plain HTTP or with unverified TLS
+    /// certificates, e.g. `registry.local:5000`.
+    pub insecure: Vec<String>,
+    /// Mirrors of each registry, which are tried in order before the registry itself.
+    pub mirrors: BTreeMap<String, Vec<String>>,
+    /// Whether to refuse images referenced by tag only, rather than pinned by digest.
+    pub require_digest: bool,
+    /// A `containers-auth.json` file holding registry credentials, or `auth.json` inside the
+    /// state directory if `None`.
+    pub auth_file: Option<PathBuf>,
+    /// The platform selected from multi-architecture images, e.g. `linux/arm/v7`, or the host
+    /// platform if `None`.
+    pub platform: Option<Platform>,
+    /// A `containers-policy.json` file restricting which images may be fetched and run by their
+    /// signatures, or the `skopeo` default if `None`, whose results are not recorded.
+    pub policy: Option<PathBuf>,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig {
+            search: vec!["docker.io".into()],
+            default: None,
+            tls_verify: true,
+            insecure: Vec::new(),
+            mirrors: BTreeMap::new(),
+            require_digest: false,
+            auth_file: None,
+            platform: None,
+            policy: None,
+        }
+    }
+}
+
+impl RegistryConfig {
+    /// Returns whether the TLS certificate of `host` should not be verified, and plain HTTP
+    /// may be used.
+    pub fn is_insecure(&self, host: &str) -> bool {
+        !self.tls_verify || self.insecure.iter().any(|h| h == host)
+    }
+
+    /// Returns the registries searched in order for short names, starting with the deprecated
+    /// `default` registry, if set.
+    pub fn search_order(&self) -> impl Iterator<Item = &str> {
+        let default = self.default.as_deref();
+        let search = self.search.iter().map(String::as_str);
+        default
+            .into_iter()
+            .chain(search.filter(move |&registry| Some(registry) != default))
+    }
+
+    /// Returns the hosts to fetch from for `registry`, starting with its mirrors.
+    pub fn hosts<'a>(&'a self, registry: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let mirrors = self.mirrors.get(registry).into_iter().flatten();
+        mirrors.map(String::as_str).chain(std::iter::once(registry))
+    }
+
+    /// Returns the platform to select from multi-architecture images by default.
+    pub fn platform(&self) -> Platform {
+        self.platform.clone().unwrap_or_else(Platform::host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_empty_config() {
+        let config: EngineConfig = toml::from_str("").unwrap();
+        assert_eq!(config, EngineConfig::default());
+    }
+
+    #[test]
+    fn parses_full_config() {
+        let config: EngineConfig = toml::from_str(
+            r#"
+            state_dir = "/var/lib/light-containerd"
+            storage_dir = "/var/cache/light-containerd"
+            preload_dir = "/usr/share/light-containerd/images"
+            max_import_size = 268435456
+            cgroup_root = "/light-containerd"
+            listen = ["127.0.0.1:8080", "[::1]:8080", "unix:///run/light-containerd.sock"]
+            log_level = "light_containerd=debug"
+            runtime = "runc"
+            monitor = "native"
+
+            [socket]
+            mode = 0o660
+            group = "wheel"
+
+            [tls]
+            cert = "/etc/light-containerd/server.crt"
+            key = "/etc/light-containerd/server.key"
+
+            [binaries]
+            crun = "/usr/local/bin/crun"
+
+            [resources]
+            memory = 268435456
+            cpus = 1.5
+
+            [registry]
+            search = ["registry.example.com", "docker.io"]
+            tls_verify = false
+            insecure = ["registry.local:5000"]
+            auth_file = "/run/containers/0/auth.json"
+            platform = "linux/arm/v7"
+            policy = "/etc/containers/policy.json"
+
+            [registry.mirrors]
+            "docker.io" = ["cache.local:5000", "mirror.gcr.io"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.state_dir, Path::new("/var/lib/light-containerd"));
+        assert_eq!(
+            config.preload_dir.as_deref(),
+            Some(Path::new("/usr/share/light-containerd/images"))
+        );
+        assert_eq!(config.max_import_size, 268435456);
+        assert_eq!(config.listen.len(), 3);
+        assert_eq!(
+            config.listen[2],
+            ListenAddr::Unix("/run/light-containerd.sock".into())
+        );
+        assert_eq!(config.socket.mode, Some(0o660));
+        assert_eq!(config.tls.as_ref().unwrap().client_ca, None);
+        assert_eq!(config.monitor, MonitorKind::Native);
+        assert_eq!(config.binaries.crun, Path::new("/usr/local/bin/crun"));
+        assert_eq!(config.binaries.runc, Path::new(RUNC_BIN));
+        assert_eq!(config.resources.memory, Some(268435456));
+        assert_eq!(config.resources.pids, None);
+        assert_eq!(
+            config.registry.search,
+            ["registry.example.com", "docker.io"]
+        );
+        assert!(!config.registry.tls_verify);
+        assert_eq!(config.registry.insecure, ["registry.local:5000"]);
+        assert_eq!(config.auth_file(), Path::new("/run/containers/0/auth.json"));
+        assert_eq!(config.registry.platform().to_string(), "linux/arm/v7");
+        assert_eq!(
+            config.registry.policy.as_deref(),
+            Some(Path::new("/etc/containers/policy.json"))
+        );
+    }
+
+    #[test]
+    fn searches_deprecated_default_registry_first() {
+        let config: EngineConfig = toml::from_str(
+            r#"
+            [registry]
+            default = "registry.example.com"
+            search = ["quay.io", "registry.example.com"]
+            "#,
+        )
+        .unwrap();
+
+        let registries: Vec<_> = config.registry.search_order().collect();
+        assert_eq!(registries, ["registry.example.com", "quay.io"]);
+        let defaults = RegistryConfig::default();
+        let registries: Vec<_> = defaults.search_order().collect();
+        assert_eq!(registries, ["docker.io"]);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(toml::from_str::<EngineConfig>("port = 8080").is_err());
+        assert!(toml::from_str::<EngineConfig>("[binaries]\ntar = \"tar\"").is_err());
+        assert!(toml::from_str::<EngineConfig>("listen = [\"unix://api.sock\"]").is_err());
+    }
+
+    #[test]
+    fn resolves_registry_hosts() {
+        let mut registry = RegistryConfig::default();
+        registry.insecure.push("cache.local:5000".into());
+        registry.mirrors.insert(
+            "docker.io".into(),
+            vec!["cache.local:5000".into(), "mirror.gcr.io".into()],
+        );
+
+        let hosts: Vec<_> = registry.hosts("docker.io").collect();
+        assert_eq!(hosts, ["cache.local:5000", "mirror.gcr.io", "docker.io"]);
+        let hosts: Vec<_> = registry.hosts("quay.io").collect();
+        assert_eq!(hosts, ["quay.io"]);
+
+        assert!(registry.is_insecure("cache.local:5000"));
+        assert!(!registry.is_insecure("docker.io"));
+        registry.tls_verify = false;
+        assert!(registry.is_insecure("docker.io"));
+    }
+
+    #[test]
+    fn applies_resource_limits() {
+        let limits = ResourceLimits {
+            memory: Some(1048576),
+            cpus: Some(0.5),
+            pids: None,
+        };
+
+        let mut config = json!({ "linux": { "resources": { "devices": [] } } });
+        limits.apply_to(&mut config);
+
+        assert_eq!(
+            config["linux"]["resources"],
+            json!({
+                "devices": [],
+                "memory": { "limit": 1048576 },
+                "cpu": { "quota": 50000, "period": 100000 }
+            })
+        );
+    }
+}
@@ -1,122 +1,194 @@
 //! Types for creating and controlling running containers.
 
-use std::path::PathBuf;
-use std::process::Stdio;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use fallible_collections::tryformat;
 use libc::pid_t;
 use serde::{Deserialize, Serialize};
-use tokio::process::Command;
-use tokio_seqpacket::UnixSeqpacket;
-use tracing::{debug, info, instrument};
-use uuid::Uuid;
-
-use crate::image::OciBundle;
-use crate::pipe::{CommandExt, StartPipe, SyncPipe};
-
-const CONMON_BIN: &str = "conmon";
-const RUNTIME_BIN: &str = "/usr/bin/crun";
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, info, instrument, warn};
+
+use crate::archive::{self, ArchiveSink, ArchiveStream, TAR_BIN};
+use crate::cgroup::{Cgroup, ContainerCgroup, Stats};
+use crate::error::Error;
+use crate::health::{Health, HealthCheck, HealthMonitor};
+use crate::image::{ImageInfo, OciBundle};
+use crate::monitor::Monitor;
+use crate::runtime::{exec_command, Runtime};
+use crate::CommitOptions;
 
 /// An actively running OCI container.
 #[derive(Debug)]
 pub struct Container {
     id: String,
-    uuid: Uuid,
     pid: pid_t,
-    console_sock: UnixSeqpacket,
-    sync_pipe: SyncPipe,
-    runtime: OciBundle,
+    guard: Box<dyn Debug + Send + Sync>,
+    bundle: OciBundle,
+    runtime: Arc<dyn Runtime>,
+    monitor: Arc<dyn Monitor>,
+    cgroup: Option<ContainerCgroup>,
+    oom_baseline: u64,
+    health: Option<HealthMonitor>,
+    /// A health check waiting for the container to be started, along with its restart channel.
+    pending_health: Option<(HealthCheck, UnboundedSender<String>)>,
+    started: bool,
+    image: Option<ImageInfo>,
+    /// Set once the runtime has deleted the container, so it isn't deleted again on drop.
+    deleted: bool,
 }
 
 impl Container {
-    /// Spawns a new container with the given `id` from the `rt` OCI bundle.
-    #[instrument(level = "debug", skip(rt), err)]
-    pub async fn create(id: &str, rt: OciBundle) -> anyhow::Result<Self> {
-        let id = tryformat!(64, "{}", id).map_err(|e| anyhow!("OOM error: {:?}", e))?;
-        let uuid = Uuid::new_v4();
-        let uuid_str = tryformat!(36, "{}", uuid).map_err(|e| anyhow!("OOM error: {:?}", e))?;
-
-        let bundle_dir = rt.bundle_dir.to_str().expect("$TMPDIR is invalid UTF-8");
-        let exits_dir = rt.exits_dir.to_str().expect("$TMPDIR is invalid UTF-8");
-        let log_file = rt.log_file.to_str().expect("$TMPDIR is invalid UTF-8");
-        let pid_file = rt.pid_file.to_str().expect("$TMPDIR is invalid UTF-8");
-        let sock_dir = rt.base_dir().to_str().expect("$TMPDIR is invalid UTF-8");
-
-        let start_pipe = StartPipe::new()?;
-        let mut sync_pipe = SyncPipe::new()?;
-
-        // Spin up the `conmon` child process.
-        let child = Command::new(CONMON_BIN)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .arg("--log-level=debug")
-            .arg("--systemd-cgroup") // Required for rootless pause/resume.
-            .arg("--terminal") // Passes `--console-sock` to `crun`.
-            .args(&["--cid", &id])
-            .args(&["--cuuid", &uuid_str])
-            .args(&["--name", &id])
-            .args(&["--runtime", RUNTIME_BIN])
-            .args(&["--bundle", bundle_dir])
-            .args(&["--exit-dir", exits_dir])
-            .args(&["--log-path", log_file])
-            .args(&["--container-pidfile", pid_file])
-            .args(&["--socket-dir-path", sock_dir])
-            .inherit_oci_pipes(&start_pipe, &sync_pipe)
-            .spawn()?;
-
-        debug!("spawned `conmon`, signaling ready for setup");
-        if let Err(e) = start_pipe.ready().await {
-            let output = child.wait_with_output().await?;
-            if output.status.success() {
-                return Err(e);
-            } else {
-                let stderr = String::from_utf8(output.stderr)?;
-                return Err(anyhow!(
-                    "{}, `conmon` exited with non-zero status: [{}]",
-                    e,
-                    stderr
-                ));
-            }
-        }
+    /// Spawns a new container with the given `id` from the `rt` OCI bundle using `runtime`,
+    /// supervised by `monitor`.
+    ///
+    /// If a `cgroup` scope is given, both the monitor and the container are placed inside of it.
+    /// Otherwise, the `systemd` cgroup manager is used.
+    #[instrument(level = "debug", skip(rt, runtime, monitor, cgroup), err)]
+    pub async fn create(
+        id: &str,
+        rt: OciBundle,
+        runtime: Arc<dyn Runtime>,
+        monitor: Arc<dyn Monitor>,
+        cgroup: Option<ContainerCgroup>,
+    ) -> anyhow::Result<Self> {
+        Container::spawn(id, rt, runtime, monitor, cgroup, None).await
+    }
 
-        debug!("waiting for `conmon` to complete initial setup");
-        let output = child.wait_with_output().await?;
-        if !output.status.success() {
-            let stderr = String::from_utf8(output.stderr)?;
-            return Err(anyhow!(
-                "failed to create container, `conmon` exited with non-zero status: [{}]",
-                stderr
-            ));
-        }
+    /// Restores a container with the given `id` from the `rt` OCI bundle and the checkpoint images
+    /// located in `checkpoint_dir`.
+    ///
+    /// Unlike [`Container::create()`], the restored container will already be running.
+    #[instrument(level = "debug", skip(rt, runtime, monitor, cgroup), err)]
+    pub async fn restore<'a>(
+        id: &'a str,
+        rt: OciBundle,
+        runtime: Arc<dyn Runtime>,
+        monitor: Arc<dyn Monitor>,
+        cgroup: Option<ContainerCgroup>,
+        checkpoint_dir: &'a Path,
+    ) -> anyhow::Result<Self> {
+        Container::spawn(id, rt, runtime, monitor, cgroup, Some(checkpoint_dir)).await
+    }
 
-        let pid = sync_pipe.get_pid().await?;
-        debug!("received container PID from `conmon`: {}", pid);
+    async fn spawn<'a>(
+        id: &'a str,
+        rt: OciBundle,
+        runtime: Arc<dyn Runtime>,
+        monitor: Arc<dyn Monitor>,
+        cgroup: Option<ContainerCgroup>,
+        restore_from: Option<&'a Path>,
+    ) -> anyhow::Result<Self> {
+        let id = tryformat!(64, "{}", id).map_err(Error::Oom)?;
+
+        if let Some(ref scope) = cgroup {
+            let cgroups_path = scope.container_path().to_owned();
+            debug!("placing container in cgroup: {}", cgroups_path.display());
+            rt.update_config(|config| {
+                config["linux"]["cgroupsPath"] = cgroups_path.to_string_lossy().into();
+            })
+            .await?;
+        }
 
-        // Setup is complete, so connect to the console socket.
-        let sock_path = rt.base_dir().join(uuid_str).join("attach");
-        debug!("connecting to console socket: {}", sock_path.display());
-        let console_sock = UnixSeqpacket::connect(&sock_path).await?;
-        debug!("connected to console socket: {}", sock_path.display());
-        info!("container has been created with PID {}", pid);
+        let monitored = monitor
+            .spawn(&id, &rt, &*runtime, cgroup.as_ref(), restore_from)
+            .await?;
 
         Ok(Container {
             id,
-            uuid,
-            pid,
-            console_sock,
-            sync_pipe,
-            runtime: rt,
+            pid: monitored.pid,
+            guard: monitored.guard,
+            bundle: rt,
+            runtime,
+            monitor,
+            cgroup,
+            oom_baseline: 0,
+            health: None,
+            pending_health: None,
+            // Restored containers are already running.
+            started: restore_from.is_some(),
+            image: None,
+            deleted: false,
         })
     }
 
+    /// Starts probing the health of the container according to `check`, or defers probing until
+    /// the container is started if it has only been created so far.
+    ///
+    /// Restart requests for unhealthy containers are sent over `restart`, if enabled.
+    pub fn set_health_check(&mut self, check: HealthCheck, restart: UnboundedSender<String>) {
+        if !self.started {
+            self.pending_health = Some((check, restart));
+            return;
+        }
+
+        let runtime = self.runtime.clone();
+        self.health = Some(HealthMonitor::spawn(
+            self.id.clone(),
+            check,
+            runtime,
+            restart,
+        ));
+    }
+
+    /// Records the image the container was created from, which is reported in its state.
+    pub fn set_image(&mut self, image: ImageInfo) {
+        self.image = Some(image);
+    }
+
+    /// Kills the container, if it is still running, and starts it again from the same bundle.
+    #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
+    pub async fn restart(&mut self) -> anyhow::Result<()> {
+        info!("restarting container");
+        self.runtime.delete(&self.id, true).await?;
+
+        // Release the previous monitor, then remove the markers it left behind, so they aren't
+        // mistaken for those of the new container process.
+        self.guard = Box::new(());
+        for marker in &[
+            self.bundle.exits_dir.join(&self.id),
+            self.bundle.persist_dir.join("oom"),
+        ] {
+            match tokio::fs::remove_file(marker).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        // OOM kills are counted for the lifetime of the `cgroup` scope, so remember how many have
+        // occurred before this restart.
+        if let Some(ref cgroup) = self.cgroup {
+            self.oom_baseline = cgroup.oom_kills().await?;
+        }
+
+        let cgroup = self.cgroup.as_ref();
+        let monitored = self
+            .monitor
+            .spawn(&self.id, &self.bundle, &*self.runtime, cgroup, None)
+            .await?;
+        self.pid = monitored.pid;
+        self.guard = monitored.guard;
+
+        if let Some(ref mut health) = self.health {
+            health.reset();
+        }
+
+        self.start().await
+    }
+
     /// Start the container, if it isn't already running.
     #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
-    pub async fn start(&self) -> anyhow::Result<()> {
+    pub async fn start(&mut self) -> anyhow::Result<()> {
         info!("starting container");
-        let mut pause_cmd = Command::new(RUNTIME_BIN);
-        pause_cmd.args(&["start", &self.id]);
-        exec_command(&mut pause_cmd).await?;
+        self.runtime.start(&self.id).await?;
+        self.started = true;
+
+        if let Some((check, restart)) = self.pending_health.take() {
+            self.set_health_check(check, restart);
+        }
+
         Ok(())
     }
 
@@ -124,29 +196,30 @@ impl Container {
     #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
     pub async fn pause(&self) -> anyhow::Result<()> {
         info!("pausing container");
-        let mut pause_cmd = Command::new(RUNTIME_BIN);
-        pause_cmd.args(&["pause", &self.id]);
-        exec_command(&mut pause_cmd).await?;
-        Ok(())
+        self.runtime.pause(&self.id).await
     }
 
     /// Resume the container's execution, if it currently paused.
     #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
     pub async fn resume(&self) -> anyhow::Result<()> {
         info!("resuming container");
-        let mut resume_cmd = Command::new(RUNTIME_BIN);
-        resume_cmd.args(&["resume", &self.id]);
-        exec_command(&mut resume_cmd).await?;
-        Ok(())
+        self.runtime.resume(&self.id).await
     }
 
     /// Delete the container immediately.
     #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
-    pub async fn delete(self) -> anyhow::Result<()> {
+    pub async fn delete(mut self) -> anyhow::Result<()> {
         info!("deleting container");
-        let mut delete_cmd = Command::new(RUNTIME_BIN);
-        delete_cmd.args(&["delete", "--force", &self.id]);
-        exec_command(&mut delete_cmd).await?;
+        self.runtime.delete(&self.id, true).await?;
+        self.deleted = true;
+
+        // Release the monitor before removing the scope it runs in.
+        let cgroup = self.cgroup.take();
+        drop(self);
+        if let Some(cgroup) = cgroup {
+            cgroup.remove().await;
+        }
+
         Ok(())
     }
 
@@ -154,20 +227,188 @@ impl Container {
     #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
     pub async fn state(&self) -> anyhow::Result<State> {
         info!("retrieving container state");
-        let mut state_cmd = Command::new(RUNTIME_BIN);
-        state_cmd.args(&["state", &self.id]);
-
-        let state = match exec_command(&mut state_cmd).await {
-            Ok(stdout) => serde_json::from_slice(&stdout)?,
+        let mut state = match self.runtime.state(&self.id).await {
+            Ok(state) => state,
             Err(_) => self.read_state_from_exit_file().await?,
         };
 
+        state.cgroup = self.cgroup.as_ref().map(|c| c.container_path().to_owned());
+        state.health = self.health.as_ref().map(HealthMonitor::health);
+        state.image = self.image.clone();
         Ok(state)
     }
 
+    /// Retrieves the current resource usage statistics of the container from its `cgroup`.
+    ///
+    /// Returns `Err` if the container is not running, or if an I/O error occurred.
+    #[instrument(level = "debug", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
+    pub async fn stats(&self) -> anyhow::Result<Stats> {
+        debug!("retrieving container stats");
+        let cgroup = match self.cgroup {
+            Some(ref scope) => scope.container_cgroup(),
+            None => Cgroup::for_pid(self.pid).await?,
+        };
+
+        cgroup.stats(self.pid).await
+    }
+
+    /// Checkpoints the container with CRIU, writing the checkpoint images to `image_dir`.
+    ///
+    /// The container is stopped afterwards, unless `leave_running` is `true`.
+    #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
+    pub async fn checkpoint(&self, image_dir: &Path, leave_running: bool) -> anyhow::Result<()> {
+        info!("checkpointing container");
+        let work_dir = self.bundle.base_dir().join("checkpoint-work");
+        tokio::fs::create_dir_all(&work_dir).await?;
+
+        self.runtime
+            .checkpoint(&self.id, image_dir, &work_dir, leave_running)
+            .await
+    }
+
+    /// Archives the file or directory at `path` inside the container as a `tar` stream.
+    ///
+    /// Running containers are archived from the inside via `exec`, so any `tmpfs` mounts are
+    /// visible. Otherwise, `path` is resolved against the bundle's root filesystem on the host.
+    ///
+    /// Returns `Err` if `path` is invalid or does not exist, or if an I/O error occurred.
+    #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
+    pub async fn copy_from(&self, path: &str) -> anyhow::Result<ArchiveStream> {
+        info!("copying files out of container");
+
+        let cmd = if self.is_running().await? {
+            let target = archive::normalize(path)?;
+            let (parent, name) = archive::split(&target);
+            let mut test_cmd = self.runtime.exec(&self.id);
+            test_cmd.args(&["test", "-e"]);
+            exec_command(test_cmd.arg(Path::new("/").join(&target)))
+                .await
+                .map_err(|_| path_not_found(path))?;
+
+            let mut cmd = self.runtime.exec(&self.id);
+            cmd.args(&[TAR_BIN, "--create", "--file", "-"])
+                .arg("--directory")
+                .arg(Path::new("/").join(parent))
+                .arg("--")
+                .arg(name);
+            cmd
+        } else {
+            let rootfs = self.rootfs_dir();
+            let resolved = archive::resolve_in_root(&rootfs, path, false)?;
+            if tokio::fs::symlink_metadata(&resolved).await.is_err() {
+                return Err(path_not_found(path).into());
+            }
+
+            let relative = resolved.strip_prefix(&rootfs)?;
+            let (parent, name) = archive::split(relative);
+            archive::pack_command(TAR_BIN, &rootfs.join(parent), Path::new(name))
+        };
+
+        archive::pack(cmd)
+    }
+
+    /// Archives the whole root filesystem of the container as a flattened `tar` stream.
+    ///
+    /// The root filesystem is read from the bundle on the host, so `tmpfs` and other mounts are
+    /// not included.
+    ///
+    /// Returns `Err` if `tar` could not be spawned.
+    #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
+    pub fn export(&self) -> anyhow::Result<ArchiveStream> {
+        info!("exporting container root filesystem");
+        let cmd = archive::pack_command(TAR_BIN, &self.rootfs_dir(), Path::new("."));
+        archive::pack(cmd)
+    }
+
+    /// Commits the changes to the root filesystem of the container into a new image in the OCI
+    /// image layout at `dest_dir`.
+    ///
+    /// Running containers are paused meanwhile, so the committed layer is consistent.
+    ///
+    /// Returns `Err` if the container could not be paused or resumed, or if committing failed. If
+    /// both committing and resuming failed, the commit error is returned and the other logged.
+    #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
+    pub async fn commit(
+        &self,
+        umoci: &Path,
+        dest_dir: &Path,
+        changes: &CommitOptions,
+    ) -> anyhow::Result<()> {
+        info!("committing container");
+
+        let running = self.is_running().await?;
+        if running {
+            self.pause().await?;
+        }
+
+        let result = self.bundle.commit(umoci, dest_dir, changes).await;
+        if running {
+            if let Err(e) = self.resume().await {
+                if result.is_ok() {
+                    return Err(e);
+                }
+                warn!("failed to resume container after failed commit: {:#}", e);
+            }
+        }
+
+        result
+    }
+
+    /// Prepares to extract a `tar` archive into the directory at `path` inside the container.
+    ///
+    /// Running containers are extracted into from the inside via `exec`. Otherwise, `path` is
+    /// resolved against the bundle's root filesystem on the host, and the archive is staged next
+    /// to it, so that symbolic links inside the container cannot redirect its entries elsewhere.
+    ///
+    /// Returns `Err` if `path` is invalid or is not a directory, or if an I/O error occurred.
+    #[instrument(level = "info", skip(self), fields(id = self.id.as_str(), pid = self.pid, err))]
+    pub async fn copy_to(&self, path: &str) -> anyhow::Result<ArchiveSink> {
+        info!("copying files into container");
+
+        if self.is_running().await? {
+            let target = Path::new("/").join(archive::normalize(path)?);
+            let mut test_cmd = self.runtime.exec(&self.id);
+            test_cmd.args(&["test", "-d"]);
+            exec_command(test_cmd.arg(&target))
+                .await
+                .map_err(|_| not_a_directory(path))?;
+
+            let mut cmd = self.runtime.exec(&self.id);
+            cmd.args(&[TAR_BIN, "--extract", "--file", "-"])
+                .arg("--no-same-owner")
+                .arg("--directory")
+                .arg(target);
+            archive::unpack(cmd)
+        } else {
+            let rootfs = self.rootfs_dir();
+            let resolved = archive::resolve_in_root(&rootfs, path, true)?;
+            match tokio::fs::symlink_metadata(&resolved).await {
+                Ok(meta) if meta.is_dir() => {
+                    let staging_dir = self.bundle.base_dir();
+                    archive::unpack_into_root(TAR_BIN, &rootfs, &resolved, staging_dir)
+                }
+                _ => Err(not_a_directory(path).into()),
+            }
+        }
+    }
+
+    /// Returns whether the container is currently running.
+    async fn is_running(&self) -> anyhow::Result<bool> {
+        let state = self.state().await?;
+        Ok(matches!(state.status, Status::Running { .. }))
+    }
+
+    /// Returns the path to the root filesystem of the container bundle.
+    fn rootfs_dir(&self) -> PathBuf {
+        self.bundle.bundle_dir.join("rootfs")
+    }
+
     /// Retrieves the final state from the exit file, assuming that the container is stopped.
+    ///
+    /// The container is considered to be OOM killed if `conmon` left an `oom` marker file behind,
+    /// or if the kernel recorded an OOM kill in the container `cgroup`.
     async fn read_state_from_exit_file(&self) -> anyhow::Result<State> {
-        let exit_file = self.runtime.exits_dir.join("exit");
+        let exit_file = self.bundle.exits_dir.join(&self.id);
         if !exit_file.exists() {
             return Err(anyhow!(
                 "exit file doesn't exist for {} at {}",
@@ -178,44 +419,58 @@ impl Container {
 
         let bytes = tokio::fs::read(&exit_file).await?;
         let string = String::from_utf8(bytes)?;
-        let exit_code = string.parse()?;
+        let exit_code = string.trim().parse()?;
+
+        let finished_at = tokio::fs::metadata(&exit_file).await?.modified()?;
+        let finished_at = tryformat!(32, "{}", humantime::format_rfc3339_seconds(finished_at))
+            .map_err(Error::Oom)?;
+
+        let oom_marker = self.bundle.persist_dir.join("oom");
+        let oom_killed = if oom_marker.exists() {
+            true
+        } else if let Some(ref cgroup) = self.cgroup {
+            cgroup.oom_kills().await? > self.oom_baseline
+        } else {
+            false
+        };
+
+        if oom_killed {
+            info!("container was killed by the OOM killer");
+        }
 
         Ok(State {
             id: self.id.clone(),
             status: Status::Stopped { exit_code },
-            bundle: self.runtime.bundle_dir.clone(),
+            bundle: self.bundle.bundle_dir.clone(),
+            cgroup: None,
+            oom_killed,
+            finished_at: Some(finished_at),
+            health: None,
+            image: None,
         })
     }
 }
 
-impl Drop for Container {
-    fn drop(&mut self) {
-        std::process::Command::new(RUNTIME_BIN)
-            .args(&["delete", "--force", &self.id])
-            .status()
-            .ok();
-    }
+/// Returns an error for a `path` which does not exist inside the container.
+fn path_not_found(path: &str) -> Error {
+    Error::NotFound(format!("path `{}` does not exist in container", path))
 }
 
-async fn exec_command(cmd: &mut Command) -> anyhow::Result<Vec<u8>> {
-    debug!("executing runtime command: {:?}", cmd);
-
-    let output = cmd
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .await?;
+/// Returns an error for a `path` which is not a directory inside the container.
+fn not_a_directory(path: &str) -> Error {
+    Error::InvalidSpec(format!("path `{}` is not a directory in container", path))
+}
 
-    if !output.status.success() {
-        let stderr = std::str::from_utf8(&output.stderr)?;
-        return Err(anyhow!("`{:?}` returned: [{}]", cmd, stderr));
+impl Drop for Container {
+    fn drop(&mut self) {
+        if !self.deleted {
+            self.runtime.delete_sync(&self.id);
+        }
     }
-
-    Ok(output.stdout)
 }
 
 /// A list of possible states that the container can be in.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "status", rename_all = "lowercase")]
 pub enum Status {
     Creating,
@@ -225,6 +480,19 @@ pub enum Status {
     Stopped { exit_code: i64 },
 }
 
+impl Status {
+    /// Returns the lowercase name of this status, as used in its JSON representation.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Creating => "creating",
+            Status::Created { .. } => "created",
+            Status::Running { .. } => "running",
+            Status::Paused { .. } => "paused",
+            Status::Stopped { .. } => "stopped",
+        }
+    }
+}
+
 /// Represents the current state of a container.
 ///
 /// Based on `state-schema.json` from [opencontainers/runtime-spec].
@@ -239,13 +507,147 @@ pub struct State {
     pub status: Status,
     /// The path to the OCI bundle directory.
     pub bundle: PathBuf,
+    /// The path to the container `cgroup`, relative to the `cgroup` V2 mount point, if managed by
+    /// the engine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cgroup: Option<PathBuf>,
+    /// Whether the container was killed by the kernel OOM killer.
+    #[serde(default)]
+    pub oom_killed: bool,
+    /// The RFC 3339 timestamp at which the container exited, if it is stopped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    /// The health of the container, if a health check is configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<Health>,
+    /// The image the container was created from, with its tag resolved to a digest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageInfo>,
 }
 
 #[cfg(test)]
 mod tests {
+    use futures_util::stream::{self, TryStreamExt};
     use serde_json::json;
 
     use super::*;
+    use crate::monitor::FakeMonitor;
+    use crate::runtime::FakeRuntime;
+
+    #[tokio::test]
+    async fn controls_container_with_fake_backends() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let monitor = Arc::new(FakeMonitor::new());
+        let bundle = OciBundle::empty().unwrap();
+
+        let mut container = Container::create("busybox", bundle, runtime.clone(), monitor, None)
+            .await
+            .unwrap();
+
+        let state = container.state().await.unwrap();
+        assert!(matches!(state.status, Status::Created { .. }));
+
+        container.start().await.unwrap();
+        container.pause().await.unwrap();
+        let state = container.state().await.unwrap();
+        assert!(matches!(state.status, Status::Paused { .. }));
+
+        container.restart().await.unwrap();
+        let state = container.state().await.unwrap();
+        assert!(matches!(state.status, Status::Running { .. }));
+
+        container.delete().await.unwrap();
+        assert!(runtime.state("busybox").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn restores_checkpointed_container() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let monitor = Arc::new(FakeMonitor::new());
+        let checkpoint = tempfile::tempdir().unwrap();
+
+        let bundle = OciBundle::empty().unwrap();
+        let mut container =
+            Container::create("busybox", bundle, runtime.clone(), monitor.clone(), None)
+                .await
+                .unwrap();
+        container.start().await.unwrap();
+        container
+            .checkpoint(checkpoint.path(), false)
+            .await
+            .unwrap();
+        let state = container.state().await.unwrap();
+        assert!(matches!(state.status, Status::Stopped { .. }));
+        container.delete().await.unwrap();
+
+        let bundle = OciBundle::empty().unwrap();
+        let container =
+            Container::restore("busybox", bundle, runtime, monitor, None, checkpoint.path())
+                .await
+                .unwrap();
+        let state = container.state().await.unwrap();
+        assert!(matches!(state.status, Status::Running { .. }));
+    }
+
+    #[tokio::test]
+    async fn exports_root_filesystem() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let monitor = Arc::new(FakeMonitor::new());
+        let bundle = OciBundle::empty().unwrap();
+        let rootfs = bundle.bundle_dir.join("rootfs");
+        std::fs::create_dir_all(rootfs.join("etc")).unwrap();
+        std::fs::write(rootfs.join("etc/hostname"), "busybox\n").unwrap();
+
+        let container = Container::create("busybox", bundle, runtime, monitor, None)
+            .await
+            .unwrap();
+
+        let chunks: Vec<_> = container.export().unwrap().try_collect().await.unwrap();
+        let archive = chunks.concat();
+        let entry = b"./etc/hostname";
+        assert!(archive.windows(entry.len()).any(|w| w == entry));
+    }
+
+    #[tokio::test]
+    async fn copies_into_stopped_container_without_escaping() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let monitor = Arc::new(FakeMonitor::new());
+        let bundle = OciBundle::empty().unwrap();
+        let rootfs = bundle.bundle_dir.join("rootfs");
+        std::fs::create_dir_all(&rootfs).unwrap();
+
+        // Plant links pointing out of the root filesystem, as a malicious image could.
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), rootfs.join("evil")).unwrap();
+        std::os::unix::fs::symlink("../../../..", rootfs.join("up")).unwrap();
+
+        let src = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(src.path().join("evil")).unwrap();
+        std::fs::create_dir_all(src.path().join("up")).unwrap();
+        std::fs::write(src.path().join("evil/owned"), "pwned\n").unwrap();
+        std::fs::write(src.path().join("up/owned"), "pwned\n").unwrap();
+        let cmd = archive::pack_command(TAR_BIN, src.path(), Path::new("."));
+        let chunks: Vec<_> = archive::pack(cmd).unwrap().try_collect().await.unwrap();
+
+        let container = Container::create("busybox", bundle, runtime, monitor, None)
+            .await
+            .unwrap();
+        container
+            .copy_to("/")
+            .await
+            .unwrap()
+            .write_from(stream::iter(
+                chunks.into_iter().map(Ok::<_, std::io::Error>),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read_dir(outside.path()).unwrap().count(), 0);
+        let inside = archive::resolve_in_root(&rootfs, "/evil/owned", false).unwrap();
+        assert!(inside.starts_with(&rootfs));
+        assert_eq!(std::fs::read_to_string(inside).unwrap(), "pwned\n");
+        assert!(rootfs.join("owned").exists());
+    }
 
     #[test]
     fn parses_creating_state() {
@@ -290,6 +692,32 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn serializes_oom_killed_state() {
+        let state = State {
+            id: "busybox".into(),
+            status: Status::Stopped { exit_code: 137 },
+            bundle: PathBuf::from("/tmp/.tmpL0EsKy/bundle"),
+            cgroup: None,
+            oom_killed: true,
+            finished_at: Some("2020-11-19T12:00:00Z".into()),
+            health: None,
+            image: None,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&state).unwrap(),
+            json!({
+                "id": "busybox",
+                "status": "stopped",
+                "exit_code": 137,
+                "bundle": "/tmp/.tmpL0EsKy/bundle",
+                "oom_killed": true,
+                "finished_at": "2020-11-19T12:00:00Z"
+            })
+        );
+    }
+
     #[test]
     fn parses_stopped_state() {
         let _state: State = serde_json::from_value(json!({
@@ -0,0 +1,291 @@
+//! Credentials for pulling images from private registries.
+//!
+//! Credentials are kept in a JSON file compatible with [`containers-auth.json`], so it can be
+//! passed to `skopeo` directly, or shared with `podman login` and friends.
+//!
+//! [`containers-auth.json`]: https://github.com/containers/image/blob/master/docs/containers-auth.json.5.md
+
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::ErrorKind;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use fallible_collections::tryformat;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::error::Error;
+
+/// Name of the auth file inside the state directory, unless configured otherwise.
+pub const AUTH_FILE: &str = "auth.json";
+
+/// Username recorded alongside identity tokens, as done by `docker login`.
+const TOKEN_USERNAME: &str = "<token>";
+
+/// Credentials for logging into a remote registry.
+///
+/// Either a `username` and `password`, or an `identity_token` (optionally with a `username`) must
+/// be given.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Credentials {
+    /// The username to log in with.
+    pub username: Option<String>,
+    /// The password to log in with.
+    pub password: Option<String>,
+    /// An OAuth2 refresh token issued by the registry, used instead of a password.
+    pub identity_token: Option<String>,
+}
+
+impl Credentials {
+    /// Converts the credentials into an entry of the `auths` object of the auth file.
+    ///
+    /// Returns `Err` if neither a username and password nor an identity token were given.
+    fn to_entry(&self) -> Result<Value, Error> {
+        match (&self.username, &self.password, &self.identity_token) {
+            (Some(username), Some(password), None) if !username.is_empty() => {
+                let auth = encode_auth(username, password);
+                Ok(json!({ "auth": auth }))
+            }
+            (username, None, Some(token)) if !token.is_empty() => {
+                let username = username.as_deref().unwrap_or(TOKEN_USERNAME);
+                let auth = encode_auth(username, "");
+                Ok(json!({ "auth": auth, "identitytoken": token }))
+            }
+            _ => Err(Error::InvalidSpec(
+                "credentials require either a username and password, or an identity token".into(),
+            )),
+        }
+    }
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field(
+                "identity_token",
+                &self.identity_token.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+/// An auth file holding credentials for remote registries.
+#[derive(Debug)]
+pub(crate) struct CredentialStore {
+    path: PathBuf,
+    /// Serializes updates, so concurrent logins don't overwrite each other.
+    lock: Mutex<()>,
+}
+
+impl CredentialStore {
+    /// Creates a store backed by the auth file at `path`, which need not exist yet.
+    pub fn new(path: PathBuf) -> Self {
+        CredentialStore {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the registries for which credentials are stored, in alphabetical order.
+    ///
+    /// Returns `Err` if the auth file could not be read or parsed.
+    pub async fn registries(&self) -> Result<Vec<String>, Error> {
+        let file = self.read().await?;
+        let mut registries: Vec<String> = match file.get("auths") {
+            Some(Value::Object(auths)) => auths.keys().cloned().collect(),
+            _ => Vec::new(),
+        };
+
+        registries.sort();
+        Ok(registries)
+    }
+
+    /// Stores `credentials` for `registry`, replacing any previous ones.
+    ///
+    /// Returns `Err` if the registry name or credentials are invalid, or if the auth file could
+    /// not be read or written.
+    pub async fn login(&self, registry: &str, credentials: &Credentials) -> Result<(), Error> {
+        validate_registry(registry)?;
+        let entry = credentials.to_entry()?;
+
+        let _guard = self.lock.lock().await;
+        let mut file = self.read().await?;
+        let auths = file
+            .entry("auths")
+            .or_insert_with(|| Value::Object(Map::new()));
+        match auths {
+            Value::Object(auths) => auths.insert(registry.to_owned(), entry),
+            _ => return Err(malformed(&self.path, "`auths` is not an object")),
+        };
+
+        self.write(&file).await?;
+        info!("stored credentials for registry {}", registry);
+        Ok(())
+    }
+
+    /// Removes the credentials stored for `registry`.
+    ///
+    /// Returns `Err` if no credentials are stored for the registry, or if the auth file could not
+    /// be read or written.
+    pub async fn logout(&self, registry: &str) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        let mut file = self.read().await?;
+        let removed = match file.get_mut("auths") {
+            Some(Value::Object(auths)) => auths.remove(registry).is_some(),
+            _ => false,
+        };
+
+        if !removed {
+            let msg = tryformat!(128, "no credentials stored for registry `{}`", registry)
+                .map_err(Error::Oom)?;
+            return Err(Error::NotFound(msg));
+        }
+
+        self.write(&file).await?;
+        info!("removed credentials for registry {}", registry);
+        Ok(())
+    }
+
+    /// Reads the auth file, which is empty if it doesn't exist yet.
+    async fn read(&self) -> Result<Map<String, Value>, Error> {
+        let bytes = match tokio::fs::read(&self.path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Map::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        serde_json::from_slice(&bytes).map_err(|e| malformed(&self.path, e))
+    }
+
+    /// Replaces the auth file with `file`, which is only readable by the engine user.
+    async fn write(&self, file: &Map<String, Value>) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Write into a temporary file first, so a failure won't leave a truncated auth file.
+        let temp_path = self.path.with_extension("json.tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true).mode(0o600);
+
+        let bytes = serde_json::to_vec_pretty(file).map_err(|e| Error::Internal(e.into()))?;
+        let mut temp_file = tokio::fs::OpenOptions::from(options)
+            .open(&temp_path)
+            .await?;
+        temp_file.write_all(&bytes).await?;
+        temp_file.sync_all().await?;
+        drop(temp_file);
+
+        tokio::fs::rename(&temp_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+/// Returns the `auth` value for `username` and `password`, as in HTTP basic authentication.
+fn encode_auth(username: &str, password: &str) -> String {
+    base64::encode(format!("{}:{}", username, password))
+}
+
+/// Checks that `registry` is a host name, with an optional port.
+fn validate_registry(registry: &str) -> Result<(), Error> {
+    let valid = !registry.is_empty()
+        && !registry.starts_with('.')
+        && registry
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == ':');
+
+    if valid {
+        Ok(())
+    } else {
+        let msg = tryformat!(128, "invalid registry name `{}`", registry).map_err(Error::Oom)?;
+        Err(Error::InvalidSpec(msg))
+    }
+}
+
+/// Returns an error for an auth file at `path` which could not be parsed.
+fn malformed<E: Display>(path: &Path, e: E) -> Error {
+    let msg = anyhow::anyhow!("malformed auth file `{}`: {}", path.display(), e);
+    Error::Internal(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn password(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: Some(username.into()),
+            password: Some(password.into()),
+            identity_token: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_credentials_in_auth_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.json");
+        std::fs::write(&path, r#"{ "credHelpers": { "gcr.io": "gcloud" } }"#).unwrap();
+
+        let store = CredentialStore::new(path.clone());
+        store
+            .login("registry.example.com:5000", &password("alice", "secret"))
+            .await
+            .unwrap();
+
+        let token = Credentials {
+            identity_token: Some("refresh".into()),
+            ..Credentials::default()
+        };
+        store.login("quay.io", &token).await.unwrap();
+
+        let file: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            file,
+            json!({
+                "auths": {
+                    "registry.example.com:5000": { "auth": "YWxpY2U6c2VjcmV0" },
+                    "quay.io": { "auth": "PHRva2VuPjo=", "identitytoken": "refresh" },
+                },
+                "credHelpers": { "gcr.io": "gcloud" },
+            })
+        );
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let registries = store.registries().await.unwrap();
+        assert_eq!(registries, ["quay.io", "registry.example.com:5000"]);
+
+        store.logout("quay.io").await.unwrap();
+        let e = store.logout("quay.io").await.unwrap_err();
+        assert_eq!(e.code(), "not_found");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CredentialStore::new(dir.path().join("auth.json"));
+        assert!(store.registries().await.unwrap().is_empty());
+
+        let e = store
+            .login("docker.io", &Credentials::default())
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), "invalid_spec");
+
+        let e = store
+            .login("../etc", &password("alice", "secret"))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), "invalid_spec");
+        assert!(!dir.path().join("auth.json").exists());
+    }
+}
@@ -0,0 +1,144 @@
+//! Error types returned by the container engine.
+
+use std::fmt::{self, Display, Formatter};
+
+use fallible_collections::{tryformat, TryReserveError};
+
+/// An error returned by the container engine.
+///
+/// Each variant has a stable, machine-readable [code](Error::code) which is reported to REST API
+/// clients alongside the message.
+#[derive(Debug)]
+pub enum Error {
+    /// The requested container, image, checkpoint or path does not exist.
+    NotFound(String),
+    /// A container with the requested name already exists.
+    AlreadyExists(String),
+    /// The container is not in a state which allows the operation, e.g. pausing a stopped one.
+    InvalidState(String),
+    /// The request is malformed, e.g. an invalid container name or unknown OCI runtime.
+    InvalidSpec(String),
+    /// The OCI runtime or container monitor failed.
+    RuntimeFailure(anyhow::Error),
+    /// The container image could not be fetched or unpacked.
+    ImagePullFailed(anyhow::Error),
+    /// The image is rejected by the signature policy, e.g. as it is not signed by a trusted key.
+    Untrusted(String),
+    /// The engine ran out of memory.
+    Oom(TryReserveError),
+    /// Any other failure, e.g. an I/O error.
+    Internal(anyhow::Error),
+}
+
+impl Error {
+    /// Returns the stable, machine-readable code of this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotFound(_) => "not_found",
+            Error::AlreadyExists(_) => "already_exists",
+            Error::InvalidState(_) => "invalid_state",
+            Error::InvalidSpec(_) => "invalid_spec",
+            Error::RuntimeFailure(_) => "runtime_failure",
+            Error::ImagePullFailed(_) => "image_pull_failed",
+            Error::Untrusted(_) => "untrusted_image",
+            Error::Oom(_) => "out_of_memory",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    /// Returns an error for the missing container `name`.
+    pub(crate) fn container_not_found(name: &str) -> Self {
+        match tryformat!(128, "container `{}` does not exist", name) {
+            Ok(msg) => Error::NotFound(msg),
+            Err(e) => Error::Oom(e),
+        }
+    }
+
+    /// Wraps a failure of the OCI runtime or container monitor, unless it is already typed.
+    pub(crate) fn runtime(e: anyhow::Error) -> Self {
+        Error::typed_or(e, Error::RuntimeFailure)
+    }
+
+    /// Wraps a failure to fetch or unpack an image, unless it is already typed.
+    pub(crate) fn image_pull(e: anyhow::Error) -> Self {
+        Error::typed_or(e, Error::ImagePullFailed)
+    }
+
+    /// Wraps a failure to import an image archive uploaded by a client, unless it is already
+    /// typed.
+    pub(crate) fn invalid_archive(e: anyhow::Error) -> Self {
+        Error::typed_or(e, |e| {
+            match tryformat!(256, "failed to import image archive: {:#}", e) {
+                Ok(msg) => Error::InvalidSpec(msg),
+                Err(e) => Error::Oom(e),
+            }
+        })
+    }
+
+    /// Recovers an `Error` wrapped inside `e`, falling back to `wrap(e)` otherwise.
+    fn typed_or(e: anyhow::Error, wrap: fn(anyhow::Error) -> Self) -> Self {
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => wrap(e),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound(msg)
+            | Error::AlreadyExists(msg)
+            | Error::InvalidState(msg)
+            | Error::InvalidSpec(msg)
+            | Error::Untrusted(msg) => f.write_str(msg),
+            Error::RuntimeFailure(e) => write!(f, "runtime failure: {:#}", e),
+            Error::ImagePullFailed(e) => write!(f, "failed to pull image: {:#}", e),
+            Error::Oom(e) => write!(f, "out of memory: {:?}", e),
+            Error::Internal(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Error::typed_or(e, Error::Internal)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Internal(e.into())
+    }
+}
+
+impl From<TryReserveError> for Error {
+    fn from(e: TryReserveError) -> Self {
+        Error::Oom(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_typed_errors_from_anyhow() {
+        let wrapped = anyhow::Error::from(Error::InvalidSpec("bad path".into()));
+        let e = Error::runtime(wrapped);
+        assert_eq!(e.code(), "invalid_spec");
+        assert_eq!(e.to_string(), "bad path");
+
+        let e = Error::image_pull(anyhow::anyhow!("manifest unknown"));
+        assert_eq!(e.code(), "image_pull_failed");
+        assert_eq!(e.to_string(), "failed to pull image: manifest unknown");
+
+        let e = Error::invalid_archive(anyhow::anyhow!("invalid tar header"));
+        assert_eq!(e.code(), "invalid_spec");
+
+        let e = Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(e.code(), "internal");
+    }
+}
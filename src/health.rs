@@ -0,0 +1,585 @@
+//! Types for periodically probing the health of running containers.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::os::unix::io::AsRawFd;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::anyhow;
+use fallible_collections::tryformat;
+use futures_util::future::{AbortHandle, Abortable};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+
+use crate::container::Status;
+use crate::error::Error;
+use crate::runtime::Runtime;
+
+/// Number of most recent probe results kept in the container state.
+const MAX_LOG_ENTRIES: usize = 5;
+
+/// Maximum number of bytes of probe output kept per result.
+const MAX_OUTPUT_LEN: usize = 256;
+
+/// A health check configuration for a container.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    /// The probe used to determine whether the container is healthy.
+    pub probe: Probe,
+    /// Time to wait between probes, e.g. `30s`.
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// Time after which a single probe is considered to have failed, e.g. `5s`.
+    #[serde(default = "default_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+    /// Number of consecutive failures after which the container is considered unhealthy.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Grace period after startup during which failed probes are not counted, e.g. `1m`.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub start_period: Duration,
+    /// Whether the container should be restarted once it becomes unhealthy.
+    #[serde(default)]
+    pub restart_on_unhealthy: bool,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    humantime::parse_duration(&text).map_err(serde::de::Error::custom)
+}
+
+/// A method of probing the health of a container.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Probe {
+    /// Executes a command inside the container, which must exit with status 0.
+    Exec {
+        /// The command and its arguments.
+        command: Vec<String>,
+    },
+    /// Opens a TCP connection from inside the container network namespace, which must be
+    /// accepted.
+    Tcp {
+        /// The host to connect to.
+        #[serde(default = "default_host")]
+        host: String,
+        /// The port to connect to.
+        port: u16,
+    },
+    /// Sends an HTTP `GET` request from inside the container network namespace, which must return
+    /// a 2xx or 3xx status code.
+    Http {
+        /// The host to connect to.
+        #[serde(default = "default_host")]
+        host: String,
+        /// The port to connect to.
+        port: u16,
+        /// The request path.
+        #[serde(default = "default_path")]
+        path: String,
+    },
+}
+
+fn default_host() -> String {
+    "127.0.0.1".into()
+}
+
+fn default_path() -> String {
+    "/".into()
+}
+
+/// The health status of a container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// The container is within its start period and has not passed a probe yet.
+    Starting,
+    /// The most recent probe succeeded.
+    Healthy,
+    /// The number of consecutive failed probes has reached the configured retries.
+    Unhealthy,
+}
+
+/// The outcome of a single health probe.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProbeResult {
+    /// The RFC 3339 timestamp at which the probe started.
+    pub started_at: String,
+    /// Whether the probe succeeded.
+    pub success: bool,
+    /// Output or error message produced by the probe, truncated if necessary.
+    pub output: String,
+}
+
+/// The health of a container, as reported in its state.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Health {
+    /// The current health status.
+    pub status: HealthStatus,
+    /// Number of consecutive failed probes.
+    pub failing_streak: u32,
+    /// The most recent probe results, oldest first.
+    pub log: VecDeque<ProbeResult>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            status: HealthStatus::Starting,
+            failing_streak: 0,
+            log: VecDeque::new(),
+        }
+    }
+}
+
+impl Health {
+    /// Records a probe `result` and updates the health status.
+    ///
+    /// Failures occurring while `in_start_period` is `true` are logged, but not counted.
+    fn record(&mut self, result: ProbeResult, in_start_period: bool, retries: u32) {
+        if result.success {
+            self.status = HealthStatus::Healthy;
+            self.failing_streak = 0;
+        } else if !in_start_period {
+            self.failing_streak += 1;
+            if self.failing_streak >= retries {
+                self.status = HealthStatus::Unhealthy;
+            }
+        }
+
+        if self.log.len() == MAX_LOG_ENTRIES {
+            self.log.pop_front();
+        }
+
+        self.log.push_back(result);
+    }
+}
+
+/// A background task which periodically probes a container.
+///
+/// Probes are skipped while the container is not running, e.g. because it has been paused, and
+/// the task ends once the container has stopped. The task is stopped automatically when this
+/// object is dropped.
+#[derive(Debug)]
+pub struct HealthMonitor {
+    id: String,
+    check: HealthCheck,
+    runtime: Arc<dyn Runtime>,
+    restart: UnboundedSender<String>,
+    health: Arc<Mutex<Health>>,
+    handle: AbortHandle,
+}
+
+impl HealthMonitor {
+    /// Starts probing the container `id` according to `check`, executing commands with `runtime`.
+    ///
+    /// If `check.restart_on_unhealthy` is set, the container ID is sent over `restart` once the
+    /// container becomes unhealthy.
+    pub fn spawn(
+        id: String,
+        check: HealthCheck,
+        runtime: Arc<dyn Runtime>,
+        restart: UnboundedSender<String>,
+    ) -> Self {
+        let health = Arc::new(Mutex::new(Health::default()));
+        let handle = HealthMonitor::probe_periodically(&id, &check, &runtime, &restart, &health);
+
+        HealthMonitor {
+            id,
+            check,
+            runtime,
+            restart,
+            health,
+            handle,
+        }
+    }
+
+    /// Returns a snapshot of the current container health.
+    pub fn health(&self) -> Health {
+        self.health.lock().expect("health lock poisoned").clone()
+    }
+
+    /// Resets the health status to [`HealthStatus::Starting`] and starts probing the container
+    /// anew, e.g. after a restart.
+    pub fn reset(&mut self) {
+        self.handle.abort();
+        *self.health.lock().expect("health lock poisoned") = Health::default();
+        self.handle = HealthMonitor::probe_periodically(
+            &self.id,
+            &self.check,
+            &self.runtime,
+            &self.restart,
+            &self.health,
+        );
+    }
+
+    fn probe_periodically(
+        id: &str,
+        check: &HealthCheck,
+        runtime: &Arc<dyn Runtime>,
+        restart: &UnboundedSender<String>,
+        health: &Arc<Mutex<Health>>,
+    ) -> AbortHandle {
+        let (handle, registration) = AbortHandle::new_pair();
+        let task = run(
+            id.to_owned(),
+            check.clone(),
+            runtime.clone(),
+            health.clone(),
+            restart.clone(),
+        );
+        tokio::spawn(Abortable::new(task, registration));
+        handle
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn run(
+    id: String,
+    check: HealthCheck,
+    runtime: Arc<dyn Runtime>,
+    health: Arc<Mutex<Health>>,
+    restart: UnboundedSender<String>,
+) {
+    let mut started = Instant::now();
+
+    loop {
+        tokio::time::delay_for(check.interval).await;
+
+        match runtime.state(&id).await.map(|state| state.status) {
+            Ok(Status::Running { .. }) => {}
+            Ok(Status::Stopped { .. }) | Err(_) => {
+                debug!("container {} has stopped, ending health probes", id);
+                return;
+            }
+            Ok(status) => {
+                debug!(
+                    "skipping health probe for {} container {}",
+                    status.as_str(),
+                    id
+                );
+                continue;
+            }
+        }
+
+        let result = probe(&id, &check, &*runtime).await;
+        debug!("health probe for {}: {:?}", id, result);
+
+        // Discard failures caused by the container being paused or stopped while it was probed.
+        if !result.success && !is_running(&id, &*runtime).await {
+            debug!("container {} stopped while probed, discarding result", id);
+            continue;
+        }
+
+        let status = {
+            let mut health = health.lock().expect("health lock poisoned");
+            let in_start_period = started.elapsed() < check.start_period;
+            health.record(result, in_start_period, check.retries);
+            health.status
+        };
+
+        if status == HealthStatus::Unhealthy && check.restart_on_unhealthy {
+            info!("container {} is unhealthy, requesting restart", id);
+            if restart.send(id.clone()).is_err() {
+                warn!("engine is no longer accepting restart requests");
+                return;
+            }
+
+            *health.lock().expect("health lock poisoned") = Health::default();
+            started = Instant::now();
+        }
+    }
+}
+
+/// Returns whether the container `id` is currently running.
+async fn is_running(id: &str, runtime: &dyn Runtime) -> bool {
+    match runtime.state(id).await {
+        Ok(state) => matches!(state.status, Status::Running { .. }),
+        Err(_) => false,
+    }
+}
+
+/// Runs a single probe against the container `id`, honoring the configured timeout.
+async fn probe(id: &str, check: &HealthCheck, runtime: &dyn Runtime) -> ProbeResult {
+    let started_at = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+
+    let probed = run_probe(id, &check.probe, runtime, check.timeout);
+    let outcome = match tokio::time::timeout(check.timeout, probed).await {
+        Ok(outcome) => outcome,
+        Err(_) => Err(anyhow!("probe timed out after {:?}", check.timeout)),
+    };
+
+    let (success, mut output) = match outcome {
+        Ok(output) => (true, output),
+        Err(e) => (false, e.to_string()),
+    };
+
+    truncate(&mut output, MAX_OUTPUT_LEN);
+
+    ProbeResult {
+        started_at,
+        success,
+        output,
+    }
+}
+
+async fn run_probe(
+    id: &str,
+    probe: &Probe,
+    runtime: &dyn Runtime,
+    timeout: Duration,
+) -> anyhow::Result<String> {
+    match probe {
+        Probe::Exec { command } => {
+            let output = runtime
+                .exec(id)
+                .args(command)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .output()
+                .await?;
+
+            let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+            if output.status.success() {
+                Ok(stdout)
+            } else {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                Err(anyhow!(
+                    "command exited with {}: {}{}",
+                    output.status,
+                    stdout,
+                    stderr
+                ))
+            }
+        }
+        Probe::Tcp { host, port } => {
+            connect(id, runtime, host, *port, timeout).await?;
+            Ok(String::new())
+        }
+        Probe::Http { host, port, path } => {
+            let mut stream = connect(id, runtime, host, *port, timeout).await?;
+            let request = format!(
+                "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: light-containerd\r\n\r\n",
+                path, host
+            );
+            stream.write_all(request.as_bytes()).await?;
+
+            let mut status_line = String::new();
+            BufReader::new(stream).read_line(&mut status_line).await?;
+            let status_line = status_line.trim_end();
+
+            match parse_status_code(status_line) {
+                Some(code) if (200..400).contains(&code) => Ok(status_line.to_owned()),
+                Some(_) => Err(anyhow!("unhealthy response: {}", status_line)),
+                None => Err(anyhow!("malformed response: {}", status_line)),
+            }
+        }
+    }
+}
+
+/// Opens a TCP connection to `host:port` from inside the network namespace of the container
+/// `id`, so ports which are only bound inside of it are reachable.
+async fn connect(
+    id: &str,
+    runtime: &dyn Runtime,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> anyhow::Result<TcpStream> {
+    let pid = match runtime.state(id).await?.status {
+        Status::Running { pid } => pid,
+        status => return Err(anyhow!("container is {}", status.as_str())),
+    };
+
+    let netns_path = tryformat!(32, "/proc/{}/ns/net", pid).map_err(Error::Oom)?;
+    let netns = File::open(&netns_path)?;
+    let host = host.to_owned();
+    let (sender, receiver) = oneshot::channel();
+
+    // Joining a namespace affects the whole thread, so use a dedicated one instead of the pool.
+    std::thread::Builder::new()
+        .name("health-probe".into())
+        .spawn(move || {
+            let _ = sender.send(connect_in(&netns, &host, port, timeout));
+        })?;
+
+    let stream = receiver
+        .await
+        .map_err(|_| anyhow!("health probe thread exited unexpectedly"))??;
+    Ok(TcpStream::from_std(stream)?)
+}
+
+/// Joins the network namespace `netns` with the current thread and connects to `host:port`.
+fn connect_in(
+    netns: &File,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> io::Result<std::net::TcpStream> {
+    if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host did not resolve");
+    for addr in (host, port).to_socket_addrs()? {
+        match std::net::TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Extracts the status code from an HTTP status line, e.g. `HTTP/1.1 200 OK`.
+fn parse_status_code(status_line: &str) -> Option<u16> {
+    let mut fields = status_line.split_whitespace();
+    match (fields.next(), fields.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => code.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Truncates `text` to at most `max_len` bytes, respecting character boundaries.
+fn truncate(text: &mut String, max_len: usize) {
+    if text.len() > max_len {
+        let mut idx = max_len;
+        while !text.is_char_boundary(idx) {
+            idx -= 1;
+        }
+        text.truncate(idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn result(success: bool) -> ProbeResult {
+        ProbeResult {
+            started_at: "2020-11-19T12:00:00Z".into(),
+            success,
+            output: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_health_check_with_defaults() {
+        let check: HealthCheck = serde_json::from_value(json!({
+            "probe": { "type": "tcp", "port": 8080 }
+        }))
+        .unwrap();
+
+        assert_eq!(check.interval, Duration::from_secs(30));
+        assert_eq!(check.timeout, Duration::from_secs(5));
+        assert_eq!(check.retries, 3);
+        assert_eq!(check.start_period, Duration::from_secs(0));
+        assert!(!check.restart_on_unhealthy);
+
+        match check.probe {
+            Probe::Tcp { host, port } => assert_eq!((host.as_str(), port), ("127.0.0.1", 8080)),
+            probe => panic!("unexpectedly parsed: {:?}", probe),
+        }
+    }
+
+    #[test]
+    fn parses_health_check_with_durations() {
+        let check: HealthCheck = serde_json::from_value(json!({
+            "probe": { "type": "exec", "command": ["pgrep", "httpd"] },
+            "interval": "10s",
+            "timeout": "500ms",
+            "start_period": "1m",
+            "retries": 1,
+            "restart_on_unhealthy": true
+        }))
+        .unwrap();
+
+        assert_eq!(check.interval, Duration::from_secs(10));
+        assert_eq!(check.timeout, Duration::from_millis(500));
+        assert_eq!(check.start_period, Duration::from_secs(60));
+        assert!(check.restart_on_unhealthy);
+    }
+
+    #[test]
+    fn becomes_unhealthy_after_retries() {
+        let mut health = Health::default();
+        health.record(result(false), false, 2);
+        assert_eq!(health.status, HealthStatus::Starting);
+        health.record(result(false), false, 2);
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+        health.record(result(true), false, 2);
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert_eq!(health.failing_streak, 0);
+    }
+
+    #[test]
+    fn ignores_failures_in_start_period() {
+        let mut health = Health::default();
+        health.record(result(false), true, 1);
+        assert_eq!(health.status, HealthStatus::Starting);
+        assert_eq!(health.failing_streak, 0);
+        assert_eq!(health.log.len(), 1);
+    }
+
+    #[test]
+    fn keeps_most_recent_results() {
+        let mut health = Health::default();
+        for _ in 0..MAX_LOG_ENTRIES {
+            health.record(result(false), false, 100);
+        }
+        health.record(result(true), false, 100);
+
+        assert_eq!(health.log.len(), MAX_LOG_ENTRIES);
+        assert!(health.log.back().unwrap().success);
+    }
+
+    #[test]
+    fn parses_http_status_codes() {
+        assert_eq!(parse_status_code("HTTP/1.1 200 OK"), Some(200));
+        assert_eq!(
+            parse_status_code("HTTP/1.0 503 Service Unavailable"),
+            Some(503)
+        );
+        assert_eq!(parse_status_code("SSH-2.0-OpenSSH"), None);
+    }
+
+    #[test]
+    fn truncates_on_char_boundary() {
+        let mut text = String::from("héllo");
+        truncate(&mut text, 2);
+        assert_eq!(text, "h");
+    }
+}
@@ -1,46 +1,200 @@
 //! Types for fetching and unpacking OCI images.
 
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::time::Duration;
 
 use anyhow::anyhow;
-use fallible_collections::{tryformat, vec::TryCollect};
+use async_trait::async_trait;
+use fallible_collections::tryformat;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
 use tokio::process::Command;
-use tracing::{info, instrument};
+use tracing::{debug, info, instrument, warn};
 
-const SKOPEO_BIN: &str = "skopeo";
-const UMOCI_BIN: &str = "umoci";
+use crate::archive::TAR_BIN;
+use crate::config::EngineConfig;
+use crate::error::Error;
+use crate::operation::Progress;
+use crate::platform::Platform;
+use crate::policy::{Policy, Verification, DOCKER_TRANSPORT};
+use crate::store::ImageLease;
+use crate::CommitOptions;
+
+/// The default `skopeo` binary.
+pub const SKOPEO_BIN: &str = "skopeo";
+
+/// The default `umoci` binary.
+pub const UMOCI_BIN: &str = "umoci";
+
+/// Interval at which the progress of an image pull is reported.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Name of the reference under which fetched images are stored in their OCI image layout.
+const LAYOUT_REF: &str = "latest";
+
+/// Length of a hex-encoded SHA-256 digest.
+const SHA256_HEX_LEN: usize = 64;
+
+/// History entry recorded for layers committed from containers.
+const COMMIT_CREATED_BY: &str = "light-containerd commit";
+
+/// Registry name under which local images are kept, as done by Podman.
+const LOCAL_REGISTRY: &str = "localhost";
+
+/// Prefix of the `skopeo` error reported when the signature policy rejects an image.
+const POLICY_REJECTED: &str = "Source image rejected";
+
+/// Annotation naming the references in an OCI image layout.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// The resolved origin of a fetched image.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ImageInfo {
+    /// The fully-qualified reference the image was fetched by, e.g. `quay.io/coreos/etcd:v3.4`.
+    pub name: String,
+    /// The digest of the image manifest in the registry, e.g. `sha256:...`.
+    pub digest: String,
+    /// The platform the image was built for, selected from multi-architecture images.
+    pub platform: Platform,
+}
 
 /// Represents a fetched OCI image.
 #[derive(Debug)]
-pub struct OciImage(TempDir);
+pub struct OciImage {
+    dir: TempDir,
+    id: String,
+    info: ImageInfo,
+    from_registry: bool,
+    verification: Option<Verification>,
+}
 
 impl OciImage {
-    /// Retrieves an image from Docker Hub with the given spec (either `name` or `name:tag`).
-    #[instrument]
-    pub async fn fetch_from_docker_hub(container_spec: &str) -> anyhow::Result<Self> {
-        let (name, tag) = parse_container_spec(container_spec)?;
-        let src_dir = tempfile::tempdir()?;
+    /// Retrieves an image with the given spec (`name`, `name:tag` or `name[:tag]@sha256:...`)
+    /// into the storage directory.
+    ///
+    /// If `name` is not qualified with a registry, e.g. `alpine` rather than `quay.io/alpine`, the
+    /// configured registries are searched in order. The mirrors of each registry are tried before
+    /// the registry itself. Tags are resolved to digests first, so the image is fetched by digest
+    /// in any case. The number of bytes pulled so far is reported to `progress` periodically.
+    ///
+    /// If the image is a multi-architecture index, the image for `platform` is selected from it.
+    ///
+    /// If a signature policy is configured, the image must satisfy it under its name in the
+    /// registry, even if it is fetched from a mirror, before it may be unpacked.
+    ///
+    /// Returns `Err` if the spec is invalid, only has a tag while digests are required, the image
+    /// is rejected by the signature policy, or if the image could not be fetched from any
+    /// registry.
+    #[instrument(skip(config, progress))]
+    pub async fn fetch(
+        container_spec: &str,
+        platform: &Platform,
+        config: &EngineConfig,
+        progress: &Progress,
+    ) -> anyhow::Result<Self> {
+        let (spec, digest) = split_digest(container_spec)?;
+        if digest.is_none() && config.registry.require_digest {
+            let msg = tryformat!(
+                256,
+                "refusing to fetch `{}` by tag only, pin it with `@sha256:<digest>`",
+                spec
+            )
+            .map_err(Error::Oom)?;
+            return Err(Error::InvalidSpec(msg).into());
+        }
+
+        let (name, tag) = parse_container_spec(spec)?;
+        let (registry, repository) = split_registry(name);
+        let registries = match registry {
+            Some(registry) => vec![registry],
+            None => config.registry.search_order().collect(),
+        };
+
+        let mut last_error = None;
+        for registry in registries {
+            let repository = normalize_repository(registry, repository)?;
+            let source = Source {
+                registry,
+                repository: &repository,
+                tag,
+                digest,
+                platform,
+            };
+
+            match OciImage::fetch_from(&source, config, progress).await {
+                Ok(image) => return Ok(image),
+                // Typed errors, e.g. running out of memory, won't go away with another registry.
+                Err(e) if e.is::<Error>() => return Err(e),
+                Err(e) => {
+                    warn!("failed to fetch `{}` from {}: {:#}", name, registry, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) => Err(e),
+            None => {
+                let msg = tryformat!(256, "no registries configured to search for `{}`", name)
+                    .map_err(Error::Oom)?;
+                Err(Error::InvalidSpec(msg).into())
+            }
+        }
+    }
+
+    /// Retrieves the image `source` from its registry or one of the registry's mirrors.
+    ///
+    /// `skopeo` tries the mirrors itself, as listed in a generated `registries.conf` file, so the
+    /// image is always verified under its name in the registry.
+    async fn fetch_from(
+        source: &Source<'_>,
+        config: &EngineConfig,
+        progress: &Progress,
+    ) -> anyhow::Result<Self> {
+        let conf_dir = tempfile::tempdir_in(&config.storage_dir)?;
+        let registries_conf = conf_dir.path().join("registries.conf");
+        let conf = render_registries_conf(source.registry, config)?;
+        tokio::fs::write(&registries_conf, conf).await?;
+
+        let digest = match source.digest {
+            Some(digest) => tryformat!(80, "{}", digest).map_err(Error::Oom)?,
+            None => resolve_digest(source, config, &registries_conf).await?,
+        };
 
-        let image_src = tryformat!(64, "docker://docker.io/{}:{}", name, tag)
-            .map_err(|e| anyhow!("OOM error: {:?}", e))?;
+        let src_dir = tempfile::tempdir_in(&config.storage_dir)?;
+        let location = source.location(&digest)?;
+        let verification = check_policy(config, DOCKER_TRANSPORT, &location).await?;
+        let image_src = tryformat!(256, "docker://{}", location).map_err(Error::Oom)?;
 
-        let image_dest = tryformat!(256, "oci:{}:{}", src_dir.path().display(), tag)
-            .map_err(|e| anyhow!("OOM error: {:?}", e))?;
+        let image_dest = tryformat!(256, "oci:{}:{}", src_dir.path().display(), LAYOUT_REF)
+            .map_err(Error::Oom)?;
 
         info!("fetching OCI image `{}` -> `{}`", image_src, image_dest);
 
-        let mut fetch_cmd = Command::new(SKOPEO_BIN);
-        let output = fetch_cmd
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .args(&["copy", &image_src, &image_dest])
-            .output()
-            .await?;
+        let mut fetch_cmd = skopeo_remote(config, source.platform, "copy", &registries_conf);
+        let output = fetch_cmd.args(&[&image_src, &image_dest]).output();
+
+        // Poll the size of the destination while `skopeo` is running.
+        tokio::pin!(output);
+        let mut ticks = tokio::time::interval(PROGRESS_INTERVAL);
+        let output = loop {
+            tokio::select! {
+                result = &mut output => break result?,
+                _ = ticks.tick() => {
+                    let bytes = blobs_size(src_dir.path()).await.unwrap_or(0);
+                    progress.set_bytes(bytes);
+                }
+            }
+        };
 
         if !output.status.success() {
             let stderr = String::from_utf8(output.stderr)?;
+            policy_rejection(&image_src, &stderr)?;
             return Err(anyhow!(
                 "failed to fetch container, `{:?}` returned non-zero exit status: [{}]",
                 fetch_cmd,
@@ -48,28 +202,800 @@ impl OciImage {
             ));
         }
 
-        Ok(OciImage(src_dir))
+        let manifest = read_manifest(src_dir.path(), LAYOUT_REF).await?;
+        if !manifest.platform.satisfies(source.platform) {
+            warn!(
+                "image `{}` is built for {} rather than {}",
+                image_src, manifest.platform, source.platform
+            );
+        }
+
+        Ok(OciImage {
+            dir: src_dir,
+            id: manifest.digest,
+            info: ImageInfo {
+                name: source.name()?,
+                digest,
+                platform: manifest.platform,
+            },
+            from_registry: true,
+            verification,
+        })
     }
 
-    /// Unpacks the downloaded image into a runnable form.
-    pub async fn unpack(self) -> anyhow::Result<OciBundle> {
-        OciBundle::unpack_from(self.0.path()).await
+    /// Copies the image at a local `skopeo` transport `source`, e.g. `oci-archive:/tmp/app.tar` or
+    /// `oci:/srv/images/app:1.0`, into the storage directory and names it `name`.
+    ///
+    /// If the image is a multi-architecture index, the image for `platform` is selected from it.
+    /// If a signature policy is configured, the image must satisfy it for its transport.
+    ///
+    /// Returns `Err` if the image is rejected by the signature policy, could not be copied, or if
+    /// an I/O error occurred.
+    pub(crate) async fn import(
+        source: &str,
+        name: String,
+        platform: &Platform,
+        config: &EngineConfig,
+    ) -> anyhow::Result<Self> {
+        let (transport, location) = match source.find(':') {
+            Some(colon) => (&source[..colon], &source[colon + 1..]),
+            None => (source, ""),
+        };
+        let verification = check_policy(config, transport, location).await?;
+
+        let dest_dir = tempfile::tempdir_in(&config.storage_dir)?;
+        let image_dest = tryformat!(256, "oci:{}:{}", dest_dir.path().display(), LAYOUT_REF)
+            .map_err(Error::Oom)?;
+
+        info!("importing OCI image `{}` -> `{}`", source, image_dest);
+
+        let mut import_cmd = skopeo(config, platform, None, "copy");
+        let output = import_cmd.args(&[source, &image_dest]).output().await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8(output.stderr)?;
+            policy_rejection(source, &stderr)?;
+            return Err(anyhow!(
+                "failed to import image, `{:?}` returned non-zero exit status: [{}]",
+                import_cmd,
+                stderr
+            ));
+        }
+
+        let mut image = OciImage::from_layout(dest_dir, name).await?;
+        image.verification = verification;
+        Ok(image)
+    }
+
+    /// Takes over the OCI image layout in `dir` holding an image under [`LAYOUT_REF`], e.g. one
+    /// committed from a container, and names it `name`.
+    ///
+    /// The image is not verified against the signature policy.
+    ///
+    /// Returns `Err` if the layout has no such image, or if an I/O error occurred.
+    pub(crate) async fn from_layout(dir: TempDir, name: String) -> anyhow::Result<Self> {
+        let manifest = read_manifest(dir.path(), LAYOUT_REF).await?;
+        Ok(OciImage {
+            dir,
+            id: manifest.digest.clone(),
+            info: ImageInfo {
+                name,
+                digest: manifest.digest,
+                platform: manifest.platform,
+            },
+            from_registry: false,
+            verification: None,
+        })
+    }
+
+    /// Returns the resolved origin of the image.
+    pub fn info(&self) -> &ImageInfo {
+        &self.info
+    }
+
+    /// Returns the digest of the platform-specific image manifest, which identifies the image.
+    ///
+    /// This differs from [`ImageInfo::digest`] for multi-architecture images, whose registry
+    /// digest refers to the index of all platforms.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the digest of the image in the registry it was fetched from, or `None` if it was
+    /// imported from a local archive or layout.
+    pub(crate) fn registry_digest(&self) -> Option<&str> {
+        if self.from_registry {
+            Some(&self.info.digest)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the outcome of verifying the image against the configured signature policy, or
+    /// `None` if no policy is configured or the image was not verified, e.g. a committed one.
+    pub(crate) fn verification(&self) -> Option<&Verification> {
+        self.verification.as_ref()
+    }
+
+    /// Moves the fetched OCI image layout to `dest`, e.g. to keep it in the image store.
+    ///
+    /// Returns `Err` if an I/O error occurred, e.g. `dest` already exists.
+    pub(crate) async fn persist(self, dest: &Path) -> io::Result<()> {
+        tokio::fs::rename(self.dir.path(), dest).await?;
+
+        // The layout has moved away, so there is nothing left to clean up.
+        let _ = self.dir.into_path();
+        Ok(())
+    }
+
+    /// Returns the total size of the fetched image blobs, in bytes.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    pub async fn size(&self) -> anyhow::Result<u64> {
+        Ok(blobs_size(self.dir.path()).await?)
     }
 }
 
+/// Fetches OCI images and unpacks them into runtime bundles.
+#[async_trait]
+pub(crate) trait ImageBackend: Debug + Send + Sync {
+    /// Retrieves the image `reference` for `platform` into the storage directory, like
+    /// [`OciImage::fetch`].
+    async fn fetch(
+        &self,
+        reference: &str,
+        platform: &Platform,
+        config: &EngineConfig,
+        progress: &Progress,
+    ) -> anyhow::Result<OciImage>;
+
+    /// Unpacks the image stored under [`LAYOUT_REF`] in the OCI image layout at `image_dir` into
+    /// a new bundle inside the storage directory.
+    async fn unpack(&self, image_dir: &Path, config: &EngineConfig) -> anyhow::Result<OciBundle>;
+}
+
+/// Fetches images from registries with `skopeo`, and unpacks them with `umoci`.
+#[derive(Debug, Default)]
+pub(crate) struct CliImageBackend;
+
+#[async_trait]
+impl ImageBackend for CliImageBackend {
+    async fn fetch(
+        &self,
+        reference: &str,
+        platform: &Platform,
+        config: &EngineConfig,
+        progress: &Progress,
+    ) -> anyhow::Result<OciImage> {
+        OciImage::fetch(reference, platform, config, progress).await
+    }
+
+    async fn unpack(&self, image_dir: &Path, config: &EngineConfig) -> anyhow::Result<OciBundle> {
+        let umoci = &config.binaries.umoci;
+        OciBundle::unpack_from(image_dir, umoci, &config.storage_dir).await
+    }
+}
+
+/// Writes empty images without any layers, and unpacks them into bundles with an empty root
+/// filesystem and configuration, for testing engine logic without a registry or `umoci`.
+///
+/// The digests in the image layouts are made up rather than SHA-256 hashes of their blobs.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct FakeImageBackend;
+
+#[cfg(test)]
+#[async_trait]
+impl ImageBackend for FakeImageBackend {
+    async fn fetch(
+        &self,
+        reference: &str,
+        platform: &Platform,
+        config: &EngineConfig,
+        _progress: &Progress,
+    ) -> anyhow::Result<OciImage> {
+        use serde_json::json;
+
+        let dir = tempfile::tempdir_in(&config.storage_dir)?;
+        let image_config = json!({
+            "os": platform.os,
+            "architecture": platform.architecture,
+            "variant": platform.variant,
+            "rootfs": { "type": "layers", "diff_ids": [] },
+        });
+        let config_digest = write_fake_blob(dir.path(), &image_config).await?;
+
+        let manifest = json!({
+            "schemaVersion": 2,
+            "config": { "digest": config_digest },
+            "layers": [],
+        });
+        let manifest_digest = write_fake_blob(dir.path(), &manifest).await?;
+
+        let index = json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "digest": manifest_digest,
+                "annotations": { REF_NAME_ANNOTATION: LAYOUT_REF },
+            }],
+        });
+        tokio::fs::write(dir.path().join("index.json"), index.to_string()).await?;
+        let layout = json!({ "imageLayoutVersion": "1.0.0" });
+        tokio::fs::write(dir.path().join("oci-layout"), layout.to_string()).await?;
+
+        // Name the image after the first registry searched, like it was fetched from there.
+        let name = qualified_names(reference, config)?.remove(0);
+        OciImage::from_layout(dir, name).await
+    }
+
+    async fn unpack(&self, image_dir: &Path, config: &EngineConfig) -> anyhow::Result<OciBundle> {
+        read_manifest(image_dir, LAYOUT_REF).await?;
+        let bundle = OciBundle::empty_in(&config.storage_dir)?;
+        tokio::fs::create_dir(bundle.bundle_dir.join("rootfs")).await?;
+        Ok(bundle)
+    }
+}
+
+/// Writes `value` as a blob into the OCI image layout at `image_dir`, under a digest made up from
+/// its contents.
+#[cfg(test)]
+async fn write_fake_blob(image_dir: &Path, value: &serde_json::Value) -> io::Result<String> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let bytes = value.to_string();
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    let hex = format!("{:064x}", hasher.finish());
+
+    let blobs_dir = image_dir.join("blobs").join("sha256");
+    tokio::fs::create_dir_all(&blobs_dir).await?;
+    tokio::fs::write(blobs_dir.join(&hex), bytes).await?;
+    Ok(format!("sha256:{}", hex))
+}
+
+/// An image to fetch from a registry or one of its mirrors.
+#[derive(Debug)]
+struct Source<'a> {
+    registry: &'a str,
+    repository: &'a str,
+    tag: &'a str,
+    digest: Option<&'a str>,
+    platform: &'a Platform,
+}
+
+impl Source<'_> {
+    /// Returns the fully-qualified reference of the image.
+    fn name(&self) -> Result<String, Error> {
+        qualified_name(self.registry, self.repository, self.tag, self.digest)
+    }
+
+    /// Returns the fully-qualified reference of the image pinned to `digest`, which is fetched
+    /// and verified under that name even if it comes from a mirror.
+    fn location(&self, digest: &str) -> Result<String, Error> {
+        qualified_name(self.registry, self.repository, self.tag, Some(digest))
+    }
+}
+
+/// A `registries.conf` file for `skopeo`, listing the mirrors of a registry and whether each
+/// host is insecure.
+#[derive(Serialize)]
+struct RegistriesConf<'a> {
+    registry: Vec<RegistryHost<'a>>,
+}
+
+/// A registry or mirror in a `registries.conf` file.
+#[derive(Serialize)]
+struct RegistryHost<'a> {
+    location: &'a str,
+    insecure: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mirror: Vec<RegistryHost<'a>>,
+}
+
+impl<'a> RegistryHost<'a> {
+    /// Returns the entry for the host at `location`, without mirrors.
+    fn new(location: &'a str, config: &EngineConfig) -> Self {
+        RegistryHost {
+            location,
+            insecure: config.registry.is_insecure(location),
+            mirror: Vec::new(),
+        }
+    }
+}
+
+/// Renders the `registries.conf` file for fetching from `registry`, whose configured mirrors
+/// `skopeo` then tries in order before the registry itself.
+///
+/// Returns `Err` if the file could not be rendered.
+fn render_registries_conf(registry: &str, config: &EngineConfig) -> anyhow::Result<String> {
+    let mirrors = config.registry.mirrors.get(registry).into_iter().flatten();
+    let conf = RegistriesConf {
+        registry: vec![RegistryHost {
+            mirror: mirrors
+                .map(|mirror| RegistryHost::new(mirror, config))
+                .collect(),
+            ..RegistryHost::new(registry, config)
+        }],
+    };
+    Ok(toml::to_string(&conf)?)
+}
+
+/// Returns the fully-qualified references an image `spec` may be stored under, in the order the
+/// configured registries are searched.
+///
+/// Names not qualified with a registry are looked up under `localhost/` last, where local images
+/// are kept, see [`local_name`], so local images never shadow those from a registry.
+///
+/// Returns `Err` if the spec is invalid, or if an out-of-memory error was encountered.
+pub(crate) fn qualified_names(spec: &str, config: &EngineConfig) -> Result<Vec<String>, Error> {
+    let (spec, digest) = split_digest(spec)?;
+    let (name, tag) = parse_container_spec(spec)?;
+    let (registry, repository) = split_registry(name);
+    let registries = match registry {
+        Some(registry) => vec![registry],
+        None => config
+            .registry
+            .search_order()
+            .chain(std::iter::once(LOCAL_REGISTRY))
+            .collect(),
+    };
+
+    registries
+        .into_iter()
+        .map(|registry| {
+            let repository = normalize_repository(registry, repository)?;
+            qualified_name(registry, &repository, tag, digest)
+        })
+        .collect()
+}
+
+/// Returns the fully-qualified name to keep a local image under, e.g. one imported from an
+/// archive.
+///
+/// As with Podman, names not qualified with a registry are placed under `localhost/`. Other
+/// registries are refused, so local images cannot pose as images from a registry.
+///
+/// Returns `Err` if the reference is invalid, pinned by digest or qualified with a registry other
+/// than `localhost`, or if an out-of-memory error was encountered.
+pub(crate) fn local_name(reference: &str) -> Result<String, Error> {
+    if reference.contains('@') {
+        let msg = tryformat!(
+            256,
+            "local image `{}` must be named by tag, not digest",
+            reference
+        )
+        .map_err(Error::Oom)?;
+        return Err(Error::InvalidSpec(msg));
+    }
+
+    let (name, tag) = parse_container_spec(reference)?;
+    let (registry, repository) = split_registry(name);
+    if let Some(registry) = registry.filter(|r| *r != LOCAL_REGISTRY) {
+        let msg = tryformat!(
+            256,
+            "local image `{}` must be kept under `{}/`, not `{}/`",
+            reference,
+            LOCAL_REGISTRY,
+            registry
+        )
+        .map_err(Error::Oom)?;
+        return Err(Error::InvalidSpec(msg));
+    }
+
+    qualified_name(LOCAL_REGISTRY, repository, tag, None)
+}
+
+/// Returns whether the fully-qualified image `name` refers to a local image, see [`local_name`].
+pub(crate) fn is_local(name: &str) -> bool {
+    split_registry(name).0 == Some(LOCAL_REGISTRY)
+}
+
+/// Returns whether the image `spec` is pinned by digest.
+///
+/// Returns `Err` if the digest is invalid.
+pub(crate) fn is_pinned(spec: &str) -> Result<bool, Error> {
+    Ok(split_digest(spec)?.1.is_some())
+}
+
+/// Returns the `skopeo` transport for reading the image in the `tar` archive at `path`, which is
+/// either an OCI image layout or a `docker save` archive.
+///
+/// Returns `Err` if the archive could not be listed, or if it is of neither kind.
+pub(crate) async fn archive_transport(path: &Path) -> anyhow::Result<String> {
+    let mut list_cmd = Command::new(TAR_BIN);
+    let output = list_cmd
+        .arg("--list")
+        .arg("--file")
+        .arg(path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let msg = "image archive is not a valid `tar` archive".into();
+        return Err(Error::InvalidSpec(msg).into());
+    }
+
+    let entries = String::from_utf8_lossy(&output.stdout);
+    let mut entries = entries.lines().map(|entry| entry.trim_start_matches("./"));
+    let transport = match entries.find(|e| *e == "oci-layout" || *e == "manifest.json") {
+        Some("oci-layout") => "oci-archive",
+        Some(_) => "docker-archive",
+        None => {
+            let msg = "image archive is neither an OCI image layout nor a `docker save` archive";
+            return Err(Error::InvalidSpec(msg.into()).into());
+        }
+    };
+
+    Ok(tryformat!(256, "{}:{}", transport, path.display()).map_err(Error::Oom)?)
+}
+
+/// Returns the fully-qualified reference of an image, which is pinned by digest if it was
+/// requested by one.
+fn qualified_name(
+    registry: &str,
+    repository: &str,
+    tag: &str,
+    digest: Option<&str>,
+) -> Result<String, Error> {
+    match digest {
+        Some(digest) => tryformat!(256, "{}/{}@{}", registry, repository, digest),
+        None => tryformat!(256, "{}/{}:{}", registry, repository, tag),
+    }
+    .map_err(Error::Oom)
+}
+
+/// Returns a `skopeo` command running `subcommand`, which resolves multi-architecture images to
+/// the image for `platform`, and reads the registries from the `registries_conf` file if set.
+fn skopeo(
+    config: &EngineConfig,
+    platform: &Platform,
+    registries_conf: Option<&Path>,
+    subcommand: &str,
+) -> Command {
+    let mut cmd = Command::new(&config.binaries.skopeo);
+    cmd.arg("--override-os")
+        .arg(&platform.os)
+        .arg("--override-arch")
+        .arg(&platform.architecture);
+    if let Some(ref variant) = platform.variant {
+        cmd.arg("--override-variant").arg(variant);
+    }
+
+    if let Some(ref policy) = config.registry.policy {
+        cmd.arg("--policy").arg(policy);
+    }
+
+    if let Some(registries_conf) = registries_conf {
+        cmd.arg("--registries-conf").arg(registries_conf);
+    }
+
+    cmd.arg(subcommand)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    cmd
+}
+
+/// Evaluates the configured signature policy for the image at `location` of `transport`, which
+/// `skopeo` enforces while copying it.
+///
+/// Returns `Ok(None)` if no policy is configured, or `Err` if the policy could not be loaded or
+/// rejects the image outright.
+async fn check_policy(
+    config: &EngineConfig,
+    transport: &str,
+    location: &str,
+) -> Result<Option<Verification>, Error> {
+    match config.registry.policy {
+        Some(ref path) => Ok(Some(Policy::load(path).await?.check(transport, location)?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns `Err` if the `stderr` of a failed `skopeo copy` of `image` reports that the signature
+/// policy rejected it, e.g. due to a missing or invalid signature.
+fn policy_rejection(image: &str, stderr: &str) -> Result<(), Error> {
+    match stderr.find(POLICY_REJECTED) {
+        Some(start) => {
+            let reason = stderr[start..].trim();
+            let msg = tryformat!(512, "image `{}` failed verification: {}", image, reason)
+                .map_err(Error::Oom)?;
+            Err(Error::Untrusted(msg))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Returns a `skopeo` command like [`skopeo`] running against a registry and its mirrors, as
+/// listed in the `registries.conf` file at `registries_conf`, with the TLS settings and the
+/// registry credentials applied.
+fn skopeo_remote(
+    config: &EngineConfig,
+    platform: &Platform,
+    subcommand: &str,
+    registries_conf: &Path,
+) -> Command {
+    let mut cmd = skopeo(config, platform, Some(registries_conf), subcommand);
+
+    // Insecure hosts are listed in `registries.conf`, unless TLS is not verified at all.
+    if !config.registry.tls_verify {
+        match subcommand {
+            "copy" => cmd.arg("--src-tls-verify=false"),
+            _ => cmd.arg("--tls-verify=false"),
+        };
+    }
+
+    let auth_file = config.auth_file();
+    if auth_file.exists() {
+        cmd.arg("--authfile").arg(auth_file);
+    }
+
+    cmd
+}
+
+/// Resolves the tag of `source` to the digest of its manifest on its registry or one of the
+/// mirrors in the `registries.conf` file at `registries_conf`.
+async fn resolve_digest(
+    source: &Source<'_>,
+    config: &EngineConfig,
+    registries_conf: &Path,
+) -> anyhow::Result<String> {
+    let image_src = tryformat!(
+        256,
+        "docker://{}/{}:{}",
+        source.registry,
+        source.repository,
+        source.tag
+    )
+    .map_err(Error::Oom)?;
+
+    let mut inspect_cmd = skopeo_remote(config, source.platform, "inspect", registries_conf);
+    let output = inspect_cmd.arg(&image_src).output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8(output.stderr)?;
+        return Err(anyhow!(
+            "failed to resolve digest, `{:?}` returned non-zero exit status: [{}]",
+            inspect_cmd,
+            stderr
+        ));
+    }
+
+    #[derive(Deserialize)]
+    struct Inspect {
+        #[serde(rename = "Digest")]
+        digest: String,
+    }
+
+    let inspect: Inspect = serde_json::from_slice(&output.stdout)?;
+    debug!("resolved `{}` to {}", image_src, inspect.digest);
+    Ok(inspect.digest)
+}
+
+/// The image manifest selected in an OCI image layout.
+#[derive(Debug)]
+pub(crate) struct Manifest {
+    /// The digest of the manifest.
+    pub digest: String,
+    /// The platform recorded in the image configuration.
+    pub platform: Platform,
+}
+
+/// The `index.json` file of an OCI image layout.
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+/// A reference to a blob in an OCI image layout.
+#[derive(Deserialize)]
+struct Descriptor {
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+impl Descriptor {
+    /// Returns the name of the reference in the image layout pointing at the blob, if any.
+    fn ref_name(&self) -> Option<&str> {
+        self.annotations
+            .get(REF_NAME_ANNOTATION)
+            .map(String::as_str)
+    }
+}
+
+/// Reads the `index.json` file of the OCI image layout at `image_dir`.
+async fn read_index(image_dir: &Path) -> anyhow::Result<Index> {
+    let bytes = tokio::fs::read(image_dir.join("index.json")).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Returns the names of the references in the OCI image layout at `image_dir`.
+///
+/// Returns `Err` if the layout has no valid `index.json` file.
+pub(crate) async fn layout_refs(image_dir: &Path) -> anyhow::Result<Vec<String>> {
+    let index = read_index(image_dir).await?;
+    let refs = index.manifests.iter().filter_map(Descriptor::ref_name);
+    Ok(refs.map(str::to_owned).collect())
+}
+
+/// Reads the manifest stored under `ref_name` in the OCI image layout at `image_dir`, along with
+/// the platform from its image configuration.
+///
+/// Returns `Err` if the layout has no such manifest, the reference points at an index of several
+/// platforms, a blob is missing or malformed, or if an I/O error occurred.
+pub(crate) async fn read_manifest(image_dir: &Path, ref_name: &str) -> anyhow::Result<Manifest> {
+    #[derive(Deserialize)]
+    struct ImageManifest {
+        config: Descriptor,
+    }
+
+    #[derive(Deserialize)]
+    struct ImageConfig {
+        os: String,
+        architecture: String,
+        variant: Option<String>,
+    }
+
+    let index = read_index(image_dir).await?;
+    let descriptor = index
+        .manifests
+        .into_iter()
+        .find(|m| m.ref_name() == Some(ref_name))
+        .ok_or_else(|| anyhow!("OCI image layout has no manifest `{}`", ref_name))?;
+
+    let manifest: ImageManifest = read_blob(image_dir, &descriptor.digest).await?;
+    let config: ImageConfig = read_blob(image_dir, &manifest.config.digest).await?;
+
+    Ok(Manifest {
+        digest: descriptor.digest,
+        platform: Platform {
+            os: config.os,
+            architecture: config.architecture,
+            variant: config.variant,
+        },
+    })
+}
+
+/// Reads and parses the JSON blob with the given `digest` from the OCI image layout at
+/// `image_dir`.
+async fn read_blob<T: DeserializeOwned>(image_dir: &Path, digest: &str) -> anyhow::Result<T> {
+    let hex = match digest.strip_prefix("sha256:") {
+        Some(hex) if is_sha256_hex(hex) => hex,
+        _ => return Err(anyhow!("unsupported blob digest `{}`", digest)),
+    };
+
+    let bytes = tokio::fs::read(image_dir.join("blobs").join("sha256").join(hex)).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Copies the OCI image layout at `src_dir` into the existing directory `dest_dir`.
+///
+/// Blobs are immutable, so they are hard-linked rather than copied where possible.
+async fn copy_layout(src_dir: &Path, dest_dir: &Path) -> io::Result<()> {
+    for file in &["oci-layout", "index.json"] {
+        tokio::fs::copy(src_dir.join(file), dest_dir.join(file)).await?;
+    }
+
+    let src_blobs = src_dir.join("blobs").join("sha256");
+    let dest_blobs = dest_dir.join("blobs").join("sha256");
+    tokio::fs::create_dir_all(&dest_blobs).await?;
+
+    let mut entries = tokio::fs::read_dir(&src_blobs).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let dest = dest_blobs.join(entry.file_name());
+        if tokio::fs::hard_link(entry.path(), &dest).await.is_err() {
+            tokio::fs::copy(entry.path(), &dest).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the given `umoci` command to completion.
+///
+/// Returns `Err` if `umoci` could not be spawned or returned a non-zero exit status.
+async fn run_umoci(mut cmd: Command) -> anyhow::Result<()> {
+    let output = cmd.kill_on_drop(true).output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8(output.stderr)?;
+        return Err(anyhow!(
+            "`{:?}` returned non-zero exit status: [{}]",
+            cmd,
+            stderr
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the total size of the blobs in the OCI image layout at `image_dir`, in bytes.
+async fn blobs_size(image_dir: &Path) -> io::Result<u64> {
+    let blobs_dir = image_dir.join("blobs").join("sha256");
+    let mut entries = tokio::fs::read_dir(&blobs_dir).await?;
+
+    let mut total = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        total += entry.metadata().await?.len();
+    }
+
+    Ok(total)
+}
+
+/// Splits a trailing `@sha256:<digest>` off `spec`, if there is one.
+fn split_digest(spec: &str) -> Result<(&str, Option<&str>), Error> {
+    let (spec, digest) = match spec.find('@') {
+        Some(i) => (&spec[..i], &spec[i + 1..]),
+        None => return Ok((spec, None)),
+    };
+
+    let hex = digest.strip_prefix("sha256:").unwrap_or_default();
+    if is_sha256_hex(hex) {
+        Ok((spec, Some(digest)))
+    } else {
+        let msg = tryformat!(
+            128,
+            "invalid image digest `{}`, expected `sha256:<hex>`",
+            digest
+        )
+        .map_err(Error::Oom)?;
+        Err(Error::InvalidSpec(msg))
+    }
+}
+
+/// Returns whether `hex` is a lowercase hex-encoded SHA-256 digest.
+pub(crate) fn is_sha256_hex(hex: &str) -> bool {
+    hex.len() == SHA256_HEX_LEN
+        && hex
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
 fn parse_container_spec<'a>(spec: &'a str) -> anyhow::Result<(&'a str, &'a str)> {
-    let segments: Vec<_> = spec
-        .splitn(2, ':')
-        .try_collect()
-        .map_err(|e| anyhow!("OOM error: {:?}", e))?;
-
-    let (name, tag) = match segments[..] {
-        [name] => (name, "latest"),
-        [name, tag] => (name, tag),
-        _ => return Err(anyhow!("container specification cannot be empty")),
+    if spec.is_empty() {
+        let msg = "container specification cannot be empty".into();
+        return Err(Error::InvalidSpec(msg).into());
+    }
+
+    // The tag follows the last path component, as the registry may contain a `:port` too.
+    let last_component = spec.rfind('/').map_or(0, |i| i + 1);
+    let (name, tag) = match spec[last_component..].find(':') {
+        Some(i) => spec.split_at(last_component + i),
+        None => return Ok((spec, "latest")),
     };
 
-    Ok((name, tag))
+    Ok((name, &tag[1..]))
+}
+
+/// Splits the registry off an image `name`, if it is qualified with one.
+///
+/// As with Docker, the first path component is a registry if it looks like a host name, e.g.
+/// `registry.local:5000/app` or `localhost/app`, but not `library/alpine`.
+fn split_registry(name: &str) -> (Option<&str>, &str) {
+    match name.find('/') {
+        Some(i) => {
+            let host = &name[..i];
+            if host.contains('.') || host.contains(':') || host == "localhost" {
+                (Some(host), &name[i + 1..])
+            } else {
+                (None, name)
+            }
+        }
+        None => (None, name),
+    }
+}
+
+/// Returns the path of `repository` on `registry`, where official Docker Hub images live under
+/// `library/`.
+///
+/// Mirrors don't apply this rule themselves, so it is resolved here.
+fn normalize_repository(registry: &str, repository: &str) -> Result<String, Error> {
+    if registry == "docker.io" && !repository.contains('/') {
+        tryformat!(128, "library/{}", repository).map_err(Error::Oom)
+    } else {
+        tryformat!(128, "{}", repository).map_err(Error::Oom)
+    }
 }
 
 /// A directory containing an unpacked OCI image.
@@ -82,36 +1008,48 @@ pub struct OciBundle {
     pub bundle_dir: PathBuf,
     /// Path to the `exits` subdirectory, containing any previous exits recorded by `conmon`.
     pub exits_dir: PathBuf,
+    /// Path to the `persist` subdirectory, containing the `oom` marker file written by `conmon`.
+    pub persist_dir: PathBuf,
     /// Path to the container log file.
     pub log_file: PathBuf,
     /// Path to the running container PID file.
     pub pid_file: PathBuf,
+    /// Path to the OCI image layout the bundle was unpacked from, if it has been kept.
+    pub image_dir: Option<PathBuf>,
+    /// The lease keeping the stored image the bundle was unpacked from, if any.
+    pub(crate) lease: Option<ImageLease>,
 }
 
 impl OciBundle {
     #[instrument]
-    async fn unpack_from(oci_src: &Path) -> anyhow::Result<Self> {
+    pub(crate) async fn unpack_from(
+        oci_src: &Path,
+        umoci: &Path,
+        storage_dir: &Path,
+    ) -> anyhow::Result<Self> {
         debug_assert!(oci_src.exists());
         debug_assert!(oci_src.is_dir());
 
         // Create new base directory and subdirectory paths for unpacked image.
-        let base_dir = tempfile::tempdir()?;
+        let base_dir = tempfile::tempdir_in(storage_dir)?;
         let bundle_dir = base_dir.path().join("bundle");
         let exits_dir = base_dir.path().join("exits");
+        let persist_dir = base_dir.path().join("persist");
         let pid_file = base_dir.path().join("container.pid");
         let log_file = base_dir.path().join("container.log");
 
         info!("unpacking OCI image `{:?}` -> `{:?}`", oci_src, bundle_dir);
 
-        let image_flag = tryformat!(256, "--image={}:latest", oci_src.display())
-            .map_err(|e| anyhow!("OOM error: {:?}", e))?;
+        let image_flag =
+            tryformat!(256, "--image={}:{}", oci_src.display(), LAYOUT_REF).map_err(Error::Oom)?;
 
         // Unpack the image into the `bundle` subdirectory.
-        let mut unpack_cmd = Command::new(UMOCI_BIN);
+        let mut unpack_cmd = Command::new(umoci);
         let output = unpack_cmd
             .args(&["unpack", "--rootless"])
             .arg(image_flag)
             .arg(&bundle_dir)
+            .kill_on_drop(true)
             .output()
             .await?;
 
@@ -126,20 +1064,131 @@ impl OciBundle {
 
         // Create the `exits` subdirectory so it can be used by `conmon` later.
         tokio::fs::create_dir(&exits_dir).await?;
+        tokio::fs::create_dir(&persist_dir).await?;
 
         Ok(OciBundle {
             base_dir,
             bundle_dir,
             exits_dir,
+            persist_dir,
             log_file,
             pid_file,
+            image_dir: None,
+            lease: None,
         })
     }
 
+    /// Commits the changes to the root filesystem of the bundle as a new layer on top of its
+    /// image, along with the configuration `changes`, into the OCI image layout at `dest_dir`.
+    ///
+    /// The committed image is stored under [`LAYOUT_REF`], while the bundle itself is left as is.
+    ///
+    /// Returns `Err` if the image layout of the bundle has not been kept, `umoci` failed, or if an
+    /// I/O error occurred.
+    pub(crate) async fn commit(
+        &self,
+        umoci: &Path,
+        dest_dir: &Path,
+        changes: &CommitOptions,
+    ) -> anyhow::Result<()> {
+        let image_dir = self
+            .image_dir
+            .as_deref()
+            .ok_or_else(|| anyhow!("the image of the bundle has not been kept"))?;
+
+        info!(
+            "committing bundle `{:?}` -> `{:?}`",
+            self.bundle_dir, dest_dir
+        );
+        copy_layout(image_dir, dest_dir).await?;
+
+        let image_flag =
+            tryformat!(256, "--image={}:{}", dest_dir.display(), LAYOUT_REF).map_err(Error::Oom)?;
+
+        let mut repack_cmd = Command::new(umoci);
+        repack_cmd
+            .arg("repack")
+            .arg(&image_flag)
+            .arg(format!("--history.created_by={}", COMMIT_CREATED_BY))
+            .arg(&self.bundle_dir);
+        run_umoci(repack_cmd).await?;
+
+        if changes.has_config_changes() {
+            let mut config_cmd = Command::new(umoci);
+            config_cmd
+                .arg("config")
+                .arg(&image_flag)
+                .arg(format!("--history.created_by={}", COMMIT_CREATED_BY));
+
+            if let Some(ref entrypoint) = changes.entrypoint {
+                config_cmd.arg("--clear=config.entrypoint");
+                for arg in entrypoint {
+                    config_cmd.arg(format!("--config.entrypoint={}", arg));
+                }
+            }
+
+            for var in &changes.env {
+                config_cmd.arg(format!("--config.env={}", var));
+            }
+
+            for (key, value) in &changes.labels {
+                config_cmd.arg(format!("--config.label={}={}", key, value));
+            }
+
+            run_umoci(config_cmd).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Modifies the OCI runtime configuration (`config.json`) of the bundle in place.
+    ///
+    /// Returns `Err` if the configuration could not be parsed, or if an I/O error occurred.
+    pub async fn update_config<F>(&self, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut serde_json::Value),
+    {
+        let config_file = self.bundle_dir.join("config.json");
+        let bytes = tokio::fs::read(&config_file).await?;
+        let mut config = serde_json::from_slice(&bytes)?;
+        f(&mut config);
+        tokio::fs::write(&config_file, serde_json::to_vec(&config)?).await?;
+        Ok(())
+    }
+
     /// Returns the base directory path.
     pub(crate) fn base_dir(&self) -> &Path {
         self.base_dir.path()
     }
+
+    /// Creates a bundle with an empty configuration in a new temporary directory, for testing.
+    #[cfg(test)]
+    pub(crate) fn empty() -> anyhow::Result<Self> {
+        OciBundle::empty_in(&std::env::temp_dir())
+    }
+
+    /// Creates a bundle with an empty configuration in a new temporary directory inside `dir`,
+    /// for testing.
+    #[cfg(test)]
+    pub(crate) fn empty_in(dir: &Path) -> anyhow::Result<Self> {
+        let base_dir = tempfile::tempdir_in(dir)?;
+        let bundle = OciBundle {
+            bundle_dir: base_dir.path().join("bundle"),
+            exits_dir: base_dir.path().join("exits"),
+            persist_dir: base_dir.path().join("persist"),
+            log_file: base_dir.path().join("container.log"),
+            pid_file: base_dir.path().join("container.pid"),
+            image_dir: None,
+            lease: None,
+            base_dir,
+        };
+
+        std::fs::create_dir(&bundle.bundle_dir)?;
+        std::fs::create_dir(&bundle.exits_dir)?;
+        std::fs::create_dir(&bundle.persist_dir)?;
+        std::fs::write(bundle.bundle_dir.join("config.json"), b"{}")?;
+        Ok(bundle)
+    }
 }
 
 #[cfg(test)]
@@ -148,6 +1197,11 @@ mod tests {
 
     const BUSYBOX_OCI_IMAGE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/busybox");
 
+    async fn unpack_busybox() -> anyhow::Result<OciBundle> {
+        let oci_src = Path::new(BUSYBOX_OCI_IMAGE);
+        OciBundle::unpack_from(oci_src, Path::new(UMOCI_BIN), &std::env::temp_dir()).await
+    }
+
     #[test]
     fn parses_spec_name_only() {
         match parse_container_spec("alpine").expect("could not parse spec") {
@@ -164,11 +1218,236 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parses_spec_with_registry_port() {
+        match parse_container_spec("registry.local:5000/app").expect("could not parse spec") {
+            ("registry.local:5000/app", "latest") => {}
+            (name, tag) => panic!("unexpectedly parsed: ({}, {})", name, tag),
+        }
+
+        match parse_container_spec("registry.local:5000/app:1.0").expect("could not parse spec") {
+            ("registry.local:5000/app", "1.0") => {}
+            (name, tag) => panic!("unexpectedly parsed: ({}, {})", name, tag),
+        }
+    }
+
+    #[test]
+    fn splits_digest_from_spec() {
+        let digest = "sha256:4b6ea4c5b8d4e5a1a86c0b3ac5b2a0e1bd1b5c1f0f1b9c5f2d5d8a0e1c2b3a4f";
+        let spec = format!("alpine:3.12@{}", digest);
+        assert_eq!(split_digest(&spec).unwrap(), ("alpine:3.12", Some(digest)));
+        assert_eq!(split_digest("alpine:3.12").unwrap(), ("alpine:3.12", None));
+
+        assert!(split_digest("alpine@sha256:1234").is_err());
+        assert!(split_digest("alpine@md5:4b6ea4c5b8d4e5a1a86c0b3ac5b2a0e1").is_err());
+        let upper = spec.to_uppercase();
+        assert!(split_digest(&upper).is_err());
+    }
+
+    #[test]
+    fn splits_registry_from_name() {
+        assert_eq!(split_registry("alpine"), (None, "alpine"));
+        assert_eq!(split_registry("library/alpine"), (None, "library/alpine"));
+        assert_eq!(
+            split_registry("quay.io/coreos/etcd"),
+            (Some("quay.io"), "coreos/etcd")
+        );
+        assert_eq!(
+            split_registry("registry.local:5000/app"),
+            (Some("registry.local:5000"), "app")
+        );
+        assert_eq!(split_registry("localhost/app"), (Some("localhost"), "app"));
+
+        assert_eq!(
+            normalize_repository("docker.io", "alpine").unwrap(),
+            "library/alpine"
+        );
+        assert_eq!(normalize_repository("quay.io", "alpine").unwrap(), "alpine");
+    }
+
+    #[test]
+    fn resolves_qualified_names() {
+        let mut config = EngineConfig::default();
+        config.registry.search = vec!["registry.local:5000".into(), "docker.io".into()];
+
+        let names = qualified_names("alpine:3.12", &config).unwrap();
+        assert_eq!(
+            names,
+            [
+                "registry.local:5000/alpine:3.12",
+                "docker.io/library/alpine:3.12",
+                "localhost/alpine:3.12"
+            ]
+        );
+
+        let digest = "sha256:4b6ea4c5b8d4e5a1a86c0b3ac5b2a0e1bd1b5c1f0f1b9c5f2d5d8a0e1c2b3a4f";
+        let spec = format!("quay.io/coreos/etcd:v3.4@{}", digest);
+        let names = qualified_names(&spec, &config).unwrap();
+        assert_eq!(names, [format!("quay.io/coreos/etcd@{}", digest)]);
+    }
+
+    #[test]
+    fn names_local_images() {
+        assert_eq!(local_name("app").unwrap(), "localhost/app:latest");
+        assert_eq!(local_name("app:1.0").unwrap(), "localhost/app:1.0");
+        assert_eq!(local_name("localhost/app").unwrap(), "localhost/app:latest");
+        assert!(is_local(&local_name("app").unwrap()));
+
+        let e = local_name("docker.io/alpine:3.12").unwrap_err();
+        assert_eq!(e.code(), "invalid_spec");
+        assert!(!is_local("docker.io/library/alpine:3.12"));
+
+        let digest = "sha256:4b6ea4c5b8d4e5a1a86c0b3ac5b2a0e1bd1b5c1f0f1b9c5f2d5d8a0e1c2b3a4f";
+        let e = local_name(&format!("app@{}", digest)).unwrap_err();
+        assert_eq!(e.code(), "invalid_spec");
+    }
+
     #[tokio::test]
-    async fn unpacks_image_correctly() {
-        let bundle = OciBundle::unpack_from(Path::new(BUSYBOX_OCI_IMAGE))
+    async fn reads_manifest_from_layout() {
+        let image_dir = Path::new(BUSYBOX_OCI_IMAGE);
+        assert_eq!(layout_refs(image_dir).await.unwrap(), ["latest"]);
+
+        let manifest = read_manifest(image_dir, "latest").await.unwrap();
+        assert_eq!(
+            manifest.digest,
+            "sha256:694de93787c3f1b8e1b61f4a8bd1bb555138e33b14b67a08237c3c3d678654ea"
+        );
+        assert_eq!(manifest.platform.to_string(), "linux/amd64");
+    }
+
+    #[tokio::test]
+    async fn copies_layout() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        copy_layout(Path::new(BUSYBOX_OCI_IMAGE), dest_dir.path())
+            .await
+            .expect("failed to copy layout");
+
+        let original = read_manifest(Path::new(BUSYBOX_OCI_IMAGE), "latest")
+            .await
+            .unwrap();
+        let copied = read_manifest(dest_dir.path(), "latest").await.unwrap();
+        assert_eq!(copied.digest, original.digest);
+        assert_eq!(copied.platform, original.platform);
+    }
+
+    #[tokio::test]
+    async fn commits_bundle_changes() {
+        use serde_json::{json, Value};
+
+        let mut bundle = unpack_busybox().await.expect("failed to unpack bundle");
+        bundle.image_dir = Some(PathBuf::from(BUSYBOX_OCI_IMAGE));
+        std::fs::write(bundle.bundle_dir.join("rootfs").join("committed"), "v2").unwrap();
+
+        let changes = CommitOptions {
+            reference: "app:v2".into(),
+            entrypoint: Some(vec!["/bin/sh".into(), "-c".into()]),
+            env: vec!["MODE=prod".into()],
+            labels: vec![("stage".to_string(), "prod".to_string())]
+                .into_iter()
+                .collect(),
+        };
+        let dest_dir = tempfile::tempdir().unwrap();
+        bundle
+            .commit(Path::new(UMOCI_BIN), dest_dir.path(), &changes)
             .await
-            .expect("failed to unpack bundle");
+            .expect("failed to commit bundle");
+
+        let original_dir = Path::new(BUSYBOX_OCI_IMAGE);
+        let original = read_manifest(original_dir, LAYOUT_REF).await.unwrap();
+        let committed = read_manifest(dest_dir.path(), LAYOUT_REF).await.unwrap();
+        assert_ne!(committed.digest, original.digest);
+        assert_eq!(committed.platform, original.platform);
+
+        let layers = |manifest: &Value| manifest["layers"].as_array().unwrap().len();
+        let original: Value = read_blob(original_dir, &original.digest).await.unwrap();
+        let manifest: Value = read_blob(dest_dir.path(), &committed.digest).await.unwrap();
+        assert_eq!(layers(&manifest), layers(&original) + 1);
+
+        let config_digest = manifest["config"]["digest"].as_str().unwrap();
+        let config: Value = read_blob(dest_dir.path(), config_digest).await.unwrap();
+        assert_eq!(config["config"]["Entrypoint"], json!(["/bin/sh", "-c"]));
+        let env = config["config"]["Env"].as_array().unwrap();
+        assert!(env.contains(&json!("MODE=prod")));
+        assert_eq!(config["config"]["Labels"]["stage"], "prod");
+        assert_eq!(
+            config["history"].as_array().unwrap().last().unwrap()["created_by"],
+            COMMIT_CREATED_BY
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_mirrored_images_under_registry_name() {
+        let policy = tempfile::NamedTempFile::new().unwrap();
+        let text = r#"{
+            "default": [{ "type": "insecureAcceptAnything" }],
+            "transports": {
+                "docker": {
+                    "docker.io/library": [{
+                        "type": "signedBy",
+                        "keyType": "GPGKeys",
+                        "keyPath": "/etc/pki/containers/library.gpg"
+                    }]
+                }
+            }
+        }"#;
+        std::fs::write(policy.path(), text).unwrap();
+
+        let mut config = EngineConfig::default();
+        config.registry.policy = Some(policy.path().into());
+        config.registry.insecure = vec!["cache.local:5000".into()];
+        config
+            .registry
+            .mirrors
+            .insert("docker.io".into(), vec!["cache.local:5000".into()]);
+
+        let platform = Platform::host();
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let source = Source {
+            registry: "docker.io",
+            repository: "library/alpine",
+            tag: "3.12",
+            digest: None,
+            platform: &platform,
+        };
+        let location = source.location(&digest).unwrap();
+        assert_eq!(location, format!("docker.io/library/alpine@{}", digest));
+
+        let verification = check_policy(&config, DOCKER_TRANSPORT, &location)
+            .await
+            .expect("failed to check policy")
+            .expect("policy not configured");
+        assert_eq!(verification.scope.as_deref(), Some("docker.io/library"));
+        assert!(verification.signed);
+
+        let conf = render_registries_conf("docker.io", &config).unwrap();
+        let conf: toml::Value = toml::from_str(&conf).unwrap();
+        let registry = &conf["registry"][0];
+        assert_eq!(registry["location"].as_str(), Some("docker.io"));
+        assert_eq!(registry["insecure"].as_bool(), Some(false));
+        assert_eq!(
+            registry["mirror"][0]["location"].as_str(),
+            Some("cache.local:5000")
+        );
+        assert_eq!(registry["mirror"][0]["insecure"].as_bool(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn updates_bundle_config() {
+        let bundle = unpack_busybox().await.expect("failed to unpack bundle");
+
+        bundle
+            .update_config(|config| config["linux"]["cgroupsPath"] = "/engine/test".into())
+            .await
+            .expect("failed to update config");
+
+        let bytes = std::fs::read(bundle.bundle_dir.join("config.json")).unwrap();
+        let config: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(config["linux"]["cgroupsPath"], "/engine/test");
+    }
+
+    #[tokio::test]
+    async fn unpacks_image_correctly() {
+        let bundle = unpack_busybox().await.expect("failed to unpack bundle");
 
         assert!(bundle.bundle_dir.exists());
         assert!(bundle.bundle_dir.is_dir());
@@ -188,6 +1467,9 @@ mod tests {
         assert!(bundle.exits_dir.exists());
         assert!(bundle.exits_dir.is_dir());
 
+        assert!(bundle.persist_dir.exists());
+        assert!(bundle.persist_dir.is_dir());
+
         assert!(!bundle.log_file.exists());
         assert!(!bundle.pid_file.exists());
     }
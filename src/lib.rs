@@ -2,70 +2,568 @@
 
 #![deny(missing_debug_implementations)]
 
+pub use self::auth::{Access, Scope};
+pub use self::cgroup::{CpuStats, IoStats, MemoryStats, NetworkStats, PidsStats, Stats};
+pub use self::config::{Binaries, EngineConfig, MonitorKind, RegistryConfig, ResourceLimits};
 pub use self::container::{State, Status};
+pub use self::credentials::Credentials;
+pub use self::error::Error;
+pub use self::health::{Health, HealthCheck, HealthStatus, Probe, ProbeResult};
+pub use self::image::ImageInfo;
+pub use self::listener::{ListenAddr, Listener, SocketPermissions};
+pub use self::monitor::{ConmonMonitor, FakeMonitor, Monitor, Monitored, NativeMonitor};
+pub use self::operation::{Operation, OperationError, OperationStatus, Phase};
+pub use self::platform::Platform;
+pub use self::policy::{Requirement, Verification};
+pub use self::runtime::{CliRuntime, FakeRuntime, Runtime};
+pub use self::store::{Image, PullPolicy};
+pub use self::tls::{TlsClient, TlsConfig};
 
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::anyhow;
+use bytes::Buf;
 use dashmap::DashMap;
-use fallible_collections::tryformat;
-use tracing::{debug, info};
+use fallible_collections::{tryformat, vec::TryCollect};
+use futures_util::stream::{Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
 use warp::{Filter, Reply};
 
+use self::archive::ArchiveStream;
+use self::auth::TokenStore;
+use self::cgroup::CgroupTree;
 use self::container::Container;
-use self::image::OciImage;
+use self::credentials::CredentialStore;
+use self::image::{CliImageBackend, ImageBackend, OciImage};
+use self::lock::NameLocks;
+use self::metrics::{ContainerSample, Metrics};
+use self::operation::{Operations, Progress};
+use self::policy::Policy;
+use self::store::{ImageStore, IMAGES_DIR};
+use self::tls::TlsServer;
 
+mod archive;
+mod auth;
+mod cgroup;
+mod config;
 mod container;
+mod credentials;
+mod error;
+mod health;
 mod image;
+mod listener;
+mod lock;
+mod metrics;
+mod monitor;
+mod operation;
 mod pipe;
+mod platform;
+mod policy;
 mod rest;
+mod runtime;
+mod store;
+mod tls;
+
+/// Name of the state subdirectory containing container checkpoints.
+const CHECKPOINTS_DIR: &str = "checkpoints";
+
+/// Maximum length of a container name given separately from its image.
+const MAX_NAME_LEN: usize = 64;
+
+/// A container which may be shared between concurrent requests.
+///
+/// Inspecting the container takes the read lock, while changing its lifecycle takes the write
+/// lock, so that e.g. pausing and deleting it cannot interleave. Deleting the container leaves
+/// `None` behind for any requests still waiting on the lock.
+type SharedContainer = Arc<RwLock<Option<Container>>>;
+
+/// Options for creating a new container.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CreateOptions {
+    /// The image to create the container from, e.g. `alpine:3.12`, in which case the container
+    /// name must be a plain identifier, e.g. `web-1`. Otherwise, the container name is used as
+    /// the image reference.
+    pub image: Option<String>,
+    /// Whether to restore the container from its last checkpoint instead of starting it afresh.
+    pub restore: bool,
+    /// The health check to run against the container, if any.
+    pub health: Option<HealthCheck>,
+    /// The name of the OCI runtime to run the container with, instead of the engine default.
+    pub runtime: Option<String>,
+    /// The platform to select from a multi-architecture image, instead of the engine default.
+    pub platform: Option<Platform>,
+    /// When to pull the image instead of using the one in the image store.
+    pub pull: PullPolicy,
+    /// Whether to start the container once it has been created, which is the default.
+    ///
+    /// Otherwise, the container is left in the `created` state until [`Engine::start`] is called.
+    /// This is selected by the REST route rather than the request body.
+    #[serde(skip)]
+    pub start: bool,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        CreateOptions {
+            image: None,
+            restore: false,
+            health: None,
+            runtime: None,
+            platform: None,
+            pull: PullPolicy::default(),
+            start: true,
+        }
+    }
+}
+
+/// Options for committing a container into a new image.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommitOptions {
+    /// The name to store the new image under, e.g. `myapp:v2`.
+    ///
+    /// The image is kept under `localhost/`, which is the only registry the name may be qualified
+    /// with.
+    pub reference: String,
+    /// The command to replace the entrypoint of the image with, if any.
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    /// Environment variables to set in the image, as `KEY=VALUE`.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Labels to set on the image.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl CommitOptions {
+    /// Returns whether the image configuration is changed, besides the new layer.
+    pub(crate) fn has_config_changes(&self) -> bool {
+        self.entrypoint.is_some() || !self.env.is_empty() || !self.labels.is_empty()
+    }
+
+    /// Checks that the environment variables are well-formed.
+    fn validate(&self) -> Result<(), Error> {
+        match self.env.iter().find(|var| !var.contains('=')) {
+            Some(var) => {
+                let msg = tryformat!(
+                    256,
+                    "invalid environment variable `{}`, expected `KEY=VALUE`",
+                    var
+                )
+                .map_err(Error::Oom)?;
+                Err(Error::InvalidSpec(msg))
+            }
+            None => Ok(()),
+        }
+    }
+}
 
 /// The container engine service.
 ///
 /// Note that containers are kept in temporary directories and will be cleaned up automatically
-/// once this object is dropped.
+/// once this object is dropped. Only container checkpoints persist in the state directory.
 #[derive(Clone, Debug)]
 pub struct Engine {
-    containers: Arc<DashMap<String, Container>>,
+    containers: Arc<DashMap<String, SharedContainer>>,
+    creating: Arc<NameLocks>,
+    config: Arc<EngineConfig>,
+    metrics: Arc<Metrics>,
+    cgroups: Option<Arc<CgroupTree>>,
+    runtimes: Arc<[Arc<dyn Runtime>]>,
+    monitor: Arc<dyn Monitor>,
+    restarts: UnboundedSender<String>,
+    tokens: Option<Arc<TokenStore>>,
+    tls: Option<Arc<TlsServer>>,
+    operations: Arc<Operations>,
+    credentials: Arc<CredentialStore>,
+    images: Arc<ImageStore>,
+    image_backend: Arc<dyn ImageBackend>,
 }
 
 impl Engine {
-    /// Creates a new empty container engine.
-    pub fn new() -> Self {
+    /// Creates a new empty container engine from the given `config`.
+    ///
+    /// If the `cgroup` subtree cannot be set up, e.g. due to missing `cgroup` V2 delegation, the
+    /// engine falls back to letting `systemd` manage the container `cgroup`s instead.
+    ///
+    /// Returns `Err` if the configured runtime is unknown, the monitor could not be set up, the
+    /// signature policy is malformed, the storage directory could not be created, or if the token
+    /// file could not be loaded.
+    pub fn new(config: EngineConfig) -> anyhow::Result<Self> {
+        let binaries = &config.binaries;
+        let mut runtimes: Vec<Arc<dyn Runtime>> = vec![
+            Arc::new(CliRuntime::new("crun", &binaries.crun)),
+            Arc::new(CliRuntime::new("runc", &binaries.runc)),
+        ];
+
+        let default = runtimes
+            .iter()
+            .position(|r| r.name() == config.runtime)
+            .ok_or_else(|| anyhow!("unknown OCI runtime `{}`", config.runtime))?;
+        runtimes.swap(0, default);
+
+        let monitor: Arc<dyn Monitor> = match config.monitor {
+            MonitorKind::Conmon => Arc::new(ConmonMonitor::new(&binaries.conmon)),
+            MonitorKind::Native => Arc::new(NativeMonitor::new()),
+        };
+
+        if config.registry.default.is_some() {
+            warn!("`registry.default` is deprecated, list the registry in `registry.search`");
+        }
+
+        // Fail early on a malformed signature policy, rather than on every image pull.
+        if let Some(ref path) = config.registry.policy {
+            Policy::load_sync(path)?;
+        }
+
+        std::fs::create_dir_all(&config.storage_dir)?;
+        Engine::with_backends(config, runtimes, monitor)
+    }
+
+    /// Creates a new empty container engine from the given `config`, which runs containers with
+    /// the given OCI `runtimes` and supervises them with `monitor`.
+    ///
+    /// The runtime and monitor settings of `config` are ignored. Instead, the first runtime is
+    /// used by default, while the others may be selected by name with [`CreateOptions::runtime`].
+    ///
+    /// Returns `Err` if the token file or the TLS certificates and keys could not be loaded, or if
+    /// the image store could not be opened.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `runtimes` is empty.
+    pub fn with_backends(
+        config: EngineConfig,
+        runtimes: Vec<Arc<dyn Runtime>>,
+        monitor: Arc<dyn Monitor>,
+    ) -> anyhow::Result<Self> {
+        assert!(!runtimes.is_empty(), "at least one OCI runtime is required");
+
+        let tokens = match config.token_file.as_deref() {
+            Some(path) => Some(Arc::new(TokenStore::load(path)?)),
+            None => None,
+        };
+
+        // Fail early on a misconfigured TLS listener, rather than once it is served.
+        let tls = match config.tls.as_ref() {
+            Some(tls_config) => Some(Arc::new(TlsServer::new(tls_config)?)),
+            None => None,
+        };
+
+        let cgroups = match CgroupTree::init(config.cgroup_root.as_deref()) {
+            Ok(tree) => Some(Arc::new(tree)),
+            Err(e) => {
+                warn!(
+                    "cgroup subtree unavailable, falling back to systemd: {:#}",
+                    e
+                );
+                None
+            }
+        };
+
         let containers = Arc::new(DashMap::new());
         let running = containers.clone();
 
         tokio::spawn(async move {
-            if let Ok(_) = tokio::signal::ctrl_c().await {
+            if tokio::signal::ctrl_c().await.is_ok() {
                 running.clear();
                 std::process::exit(130);
             }
         });
 
-        Engine { containers }
+        let credentials = Arc::new(CredentialStore::new(config.auth_file()));
+        let images = Arc::new(ImageStore::open(config.storage_dir.join(IMAGES_DIR))?);
+        let (restarts, mut restart_requests) = mpsc::unbounded_channel::<String>();
+        let engine = Engine {
+            containers,
+            creating: Arc::new(NameLocks::default()),
+            config: Arc::new(config),
+            metrics: Arc::new(Metrics::default()),
+            cgroups,
+            runtimes: Arc::from(runtimes),
+            monitor,
+            restarts,
+            tokens,
+            tls,
+            operations: Arc::new(Operations::default()),
+            credentials,
+            images,
+            image_backend: Arc::new(CliImageBackend),
+        };
+
+        // Restart containers reported as unhealthy by their health checks. Only a weak reference
+        // is kept, so the engine and its containers are not kept alive by this task.
+        let containers = Arc::downgrade(&engine.containers);
+        tokio::spawn(async move {
+            while let Some(name) = restart_requests.recv().await {
+                let shared = match containers.upgrade() {
+                    Some(containers) => containers.get(&name).map(|shared| shared.value().clone()),
+                    None => break,
+                };
+
+                let restarted = match shared {
+                    Some(shared) => match shared.write().await.as_mut() {
+                        Some(container) => container.restart().await,
+                        None => continue,
+                    },
+                    None => continue,
+                };
+
+                if let Err(e) = restarted {
+                    warn!("failed to restart unhealthy container {}: {:#}", name, e);
+                }
+            }
+        });
+
+        Ok(engine)
     }
 
-    /// Fetches an OCI container with the bearing the given `name[:tag]` combination from Docker
-    /// Hub, unpacks the bundle into a temporary directory, and starts it.
+    /// Fetches an OCI container with the bearing the given `name[:tag]` combination from the
+    /// configured registry, unpacks the bundle into the storage directory, and starts it with the
+    /// configured default resource limits.
+    ///
+    /// The image may be pinned with `name[:tag]@sha256:<digest>`. Otherwise, the tag is resolved
+    /// to a digest at pull time. Either way, the digest is reported in the container state.
+    ///
+    /// Fetched images are kept in the image store. Stored images, e.g. from [`Engine::pull`],
+    /// are used instead of fetching them again, unless `options.pull` is
+    /// [`PullPolicy::Always`]. With [`PullPolicy::Never`], creating fails if the image is not
+    /// stored. If `options.platform` is set, that platform is selected from multi-architecture
+    /// images, instead of the configured default or the host platform.
+    ///
+    /// If a signature policy is configured, fetched images must satisfy it before they are
+    /// unpacked, while stored images must have been verified against its current requirements.
+    ///
+    /// If `options.image` is set, the container is created from that image instead, and
+    /// `container_name` must be a plain identifier.
+    /// If `options.restore` is set, the container is restored from its last checkpoint instead.
+    /// If `options.health` is set, the container health is probed periodically in the background.
+    /// If `options.runtime` is set, the container is run with the OCI runtime of that name.
+    /// If `options.start` is unset, the container is only created, but not started.
+    ///
+    /// This method is idempotent and does nothing if `container_name` already exists. Concurrent
+    /// calls for the same name wait for the first one, so the image is only pulled once.
     ///
-    /// This method is idempotent and does nothing if `container_name` already exists.
+    /// Returns `Err` if the image is not trusted by the signature policy, fetching, unpacking,
+    /// creating, or restoring the container failed, no checkpoint exists to restore from, the
+    /// runtime is unknown, a restore without starting was requested, an I/O error occurred, or if
+    /// an out-of-memory error was encountered.
+    pub async fn create(&self, container_name: &str, options: CreateOptions) -> Result<(), Error> {
+        self.create_with_progress(container_name, options, &Progress::default())
+            .await
+    }
+
+    /// Starts creating a container like [`Engine::create`] in the background, and returns the
+    /// operation tracking it.
+    ///
+    /// The operation may be polled with [`Engine::operation`] and cancelled with
+    /// [`Engine::cancel_operation`], in which case any partially created container is removed. If
+    /// `container_name` is already being created in the background, that operation is returned.
+    pub fn create_async(&self, container_name: &str, options: CreateOptions) -> Operation {
+        if let Some(operation) = self.operations.find_running("create", container_name) {
+            debug!("container {} is already being created", container_name);
+            return operation;
+        }
+
+        let engine = self.clone();
+        let name = container_name.to_owned();
+        self.operations
+            .spawn("create", container_name, move |progress| async move {
+                engine.create_with_progress(&name, options, &progress).await
+            })
+    }
+
+    /// Retrieves the current state of the long-running operation identified by `id`.
+    ///
+    /// Returns `Err` if the operation does not exist or has expired.
+    pub fn operation(&self, id: &str) -> Result<Operation, Error> {
+        self.operations.get(id)
+    }
+
+    /// Cancels the long-running operation identified by `id` if it is still running, or forgets
+    /// it if it has already finished, and returns its final state.
     ///
-    /// Returns `Err` if fetching, unpacking, or creating the container failed, an I/O error
-    /// occurred, or if an out-of-memory error was encountered.
-    pub async fn create(&self, container_name: &str) -> anyhow::Result<()> {
+    /// Returns `Err` if the operation does not exist or has expired.
+    pub fn cancel_operation(&self, id: &str) -> Result<Operation, Error> {
+        self.operations.cancel(id)
+    }
+
+    /// Creates a container like [`Engine::create`], reporting each step to `progress`.
+    async fn create_with_progress(
+        &self,
+        container_name: &str,
+        options: CreateOptions,
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        let result = self
+            .create_container(container_name, options, progress)
+            .await;
+        if result.is_err() {
+            self.metrics.observe_create_failure();
+        }
+
+        result
+    }
+
+    async fn create_container(
+        &self,
+        container_name: &str,
+        options: CreateOptions,
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        let reference = match options.image {
+            Some(ref image) => {
+                validate_name(container_name)?;
+                image.as_str()
+            }
+            None => container_name,
+        };
+
+        let _creating = self.creating.lock(container_name).await;
         if self.containers.contains_key(container_name) {
+            if options.restore {
+                let msg = tryformat!(128, "container `{}` already exists", container_name)
+                    .map_err(Error::Oom)?;
+                return Err(Error::AlreadyExists(msg));
+            }
+
             debug!("container {} already exists, skipping", container_name);
             return Ok(());
         }
 
-        let fetched_image = OciImage::fetch_from_docker_hub(container_name).await?;
-        let runtime_dir = fetched_image.unpack().await?;
-        let container = Container::create(container_name, runtime_dir).await?;
-        container.start().await?;
+        if options.restore && !options.start {
+            let msg = "restored containers are always started".into();
+            return Err(Error::InvalidSpec(msg));
+        }
+
+        let runtime = self.runtime(options.runtime.as_deref())?;
+        let checkpoint_dir = if options.restore {
+            let dir = self.checkpoint_dir(container_name)?;
+            if !dir.exists() {
+                let msg = tryformat!(128, "no checkpoint exists for `{}`", container_name)
+                    .map_err(Error::Oom)?;
+                return Err(Error::NotFound(msg));
+            }
+            Some(dir)
+        } else {
+            None
+        };
+
+        let started = Instant::now();
+        let platform = match options.platform {
+            Some(platform) => platform,
+            None => self.config.registry.platform(),
+        };
+
+        let mut names = image::qualified_names(reference, &self.config)?;
+        if self.config.registry.require_digest && !image::is_pinned(reference)? {
+            // Registry images must be pinned even when stored, unlike local ones which are not.
+            names.retain(|name| image::is_local(name));
+        }
+        let stored = match options.pull {
+            PullPolicy::Always => None,
+            PullPolicy::Missing | PullPolicy::Never => self.images.find(&names, &platform),
+        };
+        let (image, runtime_dir) = match stored {
+            Some((name, stored)) => {
+                debug!(
+                    "creating {} from stored image {}",
+                    container_name, stored.id
+                );
+                if let Some(ref path) = self.config.registry.policy {
+                    let policy = Policy::load(path).await?;
+                    policy.check_stored(&name, stored.verification.as_ref())?;
+                }
+
+                progress.set_phase(Phase::Unpacking);
+                let runtime_dir = self
+                    .images
+                    .unpack(&stored.id, &*self.image_backend, &self.config)
+                    .await
+                    .map_err(Error::image_pull)?;
+                let image = ImageInfo {
+                    name,
+                    digest: stored.digest.unwrap_or(stored.id),
+                    platform: stored.platform,
+                };
+                (image, runtime_dir)
+            }
+            None if options.pull == PullPolicy::Never => {
+                let msg = tryformat!(256, "image `{}` for {} is not stored", reference, platform)
+                    .map_err(Error::Oom)?;
+                return Err(Error::NotFound(msg));
+            }
+            None => {
+                progress.set_phase(Phase::Pulling);
+                let (fetched_image, pull_bytes) =
+                    self.fetch(reference, &platform, progress).await?;
+                progress.set_bytes(pull_bytes);
+                let image = fetched_image.info().clone();
+
+                // Unpack from the image store, so the layout is kept once for committing.
+                let stored = self.store(fetched_image).await?;
+                progress.set_phase(Phase::Unpacking);
+                let runtime_dir = self
+                    .images
+                    .unpack(&stored.id, &*self.image_backend, &self.config)
+                    .await
+                    .map_err(Error::image_pull)?;
+                (image, runtime_dir)
+            }
+        };
+        if !self.config.resources.is_empty() {
+            let resources = &self.config.resources;
+            runtime_dir
+                .update_config(|config| resources.apply_to(config))
+                .await?;
+        }
+
+        let cgroup = match self.cgroups {
+            Some(ref tree) => Some(tree.create_scope(container_name)?),
+            None => None,
+        };
+
+        progress.set_phase(Phase::Creating);
+        let mut container = match checkpoint_dir {
+            Some(dir) => {
+                let monitor = self.monitor.clone();
+                Container::restore(container_name, runtime_dir, runtime, monitor, cgroup, &dir)
+                    .await
+                    .map_err(Error::runtime)?
+            }
+            None => {
+                let monitor = self.monitor.clone();
+                let mut container =
+                    Container::create(container_name, runtime_dir, runtime, monitor, cgroup)
+                        .await
+                        .map_err(Error::runtime)?;
+                if options.start {
+                    progress.set_phase(Phase::Starting);
+                    container.start().await.map_err(Error::runtime)?;
+                }
+                container
+            }
+        };
 
-        let id = tryformat!(64, "{}", container_name).map_err(|e| anyhow!("OOM error: {:?}", e))?;
+        container.set_image(image);
+        if let Some(check) = options.health {
+            container.set_health_check(check, self.restarts.clone());
+        }
+
+        let id = tryformat!(64, "{}", container_name).map_err(Error::Oom)?;
+        let container = Arc::new(RwLock::new(Some(container)));
         self.containers.insert(id, container);
+        self.metrics.observe_create(started.elapsed());
 
         Ok(())
     }
@@ -74,10 +572,45 @@ impl Engine {
     ///
     /// Returns `Err` if the container does not exist, an I/O error occurred, or if an
     /// out-of-memory error was encountered.
-    pub async fn state(&self, container_name: &str) -> anyhow::Result<State> {
-        match self.containers.get(container_name) {
-            Some(container) => container.state().await,
-            None => return Err(anyhow!("container `{}` does not exist", container_name)),
+    pub async fn state(&self, container_name: &str) -> Result<State, Error> {
+        let shared = self.container(container_name)?;
+        let slot = shared.read().await;
+        Ok(live(&slot, container_name)?.state().await?)
+    }
+
+    /// Retrieves the current resource usage statistics of a container identified by `name[:tag]`.
+    ///
+    /// Returns `Err` if the container does not exist or is not running, an I/O error occurred, or
+    /// if an out-of-memory error was encountered.
+    pub async fn stats(&self, container_name: &str) -> Result<Stats, Error> {
+        let shared = self.container(container_name)?;
+        let slot = shared.read().await;
+        let container = live(&slot, container_name)?;
+
+        match container.state().await?.status {
+            Status::Running { .. } | Status::Paused { .. } => Ok(container.stats().await?),
+            status => Err(invalid_state(container_name, "inspect", &status)),
+        }
+    }
+
+    /// Starts a container identified by `name[:tag]` which has only been created so far.
+    ///
+    /// This method is idempotent and does nothing if the container is already running.
+    ///
+    /// Returns `Err` if the container does not exist, starting failed, an I/O error occurred, or if
+    /// an out-of-memory error was encountered.
+    pub async fn start(&self, container_name: &str) -> Result<(), Error> {
+        let shared = self.container(container_name)?;
+        let mut slot = shared.write().await;
+        let container = match slot.as_mut() {
+            Some(container) => container,
+            None => return Err(Error::container_not_found(container_name)),
+        };
+
+        match container.state().await?.status {
+            Status::Created { .. } => container.start().await.map_err(Error::runtime),
+            Status::Running { .. } => Ok(()),
+            status => Err(invalid_state(container_name, "start", &status)),
         }
     }
 
@@ -87,10 +620,15 @@ impl Engine {
     ///
     /// Returns `Err` if the container does not exist, an I/O error occurred, or if an
     /// out-of-memory error was encountered.
-    pub async fn pause(&self, container_name: &str) -> anyhow::Result<()> {
-        match self.containers.get(container_name) {
-            Some(container) => container.pause().await,
-            None => return Err(anyhow!("container `{}` does not exist", container_name)),
+    pub async fn pause(&self, container_name: &str) -> Result<(), Error> {
+        let shared = self.container(container_name)?;
+        let slot = shared.write().await;
+        let container = live(&slot, container_name)?;
+
+        match container.state().await?.status {
+            Status::Running { .. } => container.pause().await.map_err(Error::runtime),
+            Status::Paused { .. } => Ok(()),
+            status => Err(invalid_state(container_name, "pause", &status)),
         }
     }
 
@@ -100,35 +638,616 @@ impl Engine {
     ///
     /// Returns `Err` if the container does not exist, an I/O error occurred, or if an
     /// out-of-memory error was encountered.
-    pub async fn resume(&self, container_name: &str) -> anyhow::Result<()> {
-        match self.containers.get(container_name) {
-            Some(container) => container.resume().await,
-            None => return Err(anyhow!("container `{}` does not exist", container_name)),
+    pub async fn resume(&self, container_name: &str) -> Result<(), Error> {
+        let shared = self.container(container_name)?;
+        let slot = shared.write().await;
+        let container = live(&slot, container_name)?;
+
+        match container.state().await?.status {
+            Status::Paused { .. } => container.resume().await.map_err(Error::runtime),
+            Status::Running { .. } => Ok(()),
+            status => Err(invalid_state(container_name, "resume", &status)),
+        }
+    }
+
+    /// Kills the container identified by `name[:tag]`, if it is still running, and starts it again
+    /// from the same bundle.
+    ///
+    /// Returns `Err` if the container does not exist, restarting failed, an I/O error occurred, or
+    /// if an out-of-memory error was encountered.
+    pub async fn restart(&self, container_name: &str) -> Result<(), Error> {
+        let shared = self.container(container_name)?;
+        let mut slot = shared.write().await;
+        match slot.as_mut() {
+            Some(container) => container.restart().await.map_err(Error::runtime),
+            None => Err(Error::container_not_found(container_name)),
         }
     }
 
-    /// Kills and deletes the container identified by `name[:tag]`.
+    /// Kills and deletes the container identified by `name[:tag]`, along with its checkpoint.
     ///
     /// Returns `Err` if the container does not exist, an I/O error occurred, or if an
     /// out-of-memory error was encountered.
-    pub async fn delete(&self, container_name: &str) -> anyhow::Result<()> {
-        match self.containers.remove(container_name) {
-            Some((_, container)) => container.delete().await,
-            None => return Err(anyhow!("container `{}` does not exist", container_name)),
+    pub async fn delete(&self, container_name: &str) -> Result<(), Error> {
+        let result = self.delete_container(container_name).await;
+        if result.is_err() {
+            self.metrics.observe_delete_failure();
+        }
+
+        result
+    }
+
+    async fn delete_container(&self, container_name: &str) -> Result<(), Error> {
+        let started = Instant::now();
+        let shared = match self.containers.remove(container_name) {
+            Some((_, shared)) => shared,
+            None => return Err(Error::container_not_found(container_name)),
+        };
+
+        // Wait for any operations in progress, and take the container out for good.
+        let container = shared.write().await.take();
+        match container {
+            Some(container) => container.delete().await.map_err(Error::runtime)?,
+            None => return Err(Error::container_not_found(container_name)),
+        }
+
+        // Containers named after a registry reference can't have been checkpointed.
+        if let Some(checkpoint_dir) = self.checkpoint_path(container_name) {
+            if checkpoint_dir.exists() {
+                tokio::fs::remove_dir_all(&checkpoint_dir).await?;
+            }
+        }
+
+        self.prune_images().await;
+        self.metrics.observe_delete(started.elapsed());
+        Ok(())
+    }
+
+    /// Checkpoints the container identified by `name[:tag]` into the state directory, replacing
+    /// any previous checkpoint, so it may later be restored with [`CreateOptions::restore`].
+    ///
+    /// The container is stopped and deleted afterwards, keeping only its checkpoint, unless
+    /// `leave_running` is `true`.
+    ///
+    /// Returns `Err` if the container does not exist, checkpointing failed, an I/O error occurred,
+    /// or if an out-of-memory error was encountered.
+    pub async fn checkpoint(&self, container_name: &str, leave_running: bool) -> Result<(), Error> {
+        let checkpoint_dir = self.checkpoint_dir(container_name)?;
+        let parent_dir = self.config.state_dir.join(CHECKPOINTS_DIR);
+        tokio::fs::create_dir_all(&parent_dir).await?;
+
+        // Write into a temporary directory first, so a failure won't clobber the last checkpoint.
+        let image_dir = tempfile::tempdir_in(&parent_dir)?;
+        let shared = self.container(container_name)?;
+        let mut slot = shared.write().await;
+        let container = live(&slot, container_name)?;
+
+        match container.state().await?.status {
+            Status::Running { .. } | Status::Paused { .. } => container
+                .checkpoint(image_dir.path(), leave_running)
+                .await
+                .map_err(Error::runtime)?,
+            status => return Err(invalid_state(container_name, "checkpoint", &status)),
+        }
+
+        if checkpoint_dir.exists() {
+            tokio::fs::remove_dir_all(&checkpoint_dir).await?;
+        }
+
+        tokio::fs::rename(image_dir.into_path(), &checkpoint_dir).await?;
+        info!("checkpoint saved to {}", checkpoint_dir.display());
+
+        // The stopped container would only stand in the way of restoring it under the same name.
+        if !leave_running {
+            self.containers.remove(container_name);
+            if let Some(container) = slot.take() {
+                container.delete().await.map_err(Error::runtime)?;
+            }
+            self.prune_images().await;
+        }
+
+        Ok(())
+    }
+
+    /// Archives the file or directory at `path` inside the container identified by `name[:tag]`,
+    /// returning it as a streamed `tar` archive.
+    ///
+    /// Returns `Err` if the container does not exist, `path` is invalid or does not exist, an I/O
+    /// error occurred, or if an out-of-memory error was encountered.
+    pub async fn copy_from(
+        &self,
+        container_name: &str,
+        path: &str,
+    ) -> Result<ArchiveStream, Error> {
+        let shared = self.container(container_name)?;
+        let slot = shared.read().await;
+        Ok(live(&slot, container_name)?.copy_from(path).await?)
+    }
+
+    /// Exports the root filesystem of the container identified by `name[:tag]` as a flattened,
+    /// streamed `tar` archive.
+    ///
+    /// Returns `Err` if the container does not exist, or if an I/O error occurred.
+    pub async fn export_container(&self, container_name: &str) -> Result<ArchiveStream, Error> {
+        let shared = self.container(container_name)?;
+        let slot = shared.read().await;
+        Ok(live(&slot, container_name)?.export()?)
+    }
+
+    /// Extracts the streamed `tar` archive into the directory at `path` inside the container
+    /// identified by `name[:tag]`.
+    ///
+    /// Returns `Err` if the container does not exist, `path` is invalid or is not a directory, the
+    /// archive could not be extracted, an I/O error occurred, or if an out-of-memory error was
+    /// encountered.
+    pub async fn copy_to<S, B, E>(
+        &self,
+        container_name: &str,
+        path: &str,
+        archive: S,
+    ) -> Result<(), Error>
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: Buf,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let sink = {
+            let shared = self.container(container_name)?;
+            let slot = shared.read().await;
+            live(&slot, container_name)?.copy_to(path).await?
+        };
+
+        Ok(sink.write_from(archive).await?)
+    }
+
+    /// Pulls the image `reference` (`name[:tag][@sha256:<digest>]`) into the image store, so
+    /// containers may be created from it without pulling it again.
+    ///
+    /// If `platform` is set, that platform is selected from multi-architecture images, instead of
+    /// the configured default or the host platform. Pulling a tag again updates it to the image
+    /// it currently points at.
+    ///
+    /// Returns `Err` if the reference is invalid, the image could not be fetched or stored, or if
+    /// an out-of-memory error was encountered.
+    pub async fn pull(&self, reference: &str, platform: Option<Platform>) -> Result<Image, Error> {
+        let platform = platform.unwrap_or_else(|| self.config.registry.platform());
+        let (fetched_image, _) = self
+            .fetch(reference, &platform, &Progress::default())
+            .await?;
+
+        self.store(fetched_image).await
+    }
+
+    /// Fetches the image `reference` and records the pull, returning the image and its size.
+    async fn fetch(
+        &self,
+        reference: &str,
+        platform: &Platform,
+        progress: &Progress,
+    ) -> Result<(OciImage, u64), Error> {
+        let started = Instant::now();
+        let fetched = async {
+            let image = self
+                .image_backend
+                .fetch(reference, platform, &self.config, progress)
+                .await
+                .map_err(Error::image_pull)?;
+            let bytes = image.size().await?;
+            Ok((image, bytes))
+        };
+
+        match fetched.await {
+            Ok((image, bytes)) => {
+                self.metrics.observe_pull(started.elapsed(), bytes);
+                Ok((image, bytes))
+            }
+            Err(e) => {
+                self.metrics.observe_pull_failure();
+                Err(e)
+            }
+        }
+    }
+
+    /// Moves `image` into the image store, and deletes any stored images it leaves unused.
+    async fn store(&self, image: OciImage) -> Result<Image, Error> {
+        let stored = self.images.insert(image).await?;
+        self.prune_images().await;
+        Ok(stored)
+    }
+
+    /// Deletes the stored images left without names which are no longer used by any container.
+    async fn prune_images(&self) {
+        if let Err(e) = self.images.prune().await {
+            warn!("failed to remove unused images: {}", e);
+        }
+    }
+
+    /// Commits the changes to the root filesystem of the container identified by `name[:tag]`
+    /// into a new image on top of the one it was created from, and stores it under
+    /// `options.reference`.
+    ///
+    /// The new image has a single added layer, and its configuration is updated with the
+    /// entrypoint, environment variables and labels from `options`. Running containers are
+    /// paused while their changes are committed.
+    ///
+    /// Returns `Err` if the options are invalid, the container does not exist, its image was not
+    /// kept, committing failed, the image could not be stored, or if an out-of-memory error was
+    /// encountered.
+    pub async fn commit(
+        &self,
+        container_name: &str,
+        options: CommitOptions,
+    ) -> Result<Image, Error> {
+        options.validate()?;
+        let name = image::local_name(&options.reference)?;
+        let commit_dir = tempfile::tempdir_in(&self.config.storage_dir)?;
+
+        {
+            let shared = self.container(container_name)?;
+            let slot = shared.write().await;
+            live(&slot, container_name)?
+                .commit(&self.config.binaries.umoci, commit_dir.path(), &options)
+                .await
+                .map_err(Error::runtime)?;
         }
+
+        let committed = OciImage::from_layout(commit_dir, name).await?;
+        self.store(committed).await
+    }
+
+    /// Imports the image in the streamed `tar` `archive` into the image store under the name
+    /// `reference`, so containers may be created from it without any registry.
+    ///
+    /// The archive holds either an OCI image layout or the output of `docker save`, and may be
+    /// at most [`EngineConfig::max_import_size`] bytes long. The image is kept under
+    /// `localhost/`, which is searched after the configured registries when creating containers.
+    /// If `platform` is set, that platform is selected from multi-architecture images, instead of
+    /// the configured default or the host platform.
+    ///
+    /// Returns `Err` if the name is invalid or qualified with another registry, the archive could
+    /// not be received, is too large, is of neither kind or could not be imported, the image could
+    /// not be stored, or if an out-of-memory error was encountered.
+    pub async fn import<S, B, E>(
+        &self,
+        reference: &str,
+        platform: Option<Platform>,
+        archive: S,
+    ) -> Result<Image, Error>
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: Buf,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let name = image::local_name(reference)?;
+        let platform = platform.unwrap_or_else(|| self.config.registry.platform());
+
+        // `skopeo` can only read archives from files, so spool the upload first.
+        let upload_dir = tempfile::tempdir_in(&self.config.storage_dir)?;
+        let archive_path = upload_dir.path().join("image.tar");
+        let max_size = self.config.max_import_size;
+        archive::spool(archive, &archive_path, max_size).await?;
+
+        let source = image::archive_transport(&archive_path).await?;
+        let imported = OciImage::import(&source, name, &platform, &self.config)
+            .await
+            .map_err(Error::invalid_archive)?;
+        self.store(imported).await
+    }
+
+    /// Imports the OCI image layouts in the configured [`EngineConfig::preload_dir`] into the
+    /// image store, so containers may be created from them without any registry.
+    ///
+    /// Each subdirectory holds an OCI image layout, whose references are imported as
+    /// `<directory>:<ref>`, or as is if they are full names, e.g. `alpine:3.12`, and kept under
+    /// `localhost/`. Images which are already stored under that name are skipped, while failures
+    /// are logged and skipped too, as is a missing or unreadable preload directory.
+    ///
+    /// Copying many images may take a while, so this is best run in the background while the API
+    /// is already served.
+    pub async fn preload(&self) {
+        let preload_dir = match self.config.preload_dir {
+            Some(ref dir) => dir,
+            None => return,
+        };
+
+        if let Err(e) = self.preload_from(preload_dir).await {
+            warn!(
+                "failed to read preload directory {}: {}",
+                preload_dir.display(),
+                e
+            );
+        }
+    }
+
+    /// Imports the OCI image layouts in the subdirectories of `preload_dir`.
+    ///
+    /// Returns `Err` if the preload directory could not be read.
+    async fn preload_from(&self, preload_dir: &Path) -> Result<(), Error> {
+        let platform = self.config.registry.platform();
+        let mut entries = tokio::fs::read_dir(preload_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let layout_dir = entry.path();
+            let refs = match image::layout_refs(&layout_dir).await {
+                Ok(refs) => refs,
+                Err(e) => {
+                    warn!("skipping preload {}: {:#}", layout_dir.display(), e);
+                    continue;
+                }
+            };
+
+            let repository = entry.file_name().to_string_lossy().into_owned();
+            for ref_name in refs {
+                let reference = if ref_name.contains(':') || ref_name.contains('/') {
+                    Cow::from(&ref_name)
+                } else {
+                    Cow::from(format!("{}:{}", repository, ref_name))
+                };
+
+                if let Err(e) = self
+                    .preload_image(&layout_dir, &ref_name, &reference, &platform)
+                    .await
+                {
+                    warn!("failed to preload image `{}`: {}", reference, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Imports the image under `ref_name` in the OCI image layout at `layout_dir` as `reference`,
+    /// unless it is already stored under that name.
+    async fn preload_image(
+        &self,
+        layout_dir: &Path,
+        ref_name: &str,
+        reference: &str,
+        platform: &Platform,
+    ) -> Result<(), Error> {
+        let name = image::local_name(reference)?;
+
+        // Indexes of several platforms have no single manifest, so they are always imported.
+        if let Ok(manifest) = image::read_manifest(layout_dir, ref_name).await {
+            let stored = self.images.get(&manifest.digest);
+            if stored.map_or(false, |image| image.names.contains(&name)) {
+                debug!("preloaded image `{}` is already stored", name);
+                return Ok(());
+            }
+        }
+
+        let source = tryformat!(256, "oci:{}:{}", layout_dir.display(), ref_name)?;
+        let imported = OciImage::import(&source, name, platform, &self.config)
+            .await
+            .map_err(Error::image_pull)?;
+        let image = self.store(imported).await?;
+        info!("preloaded image `{}` as {}", reference, image.id);
+        Ok(())
+    }
+
+    /// Exports the stored image identified by its ID or `reference` as a streamed `tar` archive
+    /// of its OCI image layout, which may be imported again with [`Engine::import`].
+    ///
+    /// Returns `Err` if the reference is invalid, no such image is stored, or if an I/O error
+    /// occurred.
+    pub fn export_image(
+        &self,
+        reference: &str,
+        platform: Option<Platform>,
+    ) -> Result<ArchiveStream, Error> {
+        let image = self.image(reference, platform)?;
+        self.images.export(&image.id)
+    }
+
+    /// Removes the stored image identified by its ID or `reference`, which is resolved like for
+    /// [`Engine::pull`].
+    ///
+    /// A reference only removes that name from the image, while an ID removes all of its names.
+    /// The image is deleted once it has no names left, but only after all containers created from
+    /// it have been deleted.
+    ///
+    /// Returns `Err` if the reference is invalid, no such image is stored, or if an I/O error
+    /// occurred.
+    pub async fn remove_image(
+        &self,
+        reference: &str,
+        platform: Option<Platform>,
+    ) -> Result<(), Error> {
+        let (id, name) = match self.images.get(reference) {
+            Some(image) => (image.id, None),
+            None => {
+                let (name, image) = self.find_image(reference, platform)?;
+                (image.id, Some(name))
+            }
+        };
+
+        self.images.untag(&id, name.as_deref()).await?;
+        self.images.prune().await
+    }
+
+    /// Lists the images kept in the image store.
+    pub fn images(&self) -> Vec<Image> {
+        self.images.list()
+    }
+
+    /// Retrieves the stored image identified by its ID (`sha256:<hex>`) or `reference`, which is
+    /// resolved like for [`Engine::pull`].
+    ///
+    /// Returns `Err` if the reference is invalid, or if no such image is stored.
+    pub fn image(&self, reference: &str, platform: Option<Platform>) -> Result<Image, Error> {
+        if let Some(image) = self.images.get(reference) {
+            return Ok(image);
+        }
+
+        let (_, image) = self.find_image(reference, platform)?;
+        Ok(image)
+    }
+
+    /// Finds the stored image `reference` for `platform`, returning the name it was found under.
+    fn find_image(
+        &self,
+        reference: &str,
+        platform: Option<Platform>,
+    ) -> Result<(String, Image), Error> {
+        let platform = platform.unwrap_or_else(|| self.config.registry.platform());
+        let names = image::qualified_names(reference, &self.config)?;
+        match self.images.find(&names, &platform) {
+            Some(found) => Ok(found),
+            None => {
+                let msg = tryformat!(256, "image `{}` for {} does not exist", reference, platform)
+                    .map_err(Error::Oom)?;
+                Err(Error::NotFound(msg))
+            }
+        }
+    }
+
+    /// Lists the remote registries for which pull credentials are stored.
+    ///
+    /// Returns `Err` if the auth file could not be read or parsed.
+    pub async fn registries(&self) -> Result<Vec<String>, Error> {
+        self.credentials.registries().await
+    }
+
+    /// Stores `credentials` for pulling images from `registry`, e.g. `registry.example.com:5000`,
+    /// replacing any previous ones.
+    ///
+    /// Returns `Err` if the registry name or credentials are invalid, or if the auth file could not
+    /// be read or written.
+    pub async fn login(&self, registry: &str, credentials: &Credentials) -> Result<(), Error> {
+        self.credentials.login(registry, credentials).await
+    }
+
+    /// Removes the pull credentials stored for `registry`.
+    ///
+    /// Returns `Err` if no credentials are stored for the registry, or if the auth file could not
+    /// be read or written.
+    pub async fn logout(&self, registry: &str) -> Result<(), Error> {
+        self.credentials.logout(registry).await
+    }
+
+    /// Renders the engine metrics and per-container statistics in the Prometheus text format.
+    ///
+    /// Returns `Err` if an out-of-memory error was encountered.
+    pub async fn metrics(&self) -> Result<String, Error> {
+        let names: Vec<String> = self
+            .containers
+            .iter()
+            .map(|c| c.key().clone())
+            .try_collect()
+            .map_err(Error::Oom)?;
+
+        let mut samples = Vec::new();
+        for name in names {
+            // Containers may have been deleted in the meantime, so skip any that fail.
+            if let Ok(state) = self.state(&name).await {
+                let stats = self.stats(&name).await.ok();
+                samples.push(ContainerSample::new(name, &state.status, stats));
+            }
+        }
+
+        let mut text = String::new();
+        self.metrics
+            .render(&samples, &mut text)
+            .map_err(|e| Error::Internal(anyhow!("failed to render metrics: {}", e)))?;
+
+        Ok(text)
+    }
+
+    /// Returns the container identified by `name[:tag]`.
+    ///
+    /// Returns `Err` if the container does not exist.
+    fn container(&self, container_name: &str) -> Result<SharedContainer, Error> {
+        match self.containers.get(container_name) {
+            Some(shared) => Ok(shared.clone()),
+            None => Err(Error::container_not_found(container_name)),
+        }
+    }
+
+    /// Returns the OCI runtime called `name`, or the default runtime if `None`.
+    ///
+    /// Returns `Err` if no runtime of that name exists.
+    fn runtime(&self, name: Option<&str>) -> Result<Arc<dyn Runtime>, Error> {
+        let runtime = match name {
+            Some(name) => self.runtimes.iter().find(|r| r.name() == name),
+            None => self.runtimes.first(),
+        };
+
+        match runtime {
+            Some(runtime) => Ok(runtime.clone()),
+            None => {
+                let msg = tryformat!(64, "unknown OCI runtime `{}`", name.unwrap_or_default())
+                    .map_err(Error::Oom)?;
+                Err(Error::InvalidSpec(msg))
+            }
+        }
+    }
+
+    /// Returns the checkpoint directory path for the container identified by `name[:tag]`.
+    ///
+    /// Returns `Err` if the name cannot be safely used as a directory name.
+    fn checkpoint_dir(&self, container_name: &str) -> Result<PathBuf, Error> {
+        match self.checkpoint_path(container_name) {
+            Some(path) => Ok(path),
+            None => {
+                let msg = tryformat!(128, "invalid container name `{}`", container_name)
+                    .map_err(Error::Oom)?;
+                Err(Error::InvalidSpec(msg))
+            }
+        }
+    }
+
+    /// Returns the checkpoint directory path for the container identified by `name[:tag]`, or
+    /// `None` if the name cannot be safely used as a directory name, so it has no checkpoint.
+    fn checkpoint_path(&self, container_name: &str) -> Option<PathBuf> {
+        if container_name.is_empty()
+            || container_name.starts_with('.')
+            || container_name.contains('/')
+        {
+            return None;
+        }
+
+        Some(
+            self.config
+                .state_dir
+                .join(CHECKPOINTS_DIR)
+                .join(container_name),
+        )
     }
 
     /// Serves the container engine as a REST API over the given TCP socket address `addr`.
     ///
+    /// See [`Engine::serve_on()`] for serving over Unix domain sockets or sockets passed by
+    /// `systemd`.
+    ///
     /// # Endpoints
     ///
-    /// HTTP Route                      | Request body             | Description
-    /// --------------------------------|--------------------------|-------------------------------
-    /// `PUT /containers/<name>`        |                          | Fetch/create container
-    /// `GET /containers/<name>`        |                          | Get container status as JSON
-    /// `DELETE /containers/<name>`     |                          | Delete container
-    /// `PUT /containers/<name>/status` | `{ "state": "paused" }`  | Pause container execution
-    /// `PUT /containers/<name>/status` | `{ "state": "running" }` | Resume container execution
+    /// HTTP Route                                   | Request body                | Description
+    /// ---------------------------------------------|-----------------------------|------------------------------------------
+    /// `PUT /containers/<name>[?async=true]`        | `{ "restore": true }`       | Fetch/create (or restore) container
+    /// `POST /containers?name=<name>[&async=true]`  | `{ "runtime": "runc" }`     | Fetch/create container without starting it
+    /// `GET /containers/<name>`                     |                             | Get container status as JSON
+    /// `POST /containers/<name>/start`              |                             | Start created container
+    /// `POST /containers/<name>/restart`            |                             | Kill and start container again
+    /// `GET /containers/<name>/stats[?stream=true]` |                             | Get (or stream) resource usage as JSON
+    /// `DELETE /containers/<name>`                  |                             | Delete container
+    /// `PUT /containers/<name>/status`              | `{ "state": "paused" }`     | Pause container execution
+    /// `PUT /containers/<name>/status`              | `{ "state": "running" }`    | Resume container execution
+    /// `POST /containers/<name>/checkpoint`         | `{ "leave_running": true }` | Checkpoint container into state directory
+    /// `GET /containers/<name>/archive?path=<path>` |                             | Copy files out of container as `tar`
+    /// `GET /containers/<name>/export`              |                             | Export container root filesystem as `tar`
+    /// `POST /containers/<name>/commit`             | `{ "reference": "app:v2" }` | Commit container changes into stored image
+    /// `PUT /containers/<name>/archive?path=<path>` | `tar` archive               | Copy files into container
+    /// `GET /operations/<id>`                       |                             | Get background operation progress as JSON
+    /// `DELETE /operations/<id>`                    |                             | Cancel background operation
+    /// `POST /images?reference=<ref>`               |                             | Pull image into image store
+    /// `POST /images/import?reference=<ref>`        | `tar` archive               | Import OCI layout or `docker save` archive
+    /// `GET /images`                                |                             | List stored images as JSON
+    /// `GET /images/inspect?reference=<ref>`        |                             | Inspect stored image by ID or reference
+    /// `GET /images/export?reference=<ref>`         |                             | Export stored image as OCI layout `tar`
+    /// `DELETE /images?reference=<ref>`             |                             | Remove stored image by ID or reference
+    /// `GET /auth`                                  |                             | List registries with stored credentials
+    /// `PUT /auth/<registry>`                       | `{ "username": "ci", ... }` | Store registry pull credentials
+    /// `DELETE /auth/<registry>`                    |                             | Remove registry pull credentials
+    /// `GET /metrics`                               |                             | Get engine metrics in Prometheus format
     #[inline]
     pub async fn serve<A: Into<SocketAddr>>(self, addr: A) {
         let socket_addr = addr.into();
@@ -136,6 +1255,41 @@ impl Engine {
         warp::serve(self.into_filter()).run(socket_addr).await
     }
 
+    /// Serves the container engine as a REST API over an already bound `listener`.
+    ///
+    /// The endpoints are the same as for [`Engine::serve()`].
+    ///
+    /// TCP listeners are served over TLS if [`EngineConfig::tls`] is set, in which case clients
+    /// authenticated by certificate are restricted to the scopes they have been granted. Other
+    /// clients must present a bearer token if [`EngineConfig::token_file`] is set.
+    ///
+    /// Returns `Err` if the listener could not be registered with the async reactor, or if it
+    /// failed to accept connections.
+    pub async fn serve_on(self, listener: Listener) -> anyhow::Result<()> {
+        info!("serving container engine on {}", listener.local_addr()?);
+        let tls_server = self.tls.clone();
+        let filter = self.into_filter();
+
+        match listener {
+            Listener::Tcp(socket) => {
+                let incoming = listener::tcp_incoming(socket)?;
+                match tls_server {
+                    Some(server) => tls::serve(warp::service(filter), incoming, server).await?,
+                    None => {
+                        let incoming = incoming.map(Ok::<_, std::io::Error>);
+                        warp::serve(filter).run_incoming(incoming).await;
+                    }
+                }
+            }
+            Listener::Unix(socket) => {
+                let incoming = listener::unix_incoming(socket)?.map(Ok::<_, std::io::Error>);
+                warp::serve(filter).run_incoming(incoming).await;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Returns the REST API as a bare [`warp`](https://docs.rs/warp) filter for serving on other
     /// transports besides TCP.
     #[inline]
@@ -143,3 +1297,343 @@ impl Engine {
         rest::to_filter(self)
     }
 }
+
+/// Returns an error for an `operation` which is not allowed on a container in `status`.
+fn invalid_state(container_name: &str, operation: &str, status: &Status) -> Error {
+    match tryformat!(
+        128,
+        "cannot {} container `{}` while it is {}",
+        operation,
+        container_name,
+        status.as_str()
+    ) {
+        Ok(msg) => Error::InvalidState(msg),
+        Err(e) => Error::Oom(e),
+    }
+}
+
+/// Checks that `container_name` is a plain identifier, as required for containers which are
+/// named separately from their image.
+fn validate_name(container_name: &str) -> Result<(), Error> {
+    let valid = container_name.len() <= MAX_NAME_LEN
+        && container_name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && container_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+
+    if valid {
+        return Ok(());
+    }
+
+    let msg = tryformat!(
+        128,
+        "invalid container name `{}`, expected letters, digits, `_`, `.` or `-`",
+        container_name
+    )
+    .map_err(Error::Oom)?;
+    Err(Error::InvalidSpec(msg))
+}
+
+/// Returns the default state directory for the engine.
+///
+/// This is `$XDG_DATA_HOME/light-containerd`, falling back to `$HOME/.local/share/light-containerd`
+/// and finally `/var/lib/light-containerd`.
+pub fn default_state_dir() -> PathBuf {
+    if let Some(data_dir) = std::env::var_os("XDG_DATA_HOME") {
+        Path::new(&data_dir).join("light-containerd")
+    } else if let Some(home_dir) = std::env::var_os("HOME") {
+        Path::new(&home_dir).join(".local/share/light-containerd")
+    } else {
+        PathBuf::from("/var/lib/light-containerd")
+    }
+}
+
+/// Returns the container in `slot`, unless it was deleted while waiting for the lock.
+fn live<'a>(slot: &'a Option<Container>, container_name: &str) -> Result<&'a Container, Error> {
+    slot.as_ref()
+        .ok_or_else(|| Error::container_not_found(container_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::image::FakeImageBackend;
+
+    /// Creates an engine with fake backends, which keeps its state and storage inside `dir`.
+    pub(crate) fn fake_engine(dir: &TempDir) -> Engine {
+        let config = EngineConfig {
+            state_dir: dir.path().join("state"),
+            storage_dir: dir.path().join("storage"),
+            ..EngineConfig::default()
+        };
+        std::fs::create_dir_all(&config.storage_dir).unwrap();
+
+        let runtimes: Vec<Arc<dyn Runtime>> = vec![Arc::new(FakeRuntime::new())];
+        let monitor = Arc::new(FakeMonitor::new());
+        let mut engine = Engine::with_backends(config, runtimes, monitor).unwrap();
+        engine.image_backend = Arc::new(FakeImageBackend);
+        engine
+    }
+
+    async fn status(engine: &Engine, container_name: &str) -> Result<Status, Error> {
+        Ok(engine.state(container_name).await?.status)
+    }
+
+    #[tokio::test]
+    async fn fails_to_start_with_invalid_tls_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = EngineConfig {
+            state_dir: dir.path().join("state"),
+            storage_dir: dir.path().to_owned(),
+            tls: Some(TlsConfig {
+                cert: dir.path().join("missing.crt"),
+                key: dir.path().join("missing.key"),
+                ..TlsConfig::default()
+            }),
+            ..EngineConfig::default()
+        };
+
+        let runtimes: Vec<Arc<dyn Runtime>> = vec![Arc::new(FakeRuntime::new())];
+        let monitor = Arc::new(FakeMonitor::new());
+        assert!(Engine::with_backends(config, runtimes, monitor).is_err());
+    }
+
+    #[tokio::test]
+    async fn controls_container_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = fake_engine(&dir);
+
+        let options = CreateOptions {
+            start: false,
+            ..CreateOptions::default()
+        };
+        engine.create("busybox", options).await.unwrap();
+        let state = engine.state("busybox").await.unwrap();
+        assert!(matches!(state.status, Status::Created { .. }));
+        assert_eq!(
+            state.image.unwrap().name,
+            "docker.io/library/busybox:latest"
+        );
+
+        engine.start("busybox").await.unwrap();
+        engine.pause("busybox").await.unwrap();
+        let paused = status(&engine, "busybox").await.unwrap();
+        assert!(matches!(paused, Status::Paused { .. }));
+        assert!(matches!(
+            engine.start("busybox").await,
+            Err(Error::InvalidState(_))
+        ));
+
+        engine.resume("busybox").await.unwrap();
+        let running = status(&engine, "busybox").await.unwrap();
+        assert!(matches!(running, Status::Running { .. }));
+
+        engine.delete("busybox").await.unwrap();
+        let deleted = status(&engine, "busybox").await;
+        assert!(matches!(deleted, Err(Error::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn deletes_containers_named_after_registry_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = fake_engine(&dir);
+        let name = "quay.io/coreos/etcd:v3.4";
+
+        engine.create(name, CreateOptions::default()).await.unwrap();
+        engine.delete(name).await.unwrap();
+        assert!(matches!(
+            status(&engine, name).await,
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            engine.checkpoint(name, false).await,
+            Err(Error::InvalidSpec(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn names_containers_separately_from_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = fake_engine(&dir);
+        let from_busybox = || CreateOptions {
+            image: Some("busybox:1.32".into()),
+            ..CreateOptions::default()
+        };
+
+        engine.create("web-1", from_busybox()).await.unwrap();
+        engine.create("web-2", from_busybox()).await.unwrap();
+        let state = engine.state("web-2").await.unwrap();
+        assert_eq!(state.image.unwrap().name, "docker.io/library/busybox:1.32");
+
+        for name in &["busybox:1.32", ".hidden", "web/1"] {
+            let invalid = engine.create(name, from_busybox()).await;
+            assert!(matches!(invalid, Err(Error::InvalidSpec(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_paused_containers_with_failing_health_checks_paused() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = fake_engine(&dir);
+
+        // Commands executed in containers of the fake runtime always fail.
+        let check: HealthCheck = serde_json::from_value(serde_json::json!({
+            "probe": { "type": "exec", "command": ["true"] },
+            "interval": "50ms",
+            "retries": 1,
+            "restart_on_unhealthy": true
+        }))
+        .unwrap();
+        let options = CreateOptions {
+            health: Some(check),
+            ..CreateOptions::default()
+        };
+
+        engine.create("busybox", options).await.unwrap();
+        engine.pause("busybox").await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+
+        let state = engine.state("busybox").await.unwrap();
+        assert!(matches!(state.status, Status::Paused { .. }));
+        let health = state.health.unwrap();
+        assert_eq!(health.status, HealthStatus::Starting);
+        assert!(health.log.is_empty());
+    }
+
+    #[tokio::test]
+    async fn restores_checkpointed_container() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = fake_engine(&dir);
+        let restore = || CreateOptions {
+            restore: true,
+            ..CreateOptions::default()
+        };
+
+        engine
+            .create("busybox", CreateOptions::default())
+            .await
+            .unwrap();
+        engine.checkpoint("busybox", true).await.unwrap();
+        let running = status(&engine, "busybox").await.unwrap();
+        assert!(matches!(running, Status::Running { .. }));
+        let exists = engine.create("busybox", restore()).await;
+        assert!(matches!(exists, Err(Error::AlreadyExists(_))));
+
+        engine.checkpoint("busybox", false).await.unwrap();
+        let checkpointed = status(&engine, "busybox").await;
+        assert!(matches!(checkpointed, Err(Error::NotFound(_))));
+        assert!(engine.checkpoint_dir("busybox").unwrap().exists());
+
+        engine.create("busybox", restore()).await.unwrap();
+        let restored = status(&engine, "busybox").await.unwrap();
+        assert!(matches!(restored, Status::Running { .. }));
+
+        engine.delete("busybox").await.unwrap();
+        assert!(!engine.checkpoint_dir("busybox").unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn counts_failed_creations() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = fake_engine(&dir);
+
+        let options = CreateOptions {
+            restore: true,
+            ..CreateOptions::default()
+        };
+        let missing = engine.create("busybox", options).await;
+        assert!(matches!(missing, Err(Error::NotFound(_))));
+
+        let mut out = String::new();
+        engine.metrics.render(&[], &mut out).unwrap();
+        assert!(out.contains("light_containerd_failures_total{operation=\"create\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn validates_commit_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = fake_engine(&dir);
+        let options = |reference: &str, env: &[&str]| CommitOptions {
+            reference: reference.into(),
+            entrypoint: None,
+            env: env.iter().map(|var| var.to_string()).collect(),
+            labels: BTreeMap::new(),
+        };
+
+        assert!(options("app:v2", &[]).validate().is_ok());
+        assert!(!options("app:v2", &[]).has_config_changes());
+        assert!(options("app:v2", &["MODE=prod", "EMPTY="])
+            .validate()
+            .is_ok());
+        assert!(options("app:v2", &["MODE=prod"]).has_config_changes());
+        assert!(matches!(
+            options("app:v2", &["MODE"]).validate(),
+            Err(Error::InvalidSpec(_))
+        ));
+
+        let foreign = engine
+            .commit("busybox", options("docker.io/library/app:v2", &[]))
+            .await;
+        assert!(matches!(foreign, Err(Error::InvalidSpec(_))));
+        let missing = engine.commit("busybox", options("app:v2", &[])).await;
+        assert!(matches!(missing, Err(Error::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn removes_images_once_unused() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = fake_engine(&dir);
+        let reference = "docker.io/library/busybox:1.32";
+        let from_stored = || CreateOptions {
+            image: Some(reference.into()),
+            pull: PullPolicy::Never,
+            ..CreateOptions::default()
+        };
+
+        let missing = engine.create("web-1", from_stored()).await;
+        assert!(matches!(missing, Err(Error::NotFound(_))));
+
+        let image = engine.pull(reference, None).await.unwrap();
+        engine.create("web-1", from_stored()).await.unwrap();
+        engine.remove_image(reference, None).await.unwrap();
+        assert!(matches!(
+            engine.image(reference, None),
+            Err(Error::NotFound(_))
+        ));
+
+        // The layout is kept while a container created from it exists.
+        let hex = image.id.trim_start_matches("sha256:");
+        let layout_dir = dir.path().join("storage").join(IMAGES_DIR).join(hex);
+        assert!(layout_dir.exists());
+        assert_eq!(engine.images().len(), 1);
+
+        engine.delete("web-1").await.unwrap();
+        assert!(!layout_dir.exists());
+        assert!(engine.images().is_empty());
+    }
+
+    #[tokio::test]
+    async fn requires_digest_for_stored_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = fake_engine(&dir);
+        Arc::make_mut(&mut engine.config).registry.require_digest = true;
+
+        let image = engine.pull("busybox:1.32", None).await.unwrap();
+        let from_stored = |reference: &str| CreateOptions {
+            image: Some(reference.into()),
+            pull: PullPolicy::Never,
+            ..CreateOptions::default()
+        };
+
+        let by_tag = engine.create("web-1", from_stored("busybox:1.32")).await;
+        assert!(matches!(by_tag, Err(Error::NotFound(_))));
+
+        let pinned = format!("busybox@{}", image.id);
+        engine.pull(&pinned, None).await.unwrap();
+        engine.create("web-1", from_stored(&pinned)).await.unwrap();
+    }
+}
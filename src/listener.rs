@@ -0,0 +1,356 @@
+//! Listening sockets for serving the REST API over TCP and Unix domain sockets.
+
+use std::ffi::CString;
+use std::fmt::{self, Display, Formatter};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{io, mem};
+
+use anyhow::{anyhow, Context};
+use futures_util::stream::{self, Stream};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use tracing::warn;
+
+/// URI scheme prefix of Unix domain socket listen addresses.
+const UNIX_SCHEME: &str = "unix://";
+
+/// URI scheme prefix of TCP listen addresses, which may also be omitted.
+const TCP_SCHEME: &str = "tcp://";
+
+/// Delay before accepting further connections after an accept error.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// First file descriptor passed by `systemd` socket activation, see `sd_listen_fds(3)`.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// An address to serve the REST API on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// A TCP socket address, written as `127.0.0.1:8080` or `tcp://127.0.0.1:8080`.
+    Tcp(SocketAddr),
+    /// A Unix domain socket path, written as `unix:///run/light-containerd.sock`.
+    Unix(PathBuf),
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}{}", TCP_SCHEME, addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(UNIX_SCHEME) {
+            if !path.starts_with('/') {
+                return Err(anyhow!("Unix socket path `{}` must be absolute", path));
+            }
+
+            Ok(ListenAddr::Unix(path.into()))
+        } else {
+            let addr = s.strip_prefix(TCP_SCHEME).unwrap_or(s);
+            let addr = addr
+                .parse()
+                .with_context(|| format!("invalid listen address `{}`", s))?;
+            Ok(ListenAddr::Tcp(addr))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|e| de::Error::custom(format!("{:#}", e)))
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+/// Ownership and permissions applied to Unix domain sockets bound by the engine.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketPermissions {
+    /// File mode of the socket, e.g. `0o660`.
+    pub mode: Option<u32>,
+    /// Name of the group owning the socket.
+    pub group: Option<String>,
+}
+
+/// A bound listening socket, ready to be served on with [`Engine::serve_on()`].
+///
+/// [`Engine::serve_on()`]: crate::Engine::serve_on
+#[derive(Debug)]
+pub enum Listener {
+    /// A listening TCP socket.
+    Tcp(TcpListener),
+    /// A listening Unix domain socket.
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds a new listening socket to `addr`.
+    ///
+    /// Any stale socket file left at a Unix domain socket path is replaced, and `permissions` are
+    /// applied to the new one.
+    ///
+    /// Returns `Err` if the socket could not be bound or its permissions could not be set.
+    pub fn bind(addr: &ListenAddr, permissions: &SocketPermissions) -> anyhow::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("failed to bind TCP socket {}", addr))?;
+                Ok(Listener::Tcp(listener))
+            }
+            ListenAddr::Unix(path) => {
+                match std::fs::symlink_metadata(path) {
+                    Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+                    Ok(_) => return Err(anyhow!("`{}` is not a socket", path.display())),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("failed to bind Unix socket {}", path.display()))?;
+                set_permissions(path, permissions)?;
+                Ok(Listener::Unix(listener))
+            }
+        }
+    }
+
+    /// Returns the listening sockets passed to this process by `systemd` socket activation.
+    ///
+    /// This follows the `LISTEN_PID` and `LISTEN_FDS` protocol described in `sd_listen_fds(3)`,
+    /// and unsets these variables so they are not inherited by child processes. Returns an empty
+    /// list if the process was not socket activated.
+    ///
+    /// Returns `Err` if a passed file descriptor is not a TCP or Unix domain stream socket.
+    pub fn from_systemd() -> anyhow::Result<Vec<Self>> {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let fds = std::env::var("LISTEN_FDS").ok();
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+
+        let pid: u32 = match pid.and_then(|pid| pid.parse().ok()) {
+            Some(pid) => pid,
+            None => return Ok(Vec::new()),
+        };
+
+        if pid != std::process::id() {
+            return Ok(Vec::new());
+        }
+
+        let count: RawFd = fds
+            .and_then(|fds| fds.parse().ok())
+            .ok_or_else(|| anyhow!("invalid LISTEN_FDS passed by systemd"))?;
+
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .map(|fd| unsafe { Listener::from_raw_fd(fd) })
+            .collect()
+    }
+
+    /// Takes ownership of the listening socket `fd`, detecting whether it is a TCP or Unix domain
+    /// socket.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open file descriptor which is not owned by anything else.
+    unsafe fn from_raw_fd(fd: RawFd) -> anyhow::Result<Self> {
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) == -1 {
+            let e = io::Error::last_os_error();
+            return Err(anyhow!(
+                "passed file descriptor {} is not a socket: {}",
+                fd,
+                e
+            ));
+        }
+
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+
+        match libc::c_int::from(addr.ss_family) {
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(TcpListener::from_raw_fd(fd))),
+            libc::AF_UNIX => Ok(Listener::Unix(UnixListener::from_raw_fd(fd))),
+            family => Err(anyhow!(
+                "passed file descriptor {} has unsupported address family {}",
+                fd,
+                family
+            )),
+        }
+    }
+
+    /// Returns the address this socket is listening on.
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().unwrap_or_else(|| Path::new(""));
+                Ok(ListenAddr::Unix(path.into()))
+            }
+        }
+    }
+}
+
+/// Returns a stream of connections accepted on the TCP `listener`.
+///
+/// Returns `Err` if the listener could not be registered with the async reactor.
+pub(crate) fn tcp_incoming(
+    listener: TcpListener,
+) -> io::Result<impl Stream<Item = tokio::net::TcpStream> + Send> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    Ok(stream::unfold(listener, |mut listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((conn, _)) => return Some((conn, listener)),
+                Err(e) => accept_failed(e).await,
+            }
+        }
+    }))
+}
+
+/// Returns a stream of connections accepted on the Unix domain socket `listener`.
+///
+/// Returns `Err` if the listener could not be registered with the async reactor.
+pub(crate) fn unix_incoming(
+    listener: UnixListener,
+) -> io::Result<impl Stream<Item = tokio::net::UnixStream> + Send> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    Ok(stream::unfold(listener, |mut listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((conn, _)) => return Some((conn, listener)),
+                Err(e) => accept_failed(e).await,
+            }
+        }
+    }))
+}
+
+/// Logs a failure to accept a connection and backs off briefly, e.g. to let file descriptors free
+/// up after `EMFILE`.
+async fn accept_failed(e: io::Error) {
+    warn!("failed to accept connection: {}", e);
+    tokio::time::delay_for(ACCEPT_BACKOFF).await;
+}
+
+/// Applies the socket file `permissions` to the Unix domain socket at `path`.
+fn set_permissions(path: &Path, permissions: &SocketPermissions) -> anyhow::Result<()> {
+    if let Some(mode) = permissions.mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set mode of {}", path.display()))?;
+    }
+
+    if let Some(group) = permissions.group.as_deref() {
+        let gid = group_id(group)?;
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::chown(c_path.as_ptr(), libc::uid_t::MAX, gid) } == -1 {
+            let e = io::Error::last_os_error();
+            return Err(anyhow!("failed to set group of {}: {}", path.display(), e));
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks up the ID of the group with the given `name`, which may also be a numeric ID.
+fn group_id(name: &str) -> anyhow::Result<libc::gid_t> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+
+    let c_name = CString::new(name)?;
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+
+    if ret != 0 {
+        Err(anyhow!(
+            "failed to look up group `{}`: {}",
+            name,
+            io::Error::from_raw_os_error(ret)
+        ))
+    } else if result.is_null() {
+        Err(anyhow!("group `{}` does not exist", name))
+    } else {
+        Ok(group.gr_gid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_listen_addrs() {
+        let tcp: ListenAddr = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(
+            tcp,
+            ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 8080)))
+        );
+        assert_eq!("tcp://127.0.0.1:8080".parse::<ListenAddr>().unwrap(), tcp);
+
+        let unix: ListenAddr = "unix:///run/light-containerd.sock".parse().unwrap();
+        assert_eq!(unix, ListenAddr::Unix("/run/light-containerd.sock".into()));
+        assert_eq!(unix.to_string(), "unix:///run/light-containerd.sock");
+
+        assert!("unix://relative.sock".parse::<ListenAddr>().is_err());
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn binds_unix_socket_with_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = ListenAddr::Unix(dir.path().join("api.sock"));
+        let permissions = SocketPermissions {
+            mode: Some(0o600),
+            group: Some(unsafe { libc::getegid() }.to_string()),
+        };
+
+        // Binding twice replaces the stale socket file.
+        drop(Listener::bind(&addr, &permissions).unwrap());
+        let listener = Listener::bind(&addr, &permissions).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        let meta = std::fs::metadata(dir.path().join("api.sock")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn refuses_to_replace_regular_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let addr = ListenAddr::Unix(file.path().into());
+        assert!(Listener::bind(&addr, &SocketPermissions::default()).is_err());
+    }
+
+    #[test]
+    fn ignores_missing_socket_activation() {
+        assert!(Listener::from_systemd().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+//! Locks for serializing engine operations on the same name.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// A set of locks keyed by name, e.g. to let only one create of a container proceed at a time.
+///
+/// Locks are created on demand and forgotten again once nobody holds or waits for them.
+#[derive(Debug, Default)]
+pub(crate) struct NameLocks {
+    locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+impl NameLocks {
+    /// Waits until `name` is no longer locked, then locks it until the returned guard is dropped.
+    pub async fn lock(&self, name: &str) -> NameGuard<'_> {
+        // Clone the lock, so no shard lock is held while waiting for it.
+        let lock = self.locks.entry(name.to_owned()).or_default().clone();
+        let guard = lock.lock_owned().await;
+
+        NameGuard {
+            locks: self,
+            name: name.to_owned(),
+            guard: Some(guard),
+        }
+    }
+}
+
+/// A held lock on a name, which is released when dropped.
+#[derive(Debug)]
+pub(crate) struct NameGuard<'a> {
+    locks: &'a NameLocks,
+    name: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for NameGuard<'_> {
+    fn drop(&mut self) {
+        // Release the lock first, so only the map holds on to it unless others are waiting.
+        self.guard.take();
+        self.locks
+            .locks
+            .remove_if(&self.name, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serializes_holders_of_the_same_name() {
+        let locks = NameLocks::default();
+        let first = locks.lock("busybox").await;
+        let _other = locks.lock("alpine").await;
+
+        let second = locks.lock("busybox");
+        tokio::pin!(second);
+        let waiting = futures_util::poll!(second.as_mut());
+        assert!(waiting.is_pending());
+
+        drop(first);
+        let second = second.await;
+        assert!(locks.locks.contains_key("busybox"));
+
+        drop(second);
+        assert!(!locks.locks.contains_key("busybox"));
+        assert!(locks.locks.contains_key("alpine"));
+    }
+}
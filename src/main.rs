@@ -1,28 +1,226 @@
 //! Entry point for the application.
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
+use anyhow::anyhow;
 use argh::FromArgs;
+use futures_util::future;
+use light_containerd::{
+    Engine, EngineConfig, ListenAddr, Listener, MonitorKind, Platform, TlsConfig,
+};
 use tracing_subscriber::{util::SubscriberInitExt, EnvFilter};
 
 /// Lightweight OCI container engine with REST API.
 #[derive(FromArgs)]
 struct Opt {
-    /// TCP port to listen on [default: 8080]
-    #[argh(option, short = 'p', default = "8080")]
-    port: u16,
+    /// path to a TOML engine configuration file
+    #[argh(option, short = 'c')]
+    config: Option<PathBuf>,
+
+    /// TCP port to listen on, replacing the configured addresses [default: 8080]
+    #[argh(option, short = 'p')]
+    port: Option<u16>,
+
+    /// address to listen on, e.g. `127.0.0.1:8080` or `unix:///run/light-containerd.sock`; may be
+    /// repeated, replacing the configured addresses
+    #[argh(option, short = 'l')]
+    listen: Vec<ListenAddr>,
+
+    /// file mode of Unix sockets listened on, in octal, e.g. `660`
+    #[argh(option, from_str_fn(parse_mode))]
+    socket_mode: Option<u32>,
+
+    /// group owning Unix sockets listened on
+    #[argh(option)]
+    socket_group: Option<String>,
+
+    /// PEM file with the server certificate chain, enabling TLS on TCP listeners
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the server private key
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file with the CA certificates to authenticate clients against
+    #[argh(option)]
+    tls_client_ca: Option<PathBuf>,
+
+    /// TOML file listing the bearer tokens accepted by the API
+    #[argh(option)]
+    token_file: Option<PathBuf>,
+
+    /// directory for persistent engine state [default: $XDG_DATA_HOME/light-containerd]
+    #[argh(option)]
+    state_dir: Option<PathBuf>,
+
+    /// directory in which images are fetched and unpacked [default: the system temp directory]
+    #[argh(option)]
+    storage_dir: Option<PathBuf>,
+
+    /// directory of OCI image layouts to import into the image store at startup
+    #[argh(option)]
+    preload_dir: Option<PathBuf>,
+
+    /// cgroup V2 subtree to place containers in [default: the engine's own cgroup]
+    #[argh(option)]
+    cgroup_root: Option<PathBuf>,
+
+    /// default OCI runtime for containers, either `crun` or `runc` [default: crun]
+    #[argh(option)]
+    runtime: Option<String>,
+
+    /// container monitor, either `conmon` or `native` [default: conmon]
+    #[argh(option)]
+    monitor: Option<String>,
+
+    /// containers-auth.json file holding registry credentials [default: $STATE_DIR/auth.json]
+    #[argh(option)]
+    auth_file: Option<PathBuf>,
+
+    /// platform selected from multi-architecture images, e.g. `linux/arm/v7` [default: host]
+    #[argh(option)]
+    platform: Option<Platform>,
+
+    /// containers-policy.json file verifying image signatures [default: skopeo's default]
+    #[argh(option)]
+    policy: Option<PathBuf>,
+
+    /// log filter directives used if `RUST_LOG` is not set [default: error]
+    #[argh(option)]
+    log_level: Option<String>,
+}
+
+impl Opt {
+    /// Loads the engine configuration and applies the command-line overrides on top of it.
+    fn into_config(self) -> anyhow::Result<EngineConfig> {
+        let mut config = match self.config {
+            Some(path) => EngineConfig::load(&path)?,
+            None => EngineConfig::default(),
+        };
+
+        if let Some(port) = self.port {
+            config.listen = vec![SocketAddr::from(([127, 0, 0, 1], port)).into()];
+        }
+
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+
+        if let Some(mode) = self.socket_mode {
+            config.socket.mode = Some(mode);
+        }
+
+        if let Some(group) = self.socket_group {
+            config.socket.group = Some(group);
+        }
+
+        if self.tls_cert.is_some() || self.tls_key.is_some() || self.tls_client_ca.is_some() {
+            let tls = config.tls.get_or_insert_with(TlsConfig::default);
+            if let Some(cert) = self.tls_cert {
+                tls.cert = cert;
+            }
+
+            if let Some(key) = self.tls_key {
+                tls.key = key;
+            }
+
+            if let Some(client_ca) = self.tls_client_ca {
+                tls.client_ca = Some(client_ca);
+            }
+        }
+
+        if let Some(token_file) = self.token_file {
+            config.token_file = Some(token_file);
+        }
+
+        if let Some(state_dir) = self.state_dir {
+            config.state_dir = state_dir;
+        }
+
+        if let Some(storage_dir) = self.storage_dir {
+            config.storage_dir = storage_dir;
+        }
+
+        if let Some(preload_dir) = self.preload_dir {
+            config.preload_dir = Some(preload_dir);
+        }
+
+        if let Some(cgroup_root) = self.cgroup_root {
+            config.cgroup_root = Some(cgroup_root);
+        }
+
+        if let Some(runtime) = self.runtime {
+            config.runtime = runtime;
+        }
+
+        if let Some(monitor) = self.monitor {
+            config.monitor = match monitor.as_str() {
+                "conmon" => MonitorKind::Conmon,
+                "native" => MonitorKind::Native,
+                other => return Err(anyhow!("unknown container monitor `{}`", other)),
+            };
+        }
+
+        if let Some(auth_file) = self.auth_file {
+            config.registry.auth_file = Some(auth_file);
+        }
+
+        if let Some(platform) = self.platform {
+            config.registry.platform = Some(platform);
+        }
+
+        if let Some(policy) = self.policy {
+            config.registry.policy = Some(policy);
+        }
+
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parses an octal file mode given on the command line.
+fn parse_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value.trim_start_matches("0o"), 8)
+        .map_err(|e| format!("invalid socket mode `{}`: {}", value, e))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let opt: Opt = argh::from_env();
+    let config = opt.into_config()?;
+
     tracing_subscriber::FmtSubscriber::builder()
-        .with_env_filter(EnvFilter::from_default_env())
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log_level)),
+        )
         .finish()
         .try_init()?;
 
-    let Opt { port } = argh::from_env();
-    let addr: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
-    light_containerd::Engine::new().serve(addr).await;
+    // Set up the engine first, so a misconfiguration, e.g. of TLS, fails before binding.
+    let listen = config.listen.clone();
+    let socket = config.socket.clone();
+    let engine = Engine::new(config)?;
+
+    // Prefer sockets passed by systemd socket activation over the configured addresses.
+    let mut listeners = Listener::from_systemd()?;
+    if listeners.is_empty() {
+        for addr in &listen {
+            listeners.push(Listener::bind(addr, &socket)?);
+        }
+    }
+
+    // Serve the API while preloading, which may copy many images.
+    let preloader = engine.clone();
+    tokio::spawn(async move { preloader.preload().await });
+
+    // Exit on the first listener to fail, rather than carrying on with the others.
+    let servers = listeners.into_iter().map(|l| engine.clone().serve_on(l));
+    future::try_join_all(servers).await?;
 
     Ok(())
 }
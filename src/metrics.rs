@@ -0,0 +1,511 @@
+//! Engine metrics in the [Prometheus] text exposition format.
+//!
+//! [Prometheus]: https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use http::{Method, StatusCode};
+
+use crate::cgroup::Stats;
+use crate::container::Status;
+
+/// Number of buckets in each latency histogram, excluding `+Inf`.
+const BUCKET_COUNT: usize = 10;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; BUCKET_COUNT] =
+    [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Status labels reported for the `containers` gauge, even if no containers are in that state.
+const STATUS_LABELS: [&str; 5] = ["creating", "created", "running", "paused", "stopped"];
+
+/// Counters and histograms recorded by the container engine.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    create_duration: Histogram,
+    delete_duration: Histogram,
+    pull_duration: Histogram,
+    pull_bytes: AtomicU64,
+    create_failures: AtomicU64,
+    delete_failures: AtomicU64,
+    pull_failures: AtomicU64,
+    requests: DashMap<RequestKey, AtomicU64>,
+    oom_rejections: AtomicU64,
+}
+
+/// Labels identifying a REST request counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RequestKey {
+    method: &'static str,
+    route: &'static str,
+    status: u16,
+}
+
+impl Metrics {
+    /// Records the time taken to create a container.
+    pub fn observe_create(&self, elapsed: Duration) {
+        self.create_duration.observe(elapsed);
+    }
+
+    /// Records the time taken to delete a container.
+    pub fn observe_delete(&self, elapsed: Duration) {
+        self.delete_duration.observe(elapsed);
+    }
+
+    /// Records the time taken to pull an image, and the number of bytes fetched.
+    pub fn observe_pull(&self, elapsed: Duration, bytes: u64) {
+        self.pull_duration.observe(elapsed);
+        self.pull_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records a container creation which failed.
+    pub fn observe_create_failure(&self) {
+        self.create_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a container deletion which failed.
+    pub fn observe_delete_failure(&self) {
+        self.delete_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an image pull which failed.
+    pub fn observe_pull_failure(&self) {
+        self.pull_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a completed REST request.
+    pub fn observe_request(&self, method: &Method, path: &str, status: StatusCode) {
+        let key = RequestKey {
+            method: method_label(method),
+            route: route_label(path),
+            status: status.as_u16(),
+        };
+
+        if let Some(counter) = self.requests.get(&key) {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        self.requests
+            .entry(key)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a request which was rejected due to an out-of-memory error.
+    pub fn observe_oom_rejection(&self) {
+        self.oom_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders all engine metrics, plus the given per-container samples, into `out`.
+    ///
+    /// Returns `Err` if writing to `out` failed.
+    pub fn render<W: Write>(&self, containers: &[ContainerSample], out: &mut W) -> fmt::Result {
+        writeln!(
+            out,
+            "# HELP light_containerd_containers Number of containers by status."
+        )?;
+        writeln!(out, "# TYPE light_containerd_containers gauge")?;
+        for label in STATUS_LABELS.iter() {
+            let count = containers.iter().filter(|c| c.status == *label).count();
+            writeln!(
+                out,
+                "light_containerd_containers{{status=\"{}\"}} {}",
+                label, count
+            )?;
+        }
+
+        self.create_duration.render(
+            "light_containerd_container_create_duration_seconds",
+            "Time taken to fetch, create and start a container.",
+            out,
+        )?;
+
+        self.delete_duration.render(
+            "light_containerd_container_delete_duration_seconds",
+            "Time taken to delete a container.",
+            out,
+        )?;
+
+        self.pull_duration.render(
+            "light_containerd_image_pull_duration_seconds",
+            "Time taken to pull an image.",
+            out,
+        )?;
+
+        let pull_bytes = self.pull_bytes.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "# HELP light_containerd_image_pull_bytes_total Bytes fetched by image pulls."
+        )?;
+        writeln!(
+            out,
+            "# TYPE light_containerd_image_pull_bytes_total counter"
+        )?;
+        writeln!(
+            out,
+            "light_containerd_image_pull_bytes_total {}",
+            pull_bytes
+        )?;
+
+        writeln!(
+            out,
+            "# HELP light_containerd_failures_total Engine operations which failed."
+        )?;
+        writeln!(out, "# TYPE light_containerd_failures_total counter")?;
+        let failures = [
+            ("create", &self.create_failures),
+            ("delete", &self.delete_failures),
+            ("pull", &self.pull_failures),
+        ];
+        for (operation, counter) in failures.iter() {
+            writeln!(
+                out,
+                "light_containerd_failures_total{{operation=\"{}\"}} {}",
+                operation,
+                counter.load(Ordering::Relaxed)
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP light_containerd_http_requests_total REST requests handled."
+        )?;
+        writeln!(out, "# TYPE light_containerd_http_requests_total counter")?;
+        for entry in self.requests.iter() {
+            let RequestKey {
+                method,
+                route,
+                status,
+            } = entry.key();
+            let count = entry.value().load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "light_containerd_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                method, route, status, count
+            )?;
+        }
+
+        let oom_rejections = self.oom_rejections.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "# HELP light_containerd_oom_rejections_total Requests rejected due to OOM."
+        )?;
+        writeln!(out, "# TYPE light_containerd_oom_rejections_total counter")?;
+        writeln!(
+            out,
+            "light_containerd_oom_rejections_total {}",
+            oom_rejections
+        )?;
+
+        render_container_stats(containers, out)
+    }
+}
+
+/// A point-in-time sample of a single container, reported alongside the engine metrics.
+#[derive(Debug)]
+pub struct ContainerSample {
+    /// The container name.
+    pub name: String,
+    /// The container status label, e.g. `running`.
+    pub status: &'static str,
+    /// The container resource usage, if it is running.
+    pub stats: Option<Stats>,
+}
+
+impl ContainerSample {
+    /// Creates a new sample for the container `name` with the given `status` and `stats`.
+    pub fn new(name: String, status: &Status, stats: Option<Stats>) -> Self {
+        let status = match status {
+            Status::Creating => "creating",
+            Status::Created { .. } => "created",
+            Status::Running { .. } => "running",
+            Status::Paused { .. } => "paused",
+            Status::Stopped { .. } => "stopped",
+        };
+
+        ContainerSample {
+            name,
+            status,
+            stats,
+        }
+    }
+}
+
+fn render_container_stats<W: Write>(containers: &[ContainerSample], out: &mut W) -> fmt::Result {
+    type Getter = fn(&Stats) -> Option<f64>;
+
+    let series: [(&str, &str, &str, Getter); 8] = [
+        (
+            "light_containerd_container_cpu_usage_seconds_total",
+            "counter",
+            "Total CPU time consumed by the container.",
+            |s| Some(s.cpu.usage_usec as f64 / 1_000_000.0),
+        ),
+        (
+            "light_containerd_container_memory_usage_bytes",
+            "gauge",
+            "Current memory usage of the container.",
+            |s| Some(s.memory.current as f64),
+        ),
+        (
+            "light_containerd_container_memory_peak_bytes",
+            "gauge",
+            "Peak memory usage of the container.",
+            |s| s.memory.peak.map(|v| v as f64),
+        ),
+        (
+            "light_containerd_container_pids",
+            "gauge",
+            "Number of processes in the container.",
+            |s| Some(s.pids.current as f64),
+        ),
+        (
+            "light_containerd_container_io_read_bytes_total",
+            "counter",
+            "Bytes read from block devices by the container.",
+            |s| Some(s.io.read_bytes as f64),
+        ),
+        (
+            "light_containerd_container_io_write_bytes_total",
+            "counter",
+            "Bytes written to block devices by the container.",
+            |s| Some(s.io.write_bytes as f64),
+        ),
+        (
+            "light_containerd_container_network_receive_bytes_total",
+            "counter",
+            "Bytes received over the network by the container.",
+            |s| Some(s.network.rx_bytes as f64),
+        ),
+        (
+            "light_containerd_container_network_transmit_bytes_total",
+            "counter",
+            "Bytes transmitted over the network by the container.",
+            |s| Some(s.network.tx_bytes as f64),
+        ),
+    ];
+
+    for (name, kind, help, get) in series.iter() {
+        writeln!(out, "# HELP {} {}", name, help)?;
+        writeln!(out, "# TYPE {} {}", name, kind)?;
+
+        for container in containers {
+            if let Some(value) = container.stats.as_ref().and_then(get) {
+                writeln!(
+                    out,
+                    "{}{{container=\"{}\"}} {}",
+                    name,
+                    Escaped(&container.name),
+                    value
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A cumulative histogram of durations with fixed buckets.
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKET_COUNT],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render<W: Write>(&self, name: &str, help: &str, out: &mut W) -> fmt::Result {
+        writeln!(out, "# HELP {} {}", name, help)?;
+        writeln!(out, "# TYPE {} histogram", name)?;
+
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            let count = bucket.load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count)?;
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count)?;
+        writeln!(out, "{}_sum {}", name, sum)?;
+        writeln!(out, "{}_count {}", name, count)
+    }
+}
+
+/// Escapes a Prometheus label value.
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::PUT => "PUT",
+        Method::POST => "POST",
+        Method::DELETE => "DELETE",
+        _ => "OTHER",
+    }
+}
+
+/// Maps a request path onto its route template, keeping the label cardinality bounded.
+fn route_label(path: &str) -> &'static str {
+    let mut segments = path.trim_matches('/').split('/');
+    match (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) {
+        (Some("metrics"), None, _, _) => "/metrics",
+        (Some("containers"), None, _, _) => "/containers",
+        (Some("containers"), Some(_), None, _) => "/containers/{name}",
+        (Some("containers"), Some(_), Some("status"), None) => "/containers/{name}/status",
+        (Some("containers"), Some(_), Some("stats"), None) => "/containers/{name}/stats",
+        (Some("containers"), Some(_), Some("checkpoint"), None) => "/containers/{name}/checkpoint",
+        (Some("containers"), Some(_), Some("archive"), None) => "/containers/{name}/archive",
+        (Some("containers"), Some(_), Some("start"), None) => "/containers/{name}/start",
+        (Some("containers"), Some(_), Some("restart"), None) => "/containers/{name}/restart",
+        (Some("containers"), Some(_), Some("export"), None) => "/containers/{name}/export",
+        (Some("containers"), Some(_), Some("commit"), None) => "/containers/{name}/commit",
+        (Some("operations"), Some(_), None, _) => "/operations/{id}",
+        (Some("images"), None, _, _) => "/images",
+        (Some("images"), Some("import"), None, _) => "/images/import",
+        (Some("images"), Some("inspect"), None, _) => "/images/inspect",
+        (Some("images"), Some("export"), None, _) => "/images/export",
+        (Some("auth"), None, _, _) => "/auth",
+        (Some("auth"), Some(_), None, _) => "/auth/{registry}",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_paths_to_route_labels() {
+        assert_eq!(route_label("/metrics"), "/metrics");
+        assert_eq!(route_label("/containers"), "/containers");
+        assert_eq!(route_label("/containers/busybox"), "/containers/{name}");
+        assert_eq!(
+            route_label("/containers/busybox/status"),
+            "/containers/{name}/status"
+        );
+        assert_eq!(
+            route_label("/containers/busybox/stats"),
+            "/containers/{name}/stats"
+        );
+        assert_eq!(
+            route_label("/containers/busybox/restart"),
+            "/containers/{name}/restart"
+        );
+        assert_eq!(route_label("/operations/1234"), "/operations/{id}");
+        assert_eq!(route_label("/images"), "/images");
+        assert_eq!(route_label("/images/import"), "/images/import");
+        assert_eq!(route_label("/images/inspect"), "/images/inspect");
+        assert_eq!(route_label("/images/export"), "/images/export");
+        assert_eq!(
+            route_label("/containers/busybox/export"),
+            "/containers/{name}/export"
+        );
+        assert_eq!(
+            route_label("/containers/busybox/commit"),
+            "/containers/{name}/commit"
+        );
+        assert_eq!(route_label("/images/alpine:3.12"), "unknown");
+        assert_eq!(route_label("/auth/quay.io"), "/auth/{registry}");
+        assert_eq!(route_label("/containers/a/b/c"), "unknown");
+        assert_eq!(route_label("/"), "unknown");
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let escaped = Escaped("a\"b\\c\nd").to_string();
+        assert_eq!(escaped, "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn renders_cumulative_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(200));
+        histogram.observe(Duration::from_secs(3));
+
+        let mut out = String::new();
+        histogram
+            .render("latency", "Test latency.", &mut out)
+            .unwrap();
+
+        assert!(out.contains("# TYPE latency histogram\n"));
+        assert!(out.contains("latency_bucket{le=\"0.1\"} 0\n"));
+        assert!(out.contains("latency_bucket{le=\"0.25\"} 1\n"));
+        assert!(out.contains("latency_bucket{le=\"5\"} 2\n"));
+        assert!(out.contains("latency_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("latency_sum 3.2\n"));
+        assert!(out.contains("latency_count 2\n"));
+    }
+
+    #[test]
+    fn renders_engine_and_container_metrics() {
+        let metrics = Metrics::default();
+        metrics.observe_request(&Method::GET, "/containers/busybox", StatusCode::OK);
+        metrics.observe_request(&Method::GET, "/containers/alpine", StatusCode::OK);
+        metrics.observe_pull(Duration::from_secs(1), 1024);
+        metrics.observe_oom_rejection();
+        metrics.observe_pull_failure();
+
+        let mut stats = Stats::default();
+        stats.memory.current = 4096;
+
+        let containers = [
+            ContainerSample::new("busybox".into(), &Status::Running { pid: 1 }, Some(stats)),
+            ContainerSample::new("alpine".into(), &Status::Stopped { exit_code: 0 }, None),
+        ];
+
+        let mut out = String::new();
+        metrics.render(&containers, &mut out).unwrap();
+
+        assert!(out.contains("light_containerd_containers{status=\"running\"} 1\n"));
+        assert!(out.contains("light_containerd_containers{status=\"stopped\"} 1\n"));
+        assert!(out.contains("light_containerd_containers{status=\"paused\"} 0\n"));
+        assert!(out.contains(
+            "light_containerd_http_requests_total{method=\"GET\",route=\"/containers/{name}\",status=\"200\"} 2\n"
+        ));
+        assert!(out.contains("light_containerd_image_pull_bytes_total 1024\n"));
+        assert!(out.contains("light_containerd_oom_rejections_total 1\n"));
+        assert!(out.contains("light_containerd_failures_total{operation=\"pull\"} 1\n"));
+        assert!(out.contains("light_containerd_failures_total{operation=\"create\"} 0\n"));
+        assert!(out.contains(
+            "light_containerd_container_memory_usage_bytes{container=\"busybox\"} 4096\n"
+        ));
+        assert!(!out.contains("container=\"alpine\""));
+    }
+}
@@ -0,0 +1,1199 @@
+//! Types for supervising container processes once they have been created.
+
+use std::ffi::{CStr, CString};
+use std::fmt::{self, Debug, Write as _};
+use std::io::{self, Read, Write};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::SystemTime;
+use std::{mem, ptr};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use fallible_collections::tryformat;
+use libc::pid_t;
+use tokio::process::Command;
+use tokio_seqpacket::UnixSeqpacket;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::cgroup::{join_cgroup, ContainerCgroup};
+use crate::error::Error;
+use crate::image::OciBundle;
+use crate::pipe::{CommandExt, StartPipe, SyncPipe};
+use crate::runtime::Runtime;
+
+/// The default `conmon` binary.
+pub const CONMON_BIN: &str = "conmon";
+
+/// Name of the console socket created in the bundle base directory by [`NativeMonitor`].
+const CONSOLE_SOCKET: &str = "console.sock";
+
+/// Longest line written to the container log by [`NativeMonitor`], before it is split.
+const LOG_LINE_MAX: usize = 8192;
+
+/// How often [`NativeMonitor`] checks for exited children while the container is quiet.
+const POLL_INTERVAL_MS: c_int = 100;
+
+/// Number of signals whose dispositions are reset by [`NativeMonitor`].
+const NSIG: c_int = 65;
+
+/// Reports sent from the process forked by [`NativeMonitor`] to the engine. The value is the
+/// container PID, the exit status of the runtime with its output, or an `errno` with the step
+/// which failed, respectively.
+const REPORT_CREATED: u8 = 0;
+const REPORT_RUNTIME_FAILED: u8 = 1;
+const REPORT_OS_ERROR: u8 = 2;
+const REPORT_HEADER_LEN: usize = 5;
+
+/// The PID reported for every container spawned by [`FakeMonitor`].
+const FAKE_PID: pid_t = 1;
+
+/// A container monitor, which creates containers with an OCI runtime and supervises them.
+///
+/// Monitors are responsible for holding the container console, writing its output to the bundle
+/// log file, and recording the exit status in the bundle `exits` directory once it terminates.
+#[async_trait]
+pub trait Monitor: Debug + Send + Sync {
+    /// Returns the name by which this monitor is selected, e.g. `conmon`.
+    fn name(&self) -> &str;
+
+    /// Creates the container `id` from the OCI bundle `rt` with `runtime`, without starting it,
+    /// and begins monitoring it.
+    ///
+    /// If a `cgroup` scope is given, the monitor should place itself inside of it. If
+    /// `restore_from` is given, the container is restored from that checkpoint directory instead.
+    async fn spawn(
+        &self,
+        id: &str,
+        rt: &OciBundle,
+        runtime: &dyn Runtime,
+        cgroup: Option<&ContainerCgroup>,
+        restore_from: Option<&Path>,
+    ) -> anyhow::Result<Monitored>;
+}
+
+/// A container process supervised by a [`Monitor`].
+#[derive(Debug)]
+pub struct Monitored {
+    /// The PID of the container init process.
+    pub pid: pid_t,
+    /// Resources which must be kept alive for as long as the container is monitored.
+    pub guard: Box<dyn Debug + Send + Sync>,
+}
+
+/// A monitor which supervises each container with a separate `conmon` process.
+#[derive(Debug)]
+pub struct ConmonMonitor {
+    binary: PathBuf,
+}
+
+impl ConmonMonitor {
+    /// Creates a monitor which invokes the given `conmon` binary.
+    pub fn new<P: Into<PathBuf>>(binary: P) -> Self {
+        ConmonMonitor {
+            binary: binary.into(),
+        }
+    }
+}
+
+impl Default for ConmonMonitor {
+    fn default() -> Self {
+        ConmonMonitor::new(CONMON_BIN)
+    }
+}
+
+/// The handles kept open to a running `conmon` process.
+#[derive(Debug)]
+struct ConmonHandles {
+    uuid: Uuid,
+    console_sock: UnixSeqpacket,
+    sync_pipe: SyncPipe,
+}
+
+#[async_trait]
+impl Monitor for ConmonMonitor {
+    fn name(&self) -> &str {
+        "conmon"
+    }
+
+    async fn spawn(
+        &self,
+        id: &str,
+        rt: &OciBundle,
+        runtime: &dyn Runtime,
+        cgroup: Option<&ContainerCgroup>,
+        restore_from: Option<&Path>,
+    ) -> anyhow::Result<Monitored> {
+        let uuid = Uuid::new_v4();
+        let uuid_str = tryformat!(36, "{}", uuid).map_err(Error::Oom)?;
+
+        let bundle_dir = rt.bundle_dir.to_str().expect("$TMPDIR is invalid UTF-8");
+        let exits_dir = rt.exits_dir.to_str().expect("$TMPDIR is invalid UTF-8");
+        let persist_dir = rt.persist_dir.to_str().expect("$TMPDIR is invalid UTF-8");
+        let log_file = rt.log_file.to_str().expect("$TMPDIR is invalid UTF-8");
+        let pid_file = rt.pid_file.to_str().expect("$TMPDIR is invalid UTF-8");
+        let sock_dir = rt.base_dir().to_str().expect("$TMPDIR is invalid UTF-8");
+
+        let start_pipe = StartPipe::new()?;
+        let mut sync_pipe = SyncPipe::new()?;
+
+        // Spin up the `conmon` child process.
+        let mut conmon_cmd = Command::new(&self.binary);
+        if let Some(checkpoint_dir) = restore_from {
+            debug!("restoring from checkpoint: {}", checkpoint_dir.display());
+            conmon_cmd.arg("--restore").arg(checkpoint_dir);
+        }
+
+        match cgroup {
+            Some(scope) => scope.attach_conmon(&mut conmon_cmd)?,
+            None => {
+                conmon_cmd.arg("--systemd-cgroup"); // Required for rootless pause/resume.
+            }
+        }
+
+        let child = conmon_cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--log-level=debug")
+            .arg("--terminal") // Passes `--console-sock` to `crun`.
+            .args(&["--cid", id])
+            .args(&["--cuuid", &uuid_str])
+            .args(&["--name", id])
+            .arg("--runtime")
+            .arg(runtime.binary())
+            .args(&["--bundle", bundle_dir])
+            .args(&["--exit-dir", exits_dir])
+            .args(&["--persist-dir", persist_dir])
+            .args(&["--log-path", log_file])
+            .args(&["--container-pidfile", pid_file])
+            .args(&["--socket-dir-path", sock_dir])
+            .inherit_oci_pipes(&start_pipe, &sync_pipe)
+            .spawn()?;
+
+        debug!("spawned `conmon`, signaling ready for setup");
+        if let Err(e) = start_pipe.ready().await {
+            let output = child.wait_with_output().await?;
+            if output.status.success() {
+                return Err(e);
+            } else {
+                let stderr = String::from_utf8(output.stderr)?;
+                return Err(anyhow!(
+                    "{}, `conmon` exited with non-zero status: [{}]",
+                    e,
+                    stderr
+                ));
+            }
+        }
+
+        debug!("waiting for `conmon` to complete initial setup");
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8(output.stderr)?;
+            return Err(anyhow!(
+                "failed to create container, `conmon` exited with non-zero status: [{}]",
+                stderr
+            ));
+        }
+
+        let pid = sync_pipe.get_pid().await?;
+        debug!("received container PID from `conmon`: {}", pid);
+
+        // Setup is complete, so connect to the console socket.
+        let sock_path = rt.base_dir().join(uuid_str).join("attach");
+        debug!("connecting to console socket: {}", sock_path.display());
+        let console_sock = UnixSeqpacket::connect(&sock_path).await?;
+        debug!("connected to console socket: {}", sock_path.display());
+        info!("container has been created with PID {}", pid);
+
+        let handles = ConmonHandles {
+            uuid,
+            console_sock,
+            sync_pipe,
+        };
+
+        Ok(Monitored {
+            pid,
+            guard: Box::new(handles),
+        })
+    }
+}
+
+/// A monitor which supervises each container with a process forked from the engine.
+///
+/// Like `conmon`, the monitor double-forks so it is detached from the engine, and registers itself
+/// as a child subreaper, so the container process is reparented to it once the OCI runtime has
+/// exited. It holds the container console, or the standard output of the container if it has no
+/// terminal, copies it into the bundle log file, and records the exit status in the bundle `exits`
+/// directory. Any orphaned descendants of the container are reaped by the monitor as well.
+///
+/// The monitor does not execute a new program after forking, so it must not allocate and only
+/// makes async-signal-safe calls; everything it needs is prepared by the engine beforehand.
+///
+/// Unlike [`ConmonMonitor`], restoring containers from checkpoints is not supported, and the
+/// engine does not reattach to the containers after it restarts.
+#[derive(Debug, Default)]
+pub struct NativeMonitor(());
+
+impl NativeMonitor {
+    /// Creates a new native monitor.
+    pub fn new() -> Self {
+        NativeMonitor::default()
+    }
+}
+
+/// The handles kept open to a process forked by [`NativeMonitor`].
+#[derive(Debug)]
+struct NativeHandles {
+    /// The bundle `exits` directory, locked while the container is released.
+    exits_dir: std::fs::File,
+    /// Written to once the container is no longer monitored, so its exit status is discarded.
+    release: std::fs::File,
+}
+
+impl Drop for NativeHandles {
+    fn drop(&mut self) {
+        // Hold the lock while releasing, so the exit file of a restarted container is either
+        // written before its monitor is released, or not at all.
+        let exits_dir = self.exits_dir.as_raw_fd();
+        if unsafe { libc::flock(exits_dir, libc::LOCK_EX) } == -1 {
+            warn!("failed to lock exits dir: {}", io::Error::last_os_error());
+        }
+
+        // The monitor closes its end once it has exited, in which case there is nothing to do.
+        match (&self.release).write_all(&[0]) {
+            Err(e) if e.raw_os_error() != Some(libc::EPIPE) => {
+                warn!("failed to release container monitor: {}", e);
+            }
+            _ => {}
+        }
+
+        unsafe { libc::flock(exits_dir, libc::LOCK_UN) };
+    }
+}
+
+#[async_trait]
+impl Monitor for NativeMonitor {
+    fn name(&self) -> &str {
+        "native"
+    }
+
+    async fn spawn(
+        &self,
+        id: &str,
+        rt: &OciBundle,
+        runtime: &dyn Runtime,
+        cgroup: Option<&ContainerCgroup>,
+        restore_from: Option<&Path>,
+    ) -> anyhow::Result<Monitored> {
+        if restore_from.is_some() {
+            return Err(anyhow!(
+                "the native monitor cannot restore from checkpoints"
+            ));
+        }
+
+        // Remove the console socket of a previous run, if the container is being restarted.
+        let socket_path = rt.base_dir().join(CONSOLE_SOCKET);
+        match tokio::fs::remove_file(&socket_path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        // The runtime sends the console before exiting, so there is no need to accept the
+        // connection concurrently; it will be waiting in the backlog afterwards.
+        let listener = UnixListener::bind(&socket_path)?;
+        listener.set_nonblocking(true)?;
+
+        let argv = vec![
+            c_path(runtime.binary())?,
+            CString::new("create")?,
+            CString::new("--bundle")?,
+            c_path(&rt.bundle_dir)?,
+            CString::new("--pid-file")?,
+            c_path(&rt.pid_file)?,
+            CString::new("--console-socket")?,
+            c_path(&socket_path)?,
+            CString::new(id)?,
+        ];
+
+        let (release_reader, release) = pipe()?;
+        let spec = MonitorSpec {
+            argv,
+            pid_file: c_path(&rt.pid_file)?,
+            exit_file: c_path(&rt.exits_dir.join(id))?,
+            listener,
+            log: std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&rt.log_file)?,
+            exits_dir: std::fs::File::open(&rt.exits_dir)?,
+            release: release_reader,
+            cgroup_procs: cgroup.map(|scope| scope.open_monitor_procs()).transpose()?,
+        };
+
+        // Forking waits for the runtime to create the container, so keep it off the executor.
+        let binary = runtime.binary().to_owned();
+        let report = tokio::task::spawn_blocking(move || launch(&spec)).await??;
+        tokio::fs::remove_file(&socket_path).await?;
+
+        let pid = parse_report(&report, &binary)?;
+        info!("container has been created with PID {}", pid);
+
+        // Open the directory again, as locks are only exclusive between open file descriptions.
+        let handles = NativeHandles {
+            exits_dir: std::fs::File::open(&rt.exits_dir)?,
+            release,
+        };
+
+        Ok(Monitored {
+            pid,
+            guard: Box::new(handles),
+        })
+    }
+}
+
+/// Everything the process forked by [`NativeMonitor`] needs, prepared up front as it must not
+/// allocate.
+struct MonitorSpec {
+    /// The OCI runtime `create` command line.
+    argv: Vec<CString>,
+    pid_file: CString,
+    exit_file: CString,
+    /// The console socket passed to the runtime.
+    listener: UnixListener,
+    log: std::fs::File,
+    exits_dir: std::fs::File,
+    /// Becomes readable once the container is released by the engine.
+    release: std::fs::File,
+    /// The `cgroup.procs` file of the leaf to place the monitor in, if any.
+    cgroup_procs: Option<std::fs::File>,
+}
+
+/// The file descriptors created for a single launch of the monitor.
+struct MonitorFds {
+    report: RawFd,
+    output_reader: RawFd,
+    output_writer: RawFd,
+    dev_null: RawFd,
+}
+
+/// Reasons for which the monitor may fail to create a container.
+enum Failure {
+    /// A system call failed while doing the described step, with the given `errno`.
+    Os(&'static str, i32),
+    /// The runtime exited with the given status, after writing the given amount of output.
+    Runtime(i32, usize),
+}
+
+/// Forks the monitor process described by `spec` and waits for it to create the container.
+///
+/// Returns the report written by the monitor, or `Err` if forking failed.
+fn launch(spec: &MonitorSpec) -> anyhow::Result<Vec<u8>> {
+    let mut argv: Vec<*const c_char> = spec.argv.iter().map(|arg| arg.as_ptr()).collect();
+    argv.push(ptr::null());
+
+    let (mut report_reader, report_writer) = pipe()?;
+    let (output_reader, output_writer) = pipe()?;
+    let dev_null = std::fs::File::open("/dev/null")?;
+    let fds = MonitorFds {
+        report: report_writer.as_raw_fd(),
+        output_reader: output_reader.as_raw_fd(),
+        output_writer: output_writer.as_raw_fd(),
+        dev_null: dev_null.as_raw_fd(),
+    };
+
+    let intermediate = unsafe { libc::fork() };
+    match intermediate {
+        -1 => return Err(io::Error::last_os_error().into()),
+        0 => unsafe { fork_monitor(spec, &fds, &argv) },
+        _ => {}
+    }
+
+    // Only the monitor may hold the write end, so the report ends once it has been written.
+    drop(report_writer);
+    if wait_for_exit(intermediate)? != 0 {
+        return Err(anyhow!("failed to fork the container monitor"));
+    }
+
+    let mut report = Vec::new();
+    report_reader.read_to_end(&mut report)?;
+    Ok(report)
+}
+
+/// Interprets the `report` written by the monitor, returning the PID of the created container.
+///
+/// Returns `Err` if the monitor or the OCI runtime `binary` failed to create the container.
+fn parse_report(report: &[u8], binary: &Path) -> anyhow::Result<pid_t> {
+    if report.len() < REPORT_HEADER_LEN {
+        return Err(anyhow!("the container monitor exited unexpectedly"));
+    }
+
+    let value = i32::from_ne_bytes([report[1], report[2], report[3], report[4]]);
+    let detail = String::from_utf8_lossy(&report[REPORT_HEADER_LEN..]);
+    match report[0] {
+        REPORT_CREATED => Ok(value),
+        REPORT_RUNTIME_FAILED => Err(anyhow!(
+            "`{}` exited with status {}: [{}]",
+            binary.display(),
+            value,
+            detail.trim()
+        )),
+        _ => Err(anyhow!(
+            "the container monitor failed to {}: {}",
+            detail,
+            io::Error::from_raw_os_error(value)
+        )),
+    }
+}
+
+/// Runs in the intermediate child, forking the monitor so it is reparented away from the engine.
+unsafe fn fork_monitor(spec: &MonitorSpec, fds: &MonitorFds, argv: &[*const c_char]) -> ! {
+    match libc::fork() {
+        -1 => {
+            report(fds.report, REPORT_OS_ERROR, errno(), b"fork");
+            libc::_exit(1)
+        }
+        0 => run_monitor(spec, fds, argv),
+        _ => libc::_exit(0),
+    }
+}
+
+/// Runs in the monitor process, creating the container and supervising it until it exits.
+unsafe fn run_monitor(spec: &MonitorSpec, fds: &MonitorFds, argv: &[*const c_char]) -> ! {
+    let mut output = [0u8; 4096];
+    let (pid, console) = match create_container(spec, fds, argv, &mut output) {
+        Ok(created) => created,
+        Err(Failure::Os(step, errno)) => {
+            report(fds.report, REPORT_OS_ERROR, errno, step.as_bytes());
+            libc::_exit(1)
+        }
+        Err(Failure::Runtime(status, len)) => {
+            report(fds.report, REPORT_RUNTIME_FAILED, status, &output[..len]);
+            libc::_exit(1)
+        }
+    };
+
+    report(fds.report, REPORT_CREATED, pid, b"");
+    libc::close(fds.report);
+
+    // The container has inherited the default disposition by now, so it is unaffected by this.
+    libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    supervise(spec, pid, [console, fds.output_reader]);
+    libc::_exit(0)
+}
+
+/// Detaches the monitor from the engine and runs the OCI runtime to create the container.
+///
+/// Returns the container PID and console, if the bundle requested a terminal. If the runtime
+/// fails, its output is written into `output`.
+unsafe fn create_container(
+    spec: &MonitorSpec,
+    fds: &MonitorFds,
+    argv: &[*const c_char],
+    output: &mut [u8],
+) -> Result<(pid_t, RawFd), Failure> {
+    let os_error = |step| Failure::Os(step, errno());
+
+    // Undo the signal handling of the engine, which is inherited by the runtime and container.
+    for signal in 1..NSIG {
+        libc::signal(signal, libc::SIG_DFL);
+    }
+
+    let mut mask: libc::sigset_t = mem::zeroed();
+    libc::sigemptyset(&mut mask);
+    libc::sigprocmask(libc::SIG_SETMASK, &mask, ptr::null_mut());
+
+    if libc::setsid() == -1 {
+        return Err(os_error("create a session"));
+    }
+
+    if libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) == -1 {
+        return Err(os_error("become a subreaper"));
+    }
+
+    if let Some(ref procs) = spec.cgroup_procs {
+        if join_cgroup(procs).is_err() {
+            return Err(os_error("join the cgroup scope"));
+        }
+    }
+
+    for stdio in 0..3 {
+        libc::dup2(fds.dev_null, stdio);
+    }
+
+    let keep = [
+        0,
+        1,
+        2,
+        fds.report,
+        fds.output_reader,
+        fds.output_writer,
+        spec.listener.as_raw_fd(),
+        spec.log.as_raw_fd(),
+        spec.exits_dir.as_raw_fd(),
+        spec.release.as_raw_fd(),
+    ];
+    if close_other_fds(&keep).is_err() {
+        return Err(os_error("close inherited file descriptors"));
+    }
+
+    let flags = libc::fcntl(fds.output_reader, libc::F_GETFL);
+    libc::fcntl(fds.output_reader, libc::F_SETFL, flags | libc::O_NONBLOCK);
+
+    let runtime = libc::fork();
+    match runtime {
+        -1 => return Err(os_error("fork the runtime")),
+        0 => {
+            // The container inherits these, unless the bundle requests a terminal.
+            libc::dup2(fds.output_writer, 1);
+            libc::dup2(fds.output_writer, 2);
+            libc::execvp(argv[0], argv.as_ptr());
+
+            let msg = b"failed to execute the OCI runtime\n";
+            libc::write(2, msg.as_ptr() as *const libc::c_void, msg.len());
+            libc::_exit(127)
+        }
+        _ => {}
+    }
+
+    libc::close(fds.output_writer);
+    let status = match wait_for_exit(runtime) {
+        Ok(status) => status,
+        Err(e) => {
+            return Err(Failure::Os(
+                "wait for the runtime",
+                e.raw_os_error().unwrap_or(0),
+            ))
+        }
+    };
+
+    if status != 0 {
+        let read = libc::read(
+            fds.output_reader,
+            output.as_mut_ptr() as *mut libc::c_void,
+            output.len(),
+        );
+        return Err(Failure::Runtime(status, read.max(0) as usize));
+    }
+
+    let listener = spec.listener.as_raw_fd();
+    let console = match libc::accept4(
+        listener,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        libc::SOCK_CLOEXEC,
+    ) {
+        -1 if errno() == libc::EAGAIN => -1,
+        -1 => return Err(os_error("accept the console")),
+        stream => {
+            let console = receive_fd(stream);
+            libc::close(stream);
+            match console {
+                Ok(fd) => fd,
+                Err(e) => {
+                    return Err(Failure::Os(
+                        "receive the console",
+                        e.raw_os_error().unwrap_or(0),
+                    ))
+                }
+            }
+        }
+    };
+
+    libc::close(listener);
+
+    let pid_file = libc::open(spec.pid_file.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC);
+    if pid_file == -1 {
+        return Err(os_error("open the pid file"));
+    }
+
+    let mut contents = [0u8; 32];
+    let read = libc::read(
+        pid_file,
+        contents.as_mut_ptr() as *mut libc::c_void,
+        contents.len(),
+    );
+    libc::close(pid_file);
+
+    match parse_pid(&contents[..read.max(0) as usize]) {
+        Some(pid) => Ok((pid, console)),
+        None => Err(Failure::Os("read the pid file", libc::EINVAL)),
+    }
+}
+
+/// Copies the container output into the log file and reaps children until the container `pid`
+/// has exited and its output has been closed.
+///
+/// The first stream is the console, which is `-1` if the bundle did not request a terminal.
+unsafe fn supervise(spec: &MonitorSpec, pid: pid_t, streams: [RawFd; 2]) {
+    let log = spec.log.as_raw_fd();
+    let mut streams = [
+        LogStream::new(streams[0], true),
+        LogStream::new(streams[1], false),
+    ];
+    let mut exited = false;
+
+    loop {
+        let mut pollfds = [
+            libc::pollfd {
+                fd: streams[0].fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: streams[1].fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+
+        let ready = libc::poll(pollfds.as_mut_ptr(), 2, POLL_INTERVAL_MS);
+        for (stream, pollfd) in streams.iter_mut().zip(&pollfds) {
+            if pollfd.revents != 0 {
+                stream.copy_to(log);
+            }
+        }
+
+        // Reap every child which has exited, including orphans reparented to the monitor.
+        let mut children_left = true;
+        loop {
+            let mut status = 0;
+            match libc::waitpid(-1, &mut status, libc::WNOHANG) {
+                0 => break,
+                -1 if errno() == libc::EINTR => continue,
+                -1 => {
+                    children_left = false;
+                    break;
+                }
+                reaped if reaped == pid => {
+                    if let Some(exit_code) = exit_code(status) {
+                        record_exit(&spec.exit_file, &spec.exits_dir, &spec.release, exit_code);
+                    }
+                    exited = true;
+                }
+                _ => {}
+            }
+        }
+
+        let closed = streams.iter().all(|stream| stream.fd == -1);
+        if (exited && closed) || (!children_left && ready == 0) {
+            break;
+        }
+    }
+
+    for stream in streams.iter_mut() {
+        stream.close(log);
+    }
+}
+
+/// The output of a container, split into lines for the log file.
+struct LogStream {
+    /// The file descriptor to read from, or `-1` once closed.
+    fd: RawFd,
+    line: [u8; LOG_LINE_MAX],
+    len: usize,
+    /// Whether carriage returns are dropped, as terminals translate `\n` into `\r\n`.
+    terminal: bool,
+}
+
+impl LogStream {
+    fn new(fd: RawFd, terminal: bool) -> Self {
+        LogStream {
+            fd,
+            line: [0; LOG_LINE_MAX],
+            len: 0,
+            terminal,
+        }
+    }
+
+    /// Reads the available output and writes every complete line to `log`, closing the stream
+    /// once it has ended.
+    ///
+    /// Lines longer than [`LOG_LINE_MAX`] are split into partial lines.
+    fn copy_to(&mut self, log: RawFd) {
+        let mut buf = [0u8; 4096];
+        let read = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+
+        // Reading from a terminal whose other end has been closed yields `EIO`.
+        if read == -1 && (errno() == libc::EAGAIN || errno() == libc::EINTR) {
+            return;
+        } else if read <= 0 {
+            self.close(log);
+            return;
+        }
+
+        for &byte in &buf[..read as usize] {
+            if byte == b'\n' {
+                write_log_line(log, &self.line[..self.len], true);
+                self.len = 0;
+            } else if byte != b'\r' || !self.terminal {
+                if self.len == self.line.len() {
+                    write_log_line(log, &self.line, false);
+                    self.len = 0;
+                }
+
+                self.line[self.len] = byte;
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Closes the stream, writing any incomplete line to `log`.
+    fn close(&mut self, log: RawFd) {
+        if self.fd == -1 {
+            return;
+        }
+
+        unsafe { libc::close(self.fd) };
+        self.fd = -1;
+        if self.len > 0 {
+            write_log_line(log, &self.line[..self.len], false);
+            self.len = 0;
+        }
+    }
+}
+
+/// Appends `line` to the `log` file, in the same `k8s-file` format used by `conmon`.
+fn write_log_line(log: RawFd, line: &[u8], complete: bool) {
+    let timestamp = humantime::format_rfc3339_nanos(SystemTime::now());
+    let tag = if complete { "F" } else { "P" };
+    let mut prefix = StackWriter::new();
+    if write!(prefix, "{} stdout {} ", timestamp, tag).is_err() {
+        return;
+    }
+
+    let iov = [iovec(prefix.as_bytes()), iovec(line), iovec(b"\n")];
+    unsafe { libc::writev(log, iov.as_ptr(), iov.len() as c_int) };
+}
+
+/// Writes `exit_code` to `exit_file` while holding the lock on `exits_dir`, unless the container
+/// has been `release`d by then.
+fn record_exit(
+    exit_file: &CStr,
+    exits_dir: &std::fs::File,
+    release: &std::fs::File,
+    exit_code: i32,
+) {
+    let exits_dir = exits_dir.as_raw_fd();
+    let mut pollfd = libc::pollfd {
+        fd: release.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    unsafe {
+        libc::flock(exits_dir, libc::LOCK_EX);
+        if libc::poll(&mut pollfd, 1, 0) == 0 {
+            let mut contents = StackWriter::new();
+            if write!(contents, "{}", exit_code).is_ok() {
+                let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC;
+                let fd = libc::open(exit_file.as_ptr(), flags, 0o644);
+                if fd != -1 {
+                    let bytes = contents.as_bytes();
+                    libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len());
+                    libc::close(fd);
+                }
+            }
+        }
+        libc::flock(exits_dir, libc::LOCK_UN);
+    }
+}
+
+/// Writes a report for the engine to the pipe `fd`, consisting of the `kind` of report, a `value`
+/// and any `detail`.
+fn report(fd: RawFd, kind: u8, value: i32, detail: &[u8]) {
+    let value = value.to_ne_bytes();
+    let header = [kind, value[0], value[1], value[2], value[3]];
+    let iov = [iovec(&header), iovec(detail)];
+    unsafe { libc::writev(fd, iov.as_ptr(), iov.len() as c_int) };
+}
+
+/// Closes every file descriptor of the current process except `keep`.
+///
+/// This is called after forking, so it must only make async-signal-safe calls.
+unsafe fn close_other_fds(keep: &[RawFd]) -> io::Result<()> {
+    let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let dir = libc::open(b"/proc/self/fd\0".as_ptr() as *const c_char, flags);
+    if dir == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Use `u64` elements to guarantee the alignment required for `dirent64`.
+    let mut entries = [0u64; 512];
+    loop {
+        let read = libc::syscall(
+            libc::SYS_getdents64,
+            dir,
+            entries.as_mut_ptr(),
+            mem::size_of_val(&entries),
+        );
+        if read == -1 {
+            let err = io::Error::last_os_error();
+            libc::close(dir);
+            return Err(err);
+        } else if read == 0 {
+            break;
+        }
+
+        // The directory is read by file descriptor number, so closing them doesn't skip any.
+        let mut offset = 0;
+        while offset < read as usize {
+            let entry = (entries.as_ptr() as *const u8).add(offset) as *const libc::dirent64;
+            let name = CStr::from_ptr((*entry).d_name.as_ptr());
+            if let Some(fd) = std::str::from_utf8(name.to_bytes())
+                .ok()
+                .and_then(|n| n.parse().ok())
+            {
+                if fd != dir && !keep.contains(&fd) {
+                    libc::close(fd);
+                }
+            }
+            offset += (*entry).d_reclen as usize;
+        }
+    }
+
+    libc::close(dir);
+    Ok(())
+}
+
+/// Receives a single file descriptor sent over the socket `stream` with `SCM_RIGHTS`.
+///
+/// This is called after forking, so it must only make async-signal-safe calls.
+fn receive_fd(stream: RawFd) -> io::Result<RawFd> {
+    // The payload is typically the path of the terminal, which we don't need.
+    let mut data = [0u8; 256];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    // Use `u64` elements to guarantee the alignment required for `cmsghdr`.
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let received = unsafe { libc::recvmsg(stream, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::from_raw_os_error(libc::EBADMSG));
+        }
+
+        Ok(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd))
+    }
+}
+
+/// Blocks until the child process `pid` exits, returning its exit code.
+fn wait_for_exit(pid: pid_t) -> io::Result<i32> {
+    let mut status = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } != -1 {
+            break;
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    exit_code(status).ok_or_else(|| io::Error::from_raw_os_error(libc::ECHILD))
+}
+
+/// Returns the exit code for the wait `status` of a terminated process.
+///
+/// Processes killed by a signal are reported as `128 + signal`, like a shell would.
+fn exit_code(status: c_int) -> Option<i32> {
+    if libc::WIFEXITED(status) {
+        Some(libc::WEXITSTATUS(status))
+    } else if libc::WIFSIGNALED(status) {
+        Some(128 + libc::WTERMSIG(status))
+    } else {
+        None
+    }
+}
+
+/// Parses the contents of a pid file written by the OCI runtime.
+fn parse_pid(contents: &[u8]) -> Option<pid_t> {
+    std::str::from_utf8(contents).ok()?.trim().parse().ok()
+}
+
+/// Returns the `errno` of the last failed system call.
+fn errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+fn iovec(buf: &[u8]) -> libc::iovec {
+    libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    }
+}
+
+/// Creates a pipe, returning its read and write ends.
+fn pipe() -> io::Result<(std::fs::File, std::fs::File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe {
+        Ok((
+            std::fs::File::from_raw_fd(fds[0]),
+            std::fs::File::from_raw_fd(fds[1]),
+        ))
+    }
+}
+
+fn c_path(path: &Path) -> anyhow::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// A fixed-size buffer for formatting text without allocating.
+struct StackWriter {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl StackWriter {
+    fn new() -> Self {
+        StackWriter {
+            buf: [0; 64],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl fmt::Write for StackWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// A monitor which only creates containers with the OCI runtime, without supervising them.
+///
+/// This is intended for testing engine logic together with [`FakeRuntime`].
+///
+/// [`FakeRuntime`]: crate::FakeRuntime
+#[derive(Debug, Default)]
+pub struct FakeMonitor(());
+
+impl FakeMonitor {
+    /// Creates a new fake monitor.
+    pub fn new() -> Self {
+        FakeMonitor::default()
+    }
+}
+
+#[async_trait]
+impl Monitor for FakeMonitor {
+    fn name(&self) -> &str {
+        "fake"
+    }
+
+    async fn spawn(
+        &self,
+        id: &str,
+        rt: &OciBundle,
+        runtime: &dyn Runtime,
+        _cgroup: Option<&ContainerCgroup>,
+        restore_from: Option<&Path>,
+    ) -> anyhow::Result<Monitored> {
+        runtime
+            .create(id, &rt.bundle_dir, &rt.pid_file, None)
+            .await?;
+
+        // Restored containers pick up where they left off.
+        if restore_from.is_some() {
+            runtime.start(id).await?;
+        }
+
+        Ok(Monitored {
+            pid: FAKE_PID,
+            guard: Box::new(()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use crate::runtime::CliRuntime;
+
+    #[test]
+    fn receives_fd_over_socket() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let file = tempfile::tempfile().unwrap();
+
+        let fd = file.as_raw_fd();
+        let mut iov = libc::iovec {
+            iov_base: b"/dev/pts/0".as_ptr() as *mut libc::c_void,
+            iov_len: 10,
+        };
+
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as _;
+
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+            assert_ne!(libc::sendmsg(sender.as_raw_fd(), &msg, 0), -1);
+        }
+
+        let received = receive_fd(receiver.as_raw_fd()).unwrap();
+        assert_ne!(received, fd);
+        unsafe { libc::close(received) };
+    }
+
+    #[test]
+    fn rejects_message_without_fd() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        sender.write_all(b"hello").unwrap();
+        assert!(receive_fd(receiver.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn reports_exit_codes_of_children() {
+        let child = std::process::Command::new("sh")
+            .args(&["-c", "exit 3"])
+            .spawn()
+            .unwrap();
+        assert_eq!(wait_for_exit(child.id() as pid_t).unwrap(), 3);
+
+        let child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        unsafe { libc::kill(child.id() as pid_t, libc::SIGKILL) };
+        assert_eq!(wait_for_exit(child.id() as pid_t).unwrap(), 137);
+    }
+
+    #[test]
+    fn discards_exit_of_released_container() {
+        let dir = tempfile::tempdir().unwrap();
+        let exits_dir = std::fs::File::open(dir.path()).unwrap();
+        let exit_file = dir.path().join("busybox");
+        let exit_file_c = c_path(&exit_file).unwrap();
+
+        let (release_reader, mut release) = pipe().unwrap();
+        record_exit(&exit_file_c, &exits_dir, &release_reader, 0);
+        assert_eq!(std::fs::read_to_string(&exit_file).unwrap(), "0");
+
+        std::fs::remove_file(&exit_file).unwrap();
+        release.write_all(&[0]).unwrap();
+        record_exit(&exit_file_c, &exits_dir, &release_reader, 0);
+        assert!(!exit_file.exists());
+    }
+
+    #[test]
+    fn writes_console_output_to_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let console_path = dir.path().join("console");
+        let log_path = dir.path().join("container.log");
+        std::fs::write(&console_path, b"hello\r\nworld\npartial").unwrap();
+
+        let console = std::fs::File::open(&console_path).unwrap();
+        let log = std::fs::File::create(&log_path).unwrap();
+        let mut stream = LogStream::new(console.into_raw_fd(), true);
+        while stream.fd != -1 {
+            stream.copy_to(log.as_raw_fd());
+        }
+
+        let text = std::fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" stdout F hello"));
+        assert!(lines[1].ends_with(" stdout F world"));
+        assert!(lines[2].ends_with(" stdout P partial"));
+    }
+
+    fn fake_runtime(dir: &Path, script: &str) -> CliRuntime {
+        let binary = dir.join("runtime");
+        std::fs::write(&binary, script).unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        CliRuntime::new("fake", binary)
+    }
+
+    #[tokio::test]
+    async fn supervises_orphaned_container_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = OciBundle::empty_in(dir.path()).unwrap();
+
+        // Like a real runtime, leave the container process behind to be reparented.
+        let runtime = fake_runtime(
+            dir.path(),
+            "#!/bin/sh\necho \"created $8\"\n(sleep 0.2; exit 3) &\necho $! > \"$5\"\n",
+        );
+
+        let monitored = NativeMonitor::new()
+            .spawn("busybox", &bundle, &runtime, None, None)
+            .await
+            .unwrap();
+        assert!(monitored.pid > 1);
+
+        let exit_file = bundle.exits_dir.join("busybox");
+        for _ in 0..50 {
+            if exit_file.exists() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(std::fs::read_to_string(&exit_file).unwrap(), "3");
+        let log = std::fs::read_to_string(&bundle.log_file).unwrap();
+        assert!(log
+            .lines()
+            .any(|l| l.ends_with(" stdout F created busybox")));
+    }
+
+    #[tokio::test]
+    async fn reports_runtime_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = OciBundle::empty_in(dir.path()).unwrap();
+        let runtime = fake_runtime(dir.path(), "#!/bin/sh\necho oops >&2\nexit 1\n");
+
+        let err = NativeMonitor::new()
+            .spawn("busybox", &bundle, &runtime, None, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("exited with status 1: [oops]"));
+    }
+}
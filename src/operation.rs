@@ -0,0 +1,328 @@
+//! Long-running engine operations which may be polled and cancelled by clients.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use dashmap::DashMap;
+use futures_util::future::{AbortHandle, Abortable};
+use serde::Serialize;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/// Time for which finished operations remain available to be polled.
+const FINISHED_TTL: Duration = Duration::from_secs(600);
+
+/// A step of a long-running operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// The operation has not started yet.
+    Pending,
+    /// The container image is being fetched from the registry.
+    Pulling,
+    /// The container image is being unpacked into a bundle.
+    Unpacking,
+    /// The container is being created or restored by the OCI runtime.
+    Creating,
+    /// The container process is being started.
+    Starting,
+    /// The operation has completed successfully.
+    Done,
+}
+
+/// The outcome of a long-running operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationStatus {
+    /// The operation is still in progress.
+    Running,
+    /// The operation has completed successfully.
+    Succeeded,
+    /// The operation has failed, see [`Operation::error`].
+    Failed,
+    /// The operation was cancelled by a client.
+    Cancelled,
+}
+
+/// A snapshot of a long-running operation.
+#[derive(Clone, Debug, Serialize)]
+pub struct Operation {
+    /// The unique operation ID.
+    pub id: String,
+    /// The kind of operation, e.g. `create`.
+    pub kind: &'static str,
+    /// The name of the container the operation applies to.
+    pub target: String,
+    /// The outcome of the operation.
+    pub status: OperationStatus,
+    /// The step the operation has reached.
+    pub phase: Phase,
+    /// The number of image bytes pulled so far.
+    pub progress_bytes: u64,
+    /// When the operation was started, in RFC 3339 format.
+    pub started_at: String,
+    /// When the operation finished, in RFC 3339 format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    /// The error the operation failed with, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<OperationError>,
+}
+
+/// An error reported by a failed operation.
+#[derive(Clone, Debug, Serialize)]
+pub struct OperationError {
+    /// The stable, machine-readable error code, see [`Error::code()`].
+    pub code: &'static str,
+    /// A human-readable error message.
+    pub message: String,
+}
+
+/// The mutable state of a tracked operation.
+#[derive(Debug)]
+struct Tracked {
+    operation: Operation,
+    finished: Option<Instant>,
+}
+
+impl Tracked {
+    /// Records the final `status` of the operation, unless it has already finished.
+    fn finish(&mut self, status: OperationStatus, error: Option<OperationError>) {
+        if self.finished.is_some() {
+            return;
+        }
+
+        self.operation.status = status;
+        self.operation.error = error;
+        self.operation.finished_at = Some(now_rfc3339());
+        if status == OperationStatus::Succeeded {
+            self.operation.phase = Phase::Done;
+        }
+        self.finished = Some(Instant::now());
+    }
+}
+
+/// A handle for reporting the progress of an operation.
+///
+/// The default handle is not attached to any operation and discards all reports.
+#[derive(Clone, Debug, Default)]
+pub struct Progress(Option<Arc<Mutex<Tracked>>>);
+
+impl Progress {
+    /// Records that the operation has reached `phase`.
+    pub fn set_phase(&self, phase: Phase) {
+        if let Some(ref tracked) = self.0 {
+            tracked.lock().unwrap().operation.phase = phase;
+        }
+    }
+
+    /// Records the number of image bytes pulled so far.
+    pub fn set_bytes(&self, bytes: u64) {
+        if let Some(ref tracked) = self.0 {
+            tracked.lock().unwrap().operation.progress_bytes = bytes;
+        }
+    }
+}
+
+/// An operation in the registry.
+#[derive(Debug)]
+struct Entry {
+    tracked: Arc<Mutex<Tracked>>,
+    handle: AbortHandle,
+}
+
+/// A registry of long-running operations running in the background.
+#[derive(Debug, Default)]
+pub(crate) struct Operations {
+    entries: DashMap<String, Entry>,
+}
+
+impl Operations {
+    /// Spawns the operation returned by `task` in the background and returns a snapshot of it.
+    ///
+    /// `task` receives a [`Progress`] handle for reporting its progress. Operations which finished
+    /// long ago are pruned from the registry.
+    pub fn spawn<F, T>(&self, kind: &'static str, target: &str, task: T) -> Operation
+    where
+        T: FnOnce(Progress) -> F,
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.prune();
+
+        let operation = Operation {
+            id: Uuid::new_v4().to_string(),
+            kind,
+            target: target.to_owned(),
+            status: OperationStatus::Running,
+            phase: Phase::Pending,
+            progress_bytes: 0,
+            started_at: now_rfc3339(),
+            finished_at: None,
+            error: None,
+        };
+
+        let tracked = Arc::new(Mutex::new(Tracked {
+            operation: operation.clone(),
+            finished: None,
+        }));
+
+        let future = task(Progress(Some(tracked.clone())));
+        let (handle, registration) = AbortHandle::new_pair();
+        let finished = tracked.clone();
+        let id = operation.id.clone();
+        tokio::spawn(async move {
+            let result = Abortable::new(future, registration).await;
+            let mut tracked = finished.lock().unwrap();
+            match result {
+                Ok(Ok(())) => tracked.finish(OperationStatus::Succeeded, None),
+                Ok(Err(e)) => {
+                    warn!("operation {} failed: {}", id, e);
+                    let error = OperationError {
+                        code: e.code(),
+                        message: e.to_string(),
+                    };
+                    tracked.finish(OperationStatus::Failed, Some(error));
+                }
+                Err(_) => tracked.finish(OperationStatus::Cancelled, None),
+            }
+        });
+
+        let entry = Entry { tracked, handle };
+        self.entries.insert(operation.id.clone(), entry);
+        operation
+    }
+
+    /// Returns a snapshot of the operation with the given `id`.
+    ///
+    /// Returns `Err` if no such operation exists.
+    pub fn get(&self, id: &str) -> Result<Operation, Error> {
+        match self.entries.get(id) {
+            Some(entry) => Ok(entry.tracked.lock().unwrap().operation.clone()),
+            None => Err(operation_not_found(id)),
+        }
+    }
+
+    /// Returns a snapshot of the running operation of the given `kind` on `target`, if any.
+    pub fn find_running(&self, kind: &str, target: &str) -> Option<Operation> {
+        self.entries.iter().find_map(|entry| {
+            let tracked = entry.tracked.lock().unwrap();
+            let operation = &tracked.operation;
+            let running = tracked.finished.is_none();
+            if running && operation.kind == kind && operation.target == target {
+                Some(operation.clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Cancels the operation with the given `id` if it is still running, or removes it from the
+    /// registry if it has already finished, and returns a final snapshot of it.
+    ///
+    /// Returns `Err` if no such operation exists.
+    pub fn cancel(&self, id: &str) -> Result<Operation, Error> {
+        let entry = match self.entries.get(id) {
+            Some(entry) => entry,
+            None => return Err(operation_not_found(id)),
+        };
+
+        let mut tracked = entry.tracked.lock().unwrap();
+        if tracked.finished.is_some() {
+            let operation = tracked.operation.clone();
+            drop(tracked);
+            drop(entry);
+            self.entries.remove(id);
+            return Ok(operation);
+        }
+
+        debug!("cancelling operation {}", id);
+        entry.handle.abort();
+        tracked.finish(OperationStatus::Cancelled, None);
+        Ok(tracked.operation.clone())
+    }
+
+    /// Removes operations which finished more than [`FINISHED_TTL`] ago.
+    fn prune(&self) {
+        self.entries.retain(|_, entry| {
+            let tracked = entry.tracked.lock().unwrap();
+            tracked
+                .finished
+                .map_or(true, |t| t.elapsed() < FINISHED_TTL)
+        });
+    }
+}
+
+/// Returns an error for the missing operation `id`.
+fn operation_not_found(id: &str) -> Error {
+    Error::NotFound(format!("operation `{}` does not exist", id))
+}
+
+/// Returns the current time in RFC 3339 format.
+fn now_rfc3339() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gives spawned operations a chance to make progress.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn tracks_operation_progress() {
+        let operations = Operations::default();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let op = operations.spawn("create", "busybox", |progress| async move {
+            progress.set_phase(Phase::Pulling);
+            progress.set_bytes(1024);
+            rx.await.ok();
+            Ok(())
+        });
+        assert_eq!(op.status, OperationStatus::Running);
+
+        settle().await;
+        let running = operations.get(&op.id).unwrap();
+        assert_eq!(running.phase, Phase::Pulling);
+        assert_eq!(running.progress_bytes, 1024);
+
+        tx.send(()).unwrap();
+        settle().await;
+        let done = operations.get(&op.id).unwrap();
+        assert_eq!(done.status, OperationStatus::Succeeded);
+        assert_eq!(done.phase, Phase::Done);
+        assert!(done.finished_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn reports_failures_and_cancellation() {
+        let operations = Operations::default();
+        let failing = operations.spawn("create", "busybox", |_| async {
+            Err(Error::InvalidSpec("bad name".into()))
+        });
+        let pending = operations.spawn("create", "alpine", |_| futures_util::future::pending());
+
+        settle().await;
+        let failed = operations.get(&failing.id).unwrap();
+        assert_eq!(failed.status, OperationStatus::Failed);
+        assert_eq!(failed.error.unwrap().code, "invalid_spec");
+
+        let running = operations.find_running("create", "alpine").unwrap();
+        assert_eq!(running.id, pending.id);
+        assert!(operations.find_running("create", "busybox").is_none());
+
+        let cancelled = operations.cancel(&pending.id).unwrap();
+        assert_eq!(cancelled.status, OperationStatus::Cancelled);
+
+        // Cancelling a finished operation removes it.
+        operations.cancel(&pending.id).unwrap();
+        assert!(operations.get(&pending.id).is_err());
+    }
+}
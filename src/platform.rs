@@ -0,0 +1,158 @@
+//! Platforms selected from multi-architecture images.
+
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use fallible_collections::tryformat;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use crate::error::Error;
+
+/// An operating system and CPU architecture an image is built for, as listed in OCI image
+/// indexes.
+///
+/// Platforms are written as `os/architecture[/variant]`, e.g. `linux/arm64` or `linux/arm/v7`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Platform {
+    /// The operating system, e.g. `linux`.
+    pub os: String,
+    /// The CPU architecture in Go notation, e.g. `amd64` or `arm64`.
+    pub architecture: String,
+    /// The CPU variant, e.g. `v7` for 32-bit ARM, if any.
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Returns the platform the engine is running on.
+    ///
+    /// Architectures are translated from Rust to Go notation as used by OCI images, e.g.
+    /// `x86_64` to `amd64`.
+    pub fn host() -> Self {
+        let (architecture, variant) = match std::env::consts::ARCH {
+            "x86_64" => ("amd64", None),
+            "x86" => ("386", None),
+            "aarch64" => ("arm64", None),
+            "arm" if cfg!(target_feature = "v7") => ("arm", Some("v7")),
+            "arm" => ("arm", Some("v6")),
+            "powerpc64" if cfg!(target_endian = "little") => ("ppc64le", None),
+            "mips64" if cfg!(target_endian = "little") => ("mips64le", None),
+            "mips" if cfg!(target_endian = "little") => ("mipsle", None),
+            arch => (arch, None),
+        };
+
+        Platform {
+            os: std::env::consts::OS.into(),
+            architecture: architecture.into(),
+            variant: variant.map(Into::into),
+        }
+    }
+
+    /// Returns whether an image built for this platform runs on the `wanted` platform.
+    ///
+    /// Variants are only compared if both platforms have one, as images often omit them, e.g.
+    /// `v8` for `arm64`.
+    pub fn satisfies(&self, wanted: &Platform) -> bool {
+        let variant_matches = match (&self.variant, &wanted.variant) {
+            (Some(variant), Some(wanted)) => variant == wanted,
+            _ => true,
+        };
+
+        self.os == wanted.os && self.architecture == wanted.architecture && variant_matches
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(ref variant) = self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(os), Some(arch), variant, None)
+                if !os.is_empty() && !arch.is_empty() && variant != Some("") =>
+            {
+                Ok(Platform {
+                    os: os.into(),
+                    architecture: arch.into(),
+                    variant: variant.map(Into::into),
+                })
+            }
+            _ => {
+                let msg = tryformat!(
+                    128,
+                    "invalid platform `{}`, expected `os/architecture[/variant]`",
+                    s
+                )
+                .map_err(Error::Oom)?;
+                Err(Error::InvalidSpec(msg))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Platform {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Platform {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_platforms() {
+        let platform: Platform = "linux/arm/v7".parse().unwrap();
+        assert_eq!(platform.os, "linux");
+        assert_eq!(platform.architecture, "arm");
+        assert_eq!(platform.variant.as_deref(), Some("v7"));
+        assert_eq!(platform.to_string(), "linux/arm/v7");
+
+        let platform: Platform = "linux/arm64".parse().unwrap();
+        assert_eq!(platform.variant, None);
+        assert_eq!(platform.to_string(), "linux/arm64");
+
+        for invalid in &["linux", "linux/", "/amd64", "linux/arm/", "linux/arm/v7/x"] {
+            let e = invalid.parse::<Platform>().unwrap_err();
+            assert_eq!(e.code(), "invalid_spec", "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn matches_wanted_platforms() {
+        let arm64: Platform = "linux/arm64".parse().unwrap();
+        let arm64_v8: Platform = "linux/arm64/v8".parse().unwrap();
+        let armv6: Platform = "linux/arm/v6".parse().unwrap();
+        let armv7: Platform = "linux/arm/v7".parse().unwrap();
+
+        assert!(arm64.satisfies(&arm64_v8));
+        assert!(arm64_v8.satisfies(&arm64));
+        assert!(!armv6.satisfies(&armv7));
+        assert!(!arm64.satisfies(&armv7));
+    }
+
+    #[test]
+    fn detects_host_platform() {
+        let host = Platform::host();
+        assert_eq!(host.os, "linux");
+        assert!(!["x86_64", "aarch64"].contains(&host.architecture.as_str()));
+    }
+}
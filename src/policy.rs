@@ -0,0 +1,444 @@
+//! Signature policies restricting which images may be run, as in `containers-policy.json`.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use fallible_collections::tryformat;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// The `skopeo` transport of images in remote registries.
+pub(crate) const DOCKER_TRANSPORT: &str = "docker";
+
+/// A requirement an image must satisfy to be accepted by a [`Policy`].
+///
+/// Fields which are not listed here, e.g. `signedIdentity`, are left to `skopeo` to evaluate.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Requirement {
+    /// Accepts any image, signed or not.
+    InsecureAcceptAnything,
+    /// Rejects any image.
+    Reject,
+    /// Requires a simple signing signature by one of the given GPG keys.
+    #[serde(rename_all = "camelCase")]
+    SignedBy {
+        /// The kind of keys, which must be `GPGKeys`.
+        key_type: String,
+        /// A GPG keyring file holding the accepted keys.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_path: Option<PathBuf>,
+        /// Several GPG keyring files holding the accepted keys.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        key_paths: Vec<PathBuf>,
+        /// The accepted keys inline, base64-encoded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_data: Option<String>,
+    },
+    /// Requires a sigstore signature, e.g. made by `cosign`, by the given public key.
+    #[serde(rename_all = "camelCase")]
+    SigstoreSigned {
+        /// A PEM file holding the accepted public key.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_path: Option<PathBuf>,
+        /// The accepted public key inline, base64-encoded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_data: Option<String>,
+    },
+    /// A requirement of another type, e.g. `signedBaseLayer`, which is left to `skopeo` to
+    /// evaluate.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Requirement {
+    /// Returns whether the requirement checks a signature, rather than accepting or rejecting
+    /// images outright.
+    ///
+    /// Unknown requirements may check signatures too, so they are treated as if they did.
+    fn is_signature(&self) -> bool {
+        matches!(
+            self,
+            Requirement::SignedBy { .. }
+                | Requirement::SigstoreSigned { .. }
+                | Requirement::Unknown
+        )
+    }
+
+    /// Checks that the requirement names the keys to check signatures with.
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Requirement::SignedBy { key_type, .. } if key_type != "GPGKeys" => {
+                Err(format!("unsupported `signedBy` key type `{}`", key_type))
+            }
+            Requirement::SignedBy {
+                key_path,
+                key_paths,
+                key_data,
+                ..
+            } => {
+                let sources = key_path.is_some() as u8
+                    + !key_paths.is_empty() as u8
+                    + key_data.is_some() as u8;
+                match sources {
+                    1 => Ok(()),
+                    _ => Err("`signedBy` needs one of `keyPath`, `keyPaths` or `keyData`".into()),
+                }
+            }
+            Requirement::SigstoreSigned { key_path, key_data } => match (key_path, key_data) {
+                (Some(_), None) | (None, Some(_)) => Ok(()),
+                _ => Err("`sigstoreSigned` needs one of `keyPath` or `keyData`".into()),
+            },
+            Requirement::InsecureAcceptAnything | Requirement::Reject | Requirement::Unknown => {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A signature policy in the format of `containers-policy.json`, which is enforced by `skopeo`
+/// when fetching or importing images.
+///
+/// The requirements for an image are those of the most specific scope of its transport matching
+/// the image, or the `default` ones otherwise.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Policy {
+    /// The requirements for images not matching any scope.
+    default: Vec<Requirement>,
+    /// The requirements for each scope of each transport, e.g. `docker.io/library` for `docker`.
+    #[serde(default)]
+    transports: BTreeMap<String, BTreeMap<String, Vec<Requirement>>>,
+}
+
+/// The outcome of evaluating the signature policy for an image, which has satisfied it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Verification {
+    /// The transport the image was evaluated for, e.g. `docker` for registries.
+    pub transport: String,
+    /// The policy scope the image matched, e.g. `docker.io/library`, or `None` if the default
+    /// requirements applied.
+    pub scope: Option<String>,
+    /// The requirements the image has satisfied.
+    pub requirements: Vec<Requirement>,
+    /// Whether the image has been accepted due to its signatures, rather than accepted outright.
+    pub signed: bool,
+}
+
+impl Policy {
+    /// Reads the policy from the file at `path`.
+    ///
+    /// Returns `Err` if the file could not be read, or if the policy is malformed.
+    pub async fn load(path: &Path) -> Result<Self, Error> {
+        let bytes = tokio::fs::read(path)
+            .await
+            .map_err(|e| read_error(path, e))?;
+        Policy::parse(&bytes, path)
+    }
+
+    /// Reads the policy from the file at `path` like [`Policy::load`], but blocking, e.g. to
+    /// check it once at startup.
+    ///
+    /// Returns `Err` if the file could not be read, or if the policy is malformed.
+    pub fn load_sync(path: &Path) -> Result<Self, Error> {
+        let bytes = std::fs::read(path).map_err(|e| read_error(path, e))?;
+        Policy::parse(&bytes, path)
+    }
+
+    /// Parses the policy read from the file at `path`.
+    ///
+    /// Returns `Err` if the policy is malformed, e.g. has no requirements for some scope or
+    /// requires signatures without naming any keys.
+    pub fn parse(bytes: &[u8], path: &Path) -> Result<Self, Error> {
+        let invalid = |msg: String| {
+            let path = path.display();
+            match tryformat!(256, "invalid signature policy `{}`: {}", path, msg) {
+                Ok(msg) => Error::InvalidSpec(msg),
+                Err(e) => Error::Oom(e),
+            }
+        };
+
+        let policy: Policy = serde_json::from_slice(bytes).map_err(|e| invalid(e.to_string()))?;
+        let scoped = policy.transports.values().flat_map(BTreeMap::values);
+        for requirements in std::iter::once(&policy.default).chain(scoped) {
+            if requirements.is_empty() {
+                return Err(invalid("empty list of requirements".into()));
+            }
+
+            for requirement in requirements {
+                requirement.validate().map_err(invalid)?;
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Evaluates the policy for the image at `location` of `transport`, e.g.
+    /// `quay.io/coreos/etcd@sha256:...` for `docker`.
+    ///
+    /// Returns `Err` if the policy rejects the image outright, while signatures are left to be
+    /// checked by `skopeo`.
+    pub fn check(&self, transport: &str, location: &str) -> Result<Verification, Error> {
+        let scopes = self.transports.get(transport);
+        let matched = scopes.and_then(|scopes| {
+            let mut candidates = match transport {
+                DOCKER_TRANSPORT => docker_scopes(location),
+                _ => path_scopes(location),
+            };
+            candidates.push(String::new());
+
+            candidates
+                .into_iter()
+                .find_map(|scope| Some((scopes.get(&scope)?, scope)))
+        });
+
+        let (requirements, scope) = match matched {
+            Some((requirements, scope)) => (requirements, Some(scope)),
+            None => (&self.default, None),
+        };
+
+        if requirements.contains(&Requirement::Reject) {
+            let msg = tryformat!(
+                256,
+                "image `{}:{}` is rejected by the signature policy",
+                transport,
+                location
+            )
+            .map_err(Error::Oom)?;
+            return Err(Error::Untrusted(msg));
+        }
+
+        Ok(Verification {
+            transport: transport.into(),
+            scope,
+            requirements: requirements.clone(),
+            signed: requirements.iter().any(Requirement::is_signature),
+        })
+    }
+
+    /// Checks that the stored image named `name` has been verified as the policy currently
+    /// requires for images fetched from registries.
+    ///
+    /// Returns `Err` if the policy rejects the image, or requires signatures while the image was
+    /// not verified against the same requirements, e.g. as it was stored before.
+    pub fn check_stored(&self, name: &str, verified: Option<&Verification>) -> Result<(), Error> {
+        let wanted = self.check(DOCKER_TRANSPORT, name)?;
+        match verified {
+            _ if !wanted.signed => Ok(()),
+            Some(verified) if verified.signed && verified.requirements == wanted.requirements => {
+                Ok(())
+            }
+            _ => {
+                let msg = tryformat!(
+                    256,
+                    "stored image `{}` has not been verified against the signature policy, pull \
+                     it again",
+                    name
+                )
+                .map_err(Error::Oom)?;
+                Err(Error::Untrusted(msg))
+            }
+        }
+    }
+}
+
+/// Returns the error for a failure to read the policy file at `path`.
+fn read_error(path: &Path, e: io::Error) -> Error {
+    match tryformat!(256, "failed to read signature policy `{}`", path.display()) {
+        Ok(msg) => Error::Internal(anyhow::Error::new(e).context(msg)),
+        Err(e) => Error::Oom(e),
+    }
+}
+
+/// Returns the scopes matching the `docker` transport image `reference`, from the most to the
+/// least specific one, e.g. `quay.io/coreos/etcd:v3.4`, `quay.io/coreos/etcd`, `quay.io/coreos`,
+/// `quay.io` and `*.io`.
+fn docker_scopes(reference: &str) -> Vec<String> {
+    let mut scopes = vec![reference.to_owned()];
+
+    let repository = match reference.find('@') {
+        Some(at) => &reference[..at],
+        None => match reference.rfind(':') {
+            Some(colon) if !reference[colon..].contains('/') => &reference[..colon],
+            _ => reference,
+        },
+    };
+
+    let mut namespace = repository;
+    loop {
+        if namespace != reference {
+            scopes.push(namespace.to_owned());
+        }
+
+        match namespace.rfind('/') {
+            Some(slash) => namespace = &namespace[..slash],
+            None => break,
+        }
+    }
+
+    // The host is left in `namespace` now, which also matches wildcards of its parent domains
+    // unless it has a port.
+    let mut domain = if namespace.contains(':') {
+        ""
+    } else {
+        namespace
+    };
+    while let Some(dot) = domain.find('.') {
+        domain = &domain[dot + 1..];
+        scopes.push(format!("*.{}", domain));
+    }
+
+    scopes
+}
+
+/// Returns the scopes matching the image at the local `location`, i.e. the location itself and
+/// its parent directories, e.g. `/srv/images/app:1.0`, `/srv/images/app` and `/srv/images`.
+fn path_scopes(location: &str) -> Vec<String> {
+    let mut scopes = vec![location.to_owned()];
+
+    let path = match location.rfind(':') {
+        Some(colon) if !location[colon..].contains('/') => &location[..colon],
+        _ => location,
+    };
+
+    if path != location {
+        scopes.push(path.to_owned());
+    }
+
+    let parents = Path::new(path).ancestors().skip(1);
+    let parents = parents.filter(|p| p.parent().is_some());
+    scopes.extend(parents.map(|p| p.display().to_string()));
+    scopes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"{
+        "default": [{ "type": "reject" }],
+        "transports": {
+            "docker": {
+                "docker.io/library": [{
+                    "type": "signedBy",
+                    "keyType": "GPGKeys",
+                    "keyPath": "/etc/pki/containers/library.gpg"
+                }],
+                "quay.io/coreos/etcd": [{
+                    "type": "sigstoreSigned",
+                    "keyPath": "/etc/pki/containers/etcd.pub",
+                    "signedIdentity": { "type": "matchRepository" }
+                }],
+                "localhost": [{ "type": "insecureAcceptAnything" }]
+            },
+            "oci": {
+                "/srv/images": [{ "type": "insecureAcceptAnything" }]
+            }
+        }
+    }"#;
+
+    fn policy() -> Policy {
+        Policy::parse(POLICY.as_bytes(), Path::new("policy.json")).unwrap()
+    }
+
+    #[test]
+    fn evaluates_most_specific_scope() {
+        let policy = policy();
+
+        let verification = policy
+            .check(DOCKER_TRANSPORT, "docker.io/library/alpine@sha256:abcd")
+            .unwrap();
+        assert_eq!(verification.scope.as_deref(), Some("docker.io/library"));
+        assert!(verification.signed);
+
+        let verification = policy
+            .check(DOCKER_TRANSPORT, "quay.io/coreos/etcd:v3.4")
+            .unwrap();
+        assert_eq!(verification.scope.as_deref(), Some("quay.io/coreos/etcd"));
+        assert!(matches!(
+            verification.requirements[0],
+            Requirement::SigstoreSigned { .. }
+        ));
+
+        let verification = policy.check(DOCKER_TRANSPORT, "localhost/app:1.0").unwrap();
+        assert_eq!(verification.scope.as_deref(), Some("localhost"));
+        assert!(!verification.signed);
+
+        let verification = policy.check("oci", "/srv/images/app:1.0").unwrap();
+        assert_eq!(verification.scope.as_deref(), Some("/srv/images"));
+
+        let e = policy
+            .check(DOCKER_TRANSPORT, "quay.io/coreos/flannel:v0.12")
+            .unwrap_err();
+        assert_eq!(e.code(), "untrusted_image");
+        assert!(policy.check("docker-archive", "/tmp/app.tar").is_err());
+    }
+
+    #[test]
+    fn checks_stored_images() {
+        let policy = policy();
+        let name = "docker.io/library/alpine:3.12";
+        let verified = policy.check(DOCKER_TRANSPORT, name).unwrap();
+
+        policy.check_stored(name, Some(&verified)).unwrap();
+        policy.check_stored("localhost/app:1.0", None).unwrap();
+
+        let e = policy.check_stored(name, None).unwrap_err();
+        assert_eq!(e.code(), "untrusted_image");
+
+        let other = policy
+            .check(DOCKER_TRANSPORT, "quay.io/coreos/etcd:v3.4")
+            .unwrap();
+        assert!(policy.check_stored(name, Some(&other)).is_err());
+    }
+
+    #[test]
+    fn lists_docker_scopes() {
+        assert_eq!(
+            docker_scopes("quay.io/coreos/etcd:v3.4"),
+            [
+                "quay.io/coreos/etcd:v3.4",
+                "quay.io/coreos/etcd",
+                "quay.io/coreos",
+                "quay.io",
+                "*.io"
+            ]
+        );
+        assert_eq!(
+            docker_scopes("registry.local:5000/app"),
+            ["registry.local:5000/app", "registry.local:5000"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        let invalid = [
+            r#"{ "default": [] }"#,
+            r#"{ "default": [{ "type": "signedBy", "keyType": "GPGKeys" }] }"#,
+            r#"{ "default": [{ "type": "signedBy", "keyType": "X509", "keyPath": "/k" }] }"#,
+            r#"{ "default": [{ "type": "sigstoreSigned" }] }"#,
+        ];
+
+        for policy in &invalid {
+            let e = Policy::parse(policy.as_bytes(), Path::new("policy.json")).unwrap_err();
+            assert_eq!(e.code(), "invalid_spec", "{}", policy);
+        }
+    }
+
+    #[test]
+    fn leaves_unknown_requirements_to_skopeo() {
+        let policy = r#"{
+            "default": [{
+                "type": "signedBaseLayer",
+                "baseLayerIdentity": { "type": "exactRepository", "dockerRepository": "busybox" }
+            }]
+        }"#;
+        let policy = Policy::parse(policy.as_bytes(), Path::new("policy.json")).unwrap();
+
+        let verification = policy.check(DOCKER_TRANSPORT, "quay.io/app:1.0").unwrap();
+        assert_eq!(verification.requirements, [Requirement::Unknown]);
+        assert!(verification.signed);
+    }
+}
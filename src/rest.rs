@@ -1,27 +1,120 @@
 //! `warp` integration for serving over HTTP.
 
 use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
 
+use bytes::Bytes;
 use fallible_collections::{tryformat, TryReserveError};
-use http::StatusCode;
+use futures_util::stream::{self, Stream};
+use http::header::{HeaderValue, CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE};
+use http::{Response, StatusCode};
+use hyper::Body;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tracing::{error, warn};
+use tracing::{debug, error, warn};
 use warp::body::BodyDeserializeError;
+use warp::reject::{InvalidQuery, MethodNotAllowed};
 use warp::{Filter, Rejection, Reply};
 
-use crate::Engine;
+use crate::archive::ArchiveStream;
+use crate::auth::{self, Forbidden, Scope, Unauthorized};
+use crate::metrics::Metrics;
+use crate::{CommitOptions, CreateOptions, Credentials, Engine, Error, Operation, Platform, Stats};
+
+/// Scopes required to inspect containers, stored images and the engine.
+const READ: &[Scope] = &[Scope::ContainersRead];
+
+/// Scopes required to modify existing containers.
+const WRITE: &[Scope] = &[Scope::ContainersWrite];
+
+/// Scopes required to create containers, which may pull their images, or to commit containers
+/// into images.
+const CREATE: &[Scope] = &[Scope::ContainersWrite, Scope::ImagesPull];
+
+/// Scopes required to pull images into the image store, import them, or remove them.
+const PULL: &[Scope] = &[Scope::ImagesPull];
+
+/// Scopes required to manage registry credentials.
+const REGISTRIES: &[Scope] = &[Scope::RegistriesAuth];
+
+/// Interval between samples when streaming container statistics.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Converts the container engine into a [`warp`](https://docs.rs/warp) REST filter.
 pub fn to_filter(svc: Engine) -> impl Filter<Extract = impl Reply> + Clone + 'static {
     let container_path = warp::path!("containers" / String);
+    let metrics = svc.metrics.clone();
+    let request_metrics = svc.metrics.clone();
+    let tokens = svc.tokens.clone();
+    let authorize = move |required: &'static [Scope]| auth::authorize(tokens.clone(), required);
     let engine = warp::any().map(move || svc.clone());
 
     let create = warp::put()
         .and(engine.clone())
         .and(container_path)
+        .and(authorize(CREATE))
+        .and(warp::query::<CreateQuery>())
+        .and(optional_json())
+        .and_then(
+            move |eng: Engine, name: String, query: CreateQuery, body: CreateOptions| async move {
+                if query.asynchronous {
+                    let operation = eng.create_async(&name, body);
+                    return accepted(&operation).map_err(|e| warp::reject::custom(EngineError(e)));
+                }
+
+                if let Err(e) = eng.create(&name, body).await {
+                    warn!("error creating container: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                } else {
+                    Ok(warp::reply().into_response())
+                }
+            },
+        );
+
+    let create_only = warp::post()
+        .and(engine.clone())
+        .and(warp::path!("containers"))
+        .and(authorize(CREATE))
+        .and(warp::query::<NewContainerQuery>())
+        .and(optional_json())
+        .and_then(
+            move |eng: Engine, query: NewContainerQuery, mut body: CreateOptions| async move {
+                body.start = false;
+                if query.asynchronous {
+                    let operation = eng.create_async(&query.name, body);
+                    return accepted(&operation).map_err(|e| warp::reject::custom(EngineError(e)));
+                }
+
+                if let Err(e) = eng.create(&query.name, body).await {
+                    warn!("error creating container: {}", e);
+                    return Err(warp::reject::custom(EngineError(e)));
+                }
+
+                created(&query.name).map_err(|e| warp::reject::custom(EngineError(e)))
+            },
+        );
+
+    let start = warp::post()
+        .and(engine.clone())
+        .and(warp::path!("containers" / String / "start"))
+        .and(authorize(WRITE))
+        .and_then(move |eng: Engine, name: String| async move {
+            if let Err(e) = eng.start(&name).await {
+                warn!("error starting container: {}", e);
+                Err(warp::reject::custom(EngineError(e)))
+            } else {
+                Ok(warp::reply())
+            }
+        });
+
+    let restart = warp::post()
+        .and(engine.clone())
+        .and(warp::path!("containers" / String / "restart"))
+        .and(authorize(WRITE))
         .and_then(move |eng: Engine, name: String| async move {
-            if let Err(e) = eng.create(&name).await {
-                warn!("error creating container: {}", e);
+            if let Err(e) = eng.restart(&name).await {
+                warn!("error restarting container: {}", e);
                 Err(warp::reject::custom(EngineError(e)))
             } else {
                 Ok(warp::reply())
@@ -31,6 +124,7 @@ pub fn to_filter(svc: Engine) -> impl Filter<Extract = impl Reply> + Clone + 'st
     let delete = warp::delete()
         .and(engine.clone())
         .and(container_path)
+        .and(authorize(WRITE))
         .and_then(move |eng: Engine, name: String| async move {
             if let Err(e) = eng.delete(&name).await {
                 warn!("error deleting container: {}", e);
@@ -43,6 +137,7 @@ pub fn to_filter(svc: Engine) -> impl Filter<Extract = impl Reply> + Clone + 'st
     let modify = warp::put()
         .and(engine.clone())
         .and(warp::path!("containers" / String / "status"))
+        .and(authorize(WRITE))
         .and(warp::body::json())
         .and_then(move |eng: Engine, name: String, body: Modify| async move {
             let result = match body.state {
@@ -58,8 +153,300 @@ pub fn to_filter(svc: Engine) -> impl Filter<Extract = impl Reply> + Clone + 'st
             }
         });
 
-    let state = warp::get().and(engine).and(container_path).and_then(
-        move |eng: Engine, name: String| async move {
+    let checkpoint = warp::post()
+        .and(engine.clone())
+        .and(warp::path!("containers" / String / "checkpoint"))
+        .and(authorize(WRITE))
+        .and(optional_json())
+        .and_then(
+            move |eng: Engine, name: String, body: Checkpoint| async move {
+                if let Err(e) = eng.checkpoint(&name, body.leave_running).await {
+                    warn!("error checkpointing container: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                } else {
+                    Ok(warp::reply())
+                }
+            },
+        );
+
+    let stats = warp::get()
+        .and(engine.clone())
+        .and(warp::path!("containers" / String / "stats"))
+        .and(authorize(READ))
+        .and(warp::query::<StatsQuery>())
+        .and_then(
+            move |eng: Engine, name: String, query: StatsQuery| async move {
+                let first = match eng.stats(&name).await {
+                    Ok(stats) => stats,
+                    Err(e) => {
+                        warn!("error retrieving container stats: {}", e);
+                        return Err(warp::reject::custom(EngineError(e)));
+                    }
+                };
+
+                if !query.stream {
+                    return Ok(warp::reply::json(&first).into_response());
+                }
+
+                let mut response = Response::new(Body::wrap_stream(stats_stream(eng, name, first)));
+                let content_type = HeaderValue::from_static("application/x-ndjson");
+                response.headers_mut().insert(CONTENT_TYPE, content_type);
+                Ok(response)
+            },
+        );
+
+    let metrics_text = warp::get()
+        .and(engine.clone())
+        .and(warp::path!("metrics"))
+        .and(authorize(READ))
+        .and_then(move |eng: Engine| async move {
+            match eng.metrics().await {
+                Ok(text) => {
+                    let mut response = Response::new(Body::from(text));
+                    let content_type = HeaderValue::from_static("text/plain; version=0.0.4");
+                    response.headers_mut().insert(CONTENT_TYPE, content_type);
+                    Ok(response)
+                }
+                Err(e) => {
+                    warn!("error rendering metrics: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                }
+            }
+        });
+
+    let archive_path = warp::path!("containers" / String / "archive");
+
+    let copy_from = warp::get()
+        .and(engine.clone())
+        .and(archive_path)
+        .and(authorize(READ))
+        .and(warp::query::<ArchiveQuery>())
+        .and_then(
+            move |eng: Engine, name: String, query: ArchiveQuery| async move {
+                match eng.copy_from(&name, &query.path).await {
+                    Ok(archive) => Ok(tar_response(archive)),
+                    Err(e) => {
+                        warn!("error copying files from container: {}", e);
+                        Err(warp::reject::custom(EngineError(e)))
+                    }
+                }
+            },
+        );
+
+    let export_container = warp::get()
+        .and(engine.clone())
+        .and(warp::path!("containers" / String / "export"))
+        .and(authorize(READ))
+        .and_then(move |eng: Engine, name: String| async move {
+            match eng.export_container(&name).await {
+                Ok(archive) => Ok(tar_response(archive)),
+                Err(e) => {
+                    warn!("error exporting container: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                }
+            }
+        });
+
+    let commit = warp::post()
+        .and(engine.clone())
+        .and(warp::path!("containers" / String / "commit"))
+        .and(authorize(CREATE))
+        .and(warp::body::json())
+        .and_then(
+            move |eng: Engine, name: String, options: CommitOptions| async move {
+                match eng.commit(&name, options).await {
+                    Ok(image) => Ok(warp::reply::json(&image)),
+                    Err(e) => {
+                        warn!("error committing container: {}", e);
+                        Err(warp::reject::custom(EngineError(e)))
+                    }
+                }
+            },
+        );
+
+    let copy_to = warp::put()
+        .and(engine.clone())
+        .and(archive_path)
+        .and(authorize(WRITE))
+        .and(warp::query::<ArchiveQuery>())
+        .and(warp::body::stream())
+        .and_then(
+            move |eng: Engine, name: String, query: ArchiveQuery, body| async move {
+                if let Err(e) = eng.copy_to(&name, &query.path, Box::pin(body)).await {
+                    warn!("error copying files into container: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                } else {
+                    Ok(warp::reply())
+                }
+            },
+        );
+
+    let operation_path = warp::path!("operations" / String);
+
+    let operation = warp::get()
+        .and(engine.clone())
+        .and(operation_path)
+        .and(authorize(READ))
+        .and_then(move |eng: Engine, id: String| async move {
+            match eng.operation(&id) {
+                Ok(operation) => Ok(warp::reply::json(&operation)),
+                Err(e) => {
+                    warn!("error retrieving operation: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                }
+            }
+        });
+
+    let cancel = warp::delete()
+        .and(engine.clone())
+        .and(operation_path)
+        .and(authorize(WRITE))
+        .and_then(move |eng: Engine, id: String| async move {
+            match eng.cancel_operation(&id) {
+                Ok(operation) => Ok(warp::reply::json(&operation)),
+                Err(e) => {
+                    warn!("error cancelling operation: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                }
+            }
+        });
+
+    let pull = warp::post()
+        .and(engine.clone())
+        .and(warp::path!("images"))
+        .and(authorize(PULL))
+        .and(warp::query::<ImageQuery>())
+        .and_then(move |eng: Engine, query: ImageQuery| async move {
+            match eng.pull(&query.reference, query.platform).await {
+                Ok(image) => Ok(warp::reply::json(&image)),
+                Err(e) => {
+                    warn!("error pulling image: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                }
+            }
+        });
+
+    let import = warp::post()
+        .and(engine.clone())
+        .and(warp::path!("images" / "import"))
+        .and(authorize(PULL))
+        .and(warp::query::<ImageQuery>())
+        .and(warp::body::stream())
+        .and_then(move |eng: Engine, query: ImageQuery, body| async move {
+            match eng
+                .import(&query.reference, query.platform, Box::pin(body))
+                .await
+            {
+                Ok(image) => Ok(warp::reply::json(&image)),
+                Err(e) => {
+                    warn!("error importing image: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                }
+            }
+        });
+
+    let images = warp::get()
+        .and(engine.clone())
+        .and(warp::path!("images"))
+        .and(authorize(READ))
+        .map(move |eng: Engine| warp::reply::json(&eng.images()));
+
+    // Stored images are named by fully qualified references, which contain slashes, so they are
+    // passed as query parameters rather than path segments.
+    let image = warp::get()
+        .and(engine.clone())
+        .and(warp::path!("images" / "inspect"))
+        .and(authorize(READ))
+        .and(warp::query::<ImageQuery>())
+        .and_then(move |eng: Engine, query: ImageQuery| async move {
+            match eng.image(&query.reference, query.platform) {
+                Ok(image) => Ok(warp::reply::json(&image)),
+                Err(e) => {
+                    warn!("error retrieving image: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                }
+            }
+        });
+
+    let export_image = warp::get()
+        .and(engine.clone())
+        .and(warp::path!("images" / "export"))
+        .and(authorize(READ))
+        .and(warp::query::<ImageQuery>())
+        .and_then(move |eng: Engine, query: ImageQuery| async move {
+            match eng.export_image(&query.reference, query.platform) {
+                Ok(archive) => Ok(tar_response(archive)),
+                Err(e) => {
+                    warn!("error exporting image: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                }
+            }
+        });
+
+    let remove_image = warp::delete()
+        .and(engine.clone())
+        .and(warp::path!("images"))
+        .and(authorize(PULL))
+        .and(warp::query::<ImageQuery>())
+        .and_then(move |eng: Engine, query: ImageQuery| async move {
+            if let Err(e) = eng.remove_image(&query.reference, query.platform).await {
+                warn!("error removing image: {}", e);
+                Err(warp::reject::custom(EngineError(e)))
+            } else {
+                Ok(warp::reply())
+            }
+        });
+
+    let registries = warp::get()
+        .and(engine.clone())
+        .and(warp::path!("auth"))
+        .and(authorize(REGISTRIES))
+        .and_then(move |eng: Engine| async move {
+            match eng.registries().await {
+                Ok(registries) => Ok(warp::reply::json(&Registries { registries })),
+                Err(e) => {
+                    warn!("error listing registry credentials: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                }
+            }
+        });
+
+    let registry_path = warp::path!("auth" / String);
+
+    let login = warp::put()
+        .and(engine.clone())
+        .and(registry_path)
+        .and(authorize(REGISTRIES))
+        .and(warp::body::json())
+        .and_then(
+            move |eng: Engine, registry: String, body: Credentials| async move {
+                if let Err(e) = eng.login(&registry, &body).await {
+                    warn!("error storing registry credentials: {}", e);
+                    Err(warp::reject::custom(EngineError(e)))
+                } else {
+                    Ok(warp::reply())
+                }
+            },
+        );
+
+    let logout = warp::delete()
+        .and(engine.clone())
+        .and(registry_path)
+        .and(authorize(REGISTRIES))
+        .and_then(move |eng: Engine, registry: String| async move {
+            if let Err(e) = eng.logout(&registry).await {
+                warn!("error removing registry credentials: {}", e);
+                Err(warp::reject::custom(EngineError(e)))
+            } else {
+                Ok(warp::reply())
+            }
+        });
+
+    let state = warp::get()
+        .and(engine)
+        .and(container_path)
+        .and(authorize(READ))
+        .and_then(move |eng: Engine, name: String| async move {
             match eng.state(&name).await {
                 Ok(state) => Ok(warp::reply::json(&state)),
                 Err(e) => {
@@ -67,10 +454,99 @@ pub fn to_filter(svc: Engine) -> impl Filter<Extract = impl Reply> + Clone + 'st
                     Err(warp::reject::custom(EngineError(e)))
                 }
             }
-        },
-    );
+        });
+
+    (create
+        .or(create_only)
+        .or(start)
+        .or(restart)
+        .or(delete)
+        .or(modify)
+        .or(checkpoint)
+        .or(stats)
+        .or(copy_from)
+        .or(copy_to)
+        .or(export_container)
+        .or(commit)
+        .or(state)
+        .or(operation)
+        .or(cancel)
+        .or(pull)
+        .or(import)
+        .or(images)
+        .or(image)
+        .or(export_image)
+        .or(remove_image)
+        .or(registries)
+        .or(login)
+        .or(logout)
+        .or(metrics_text))
+    .recover(move |err| handle_rejection(metrics.clone(), err))
+    .with(warp::log::custom(move |info| {
+        request_metrics.observe_request(info.method(), info.path(), info.status());
+    }))
+}
+
+/// Query parameters for the create request.
+#[derive(Deserialize)]
+struct CreateQuery {
+    /// Whether to create the container in the background and return the operation tracking it.
+    #[serde(rename = "async", default)]
+    asynchronous: bool,
+}
+
+/// Query parameters for the request creating a container without starting it.
+#[derive(Deserialize)]
+struct NewContainerQuery {
+    /// The name of the container to create.
+    name: String,
+    /// Whether to create the container in the background and return the operation tracking it.
+    #[serde(rename = "async", default)]
+    asynchronous: bool,
+}
+
+/// Query parameters for the image requests.
+#[derive(Deserialize)]
+struct ImageQuery {
+    /// The image to pull or look up by ID or reference, or the name to import an image as, e.g.
+    /// `alpine:3.12`.
+    reference: String,
+    /// The platform to select from a multi-architecture image, or of the image to look up by
+    /// reference, e.g. `linux/arm64`.
+    platform: Option<Platform>,
+}
+
+/// Returns a `201 Created` response with a `Location` header pointing to the container `name`.
+///
+/// The header is omitted if the name cannot be represented in it, e.g. non-ASCII characters.
+fn created(name: &str) -> Result<Response<Body>, Error> {
+    let location = tryformat!(64, "/containers/{}", name).map_err(Error::Oom)?;
+
+    let mut response = warp::reply::with_status(warp::reply(), StatusCode::CREATED).into_response();
+    if let Ok(location) = HeaderValue::from_str(&location) {
+        response.headers_mut().insert(LOCATION, location);
+    }
+    Ok(response)
+}
+
+/// Returns a `202 Accepted` response describing the background `operation`, with a `Location`
+/// header pointing to its status.
+fn accepted(operation: &Operation) -> Result<Response<Body>, Error> {
+    let location = tryformat!(64, "/operations/{}", operation.id).map_err(Error::Oom)?;
+    let location = HeaderValue::from_str(&location).map_err(|e| Error::Internal(e.into()))?;
 
-    (create.or(delete).or(modify).or(state)).recover(handle_rejection)
+    let json = warp::reply::json(operation);
+    let mut response = warp::reply::with_status(json, StatusCode::ACCEPTED).into_response();
+    response.headers_mut().insert(LOCATION, location);
+    Ok(response)
+}
+
+/// Returns a response streaming the `tar` `archive`.
+fn tar_response(archive: ArchiveStream) -> Response<Body> {
+    let mut response = Response::new(Body::wrap_stream(archive));
+    let content_type = HeaderValue::from_static("application/x-tar");
+    response.headers_mut().insert(CONTENT_TYPE, content_type);
+    response
 }
 
 /// A list of possible container state transitions.
@@ -91,9 +567,94 @@ struct Modify {
     state: State,
 }
 
+/// An optional JSON body for the checkpoint request.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Checkpoint {
+    /// Whether the container should keep running after being checkpointed.
+    leave_running: bool,
+}
+
+/// Query parameters for the stats request.
+#[derive(Deserialize)]
+struct StatsQuery {
+    /// Whether to keep streaming newline-delimited samples until the container stops.
+    #[serde(default)]
+    stream: bool,
+}
+
+/// Returns a stream of newline-delimited JSON container statistics, starting with `first`.
+///
+/// The stream ends once statistics can no longer be retrieved, e.g. the container has stopped.
+fn stats_stream(
+    eng: Engine,
+    name: String,
+    first: Stats,
+) -> impl Stream<Item = Result<Bytes, serde_json::Error>> + Send + 'static {
+    stream::unfold(Some(first), move |next| {
+        let eng = eng.clone();
+        let name = name.clone();
+        async move {
+            let stats = match next {
+                Some(stats) => stats,
+                None => {
+                    tokio::time::delay_for(STATS_INTERVAL).await;
+                    match eng.stats(&name).await {
+                        Ok(stats) => stats,
+                        Err(e) => {
+                            debug!("ending stats stream for {}: {}", name, e);
+                            return None;
+                        }
+                    }
+                }
+            };
+
+            let line = serde_json::to_vec(&stats).map(|mut line| {
+                line.push(b'\n');
+                Bytes::from(line)
+            });
+
+            Some((line, None))
+        }
+    })
+}
+
+/// A JSON response listing the registries with stored credentials.
+#[derive(Serialize)]
+struct Registries {
+    registries: Vec<String>,
+}
+
+/// Query parameters for the archive requests.
+#[derive(Deserialize)]
+struct ArchiveQuery {
+    /// The absolute path inside the container to copy from or into.
+    path: String,
+}
+
+/// Extracts an optional JSON request body, falling back to `T::default()` if the body is empty.
+fn optional_json<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Copy
+where
+    T: DeserializeOwned + Default + Send + 'static,
+{
+    warp::body::bytes().and_then(|body: Bytes| async move {
+        if body.is_empty() {
+            Ok(T::default())
+        } else {
+            serde_json::from_slice(&body).map_err(|e| warp::reject::custom(InvalidBody(e)))
+        }
+    })
+}
+
+/// Custom `warp` rejection wrapping a malformed JSON request body.
+#[derive(Debug)]
+struct InvalidBody(serde_json::Error);
+
+impl warp::reject::Reject for InvalidBody {}
+
 /// Custom `warp` rejection wrapping a container engine error.
 #[derive(Debug)]
-struct EngineError(anyhow::Error);
+struct EngineError(Error);
 
 impl warp::reject::Reject for EngineError {}
 
@@ -103,44 +664,145 @@ struct OomError(TryReserveError);
 
 impl warp::reject::Reject for OomError {}
 
+/// Records an out-of-memory error in the engine metrics and converts it into a rejection.
+fn oom_rejection(metrics: &Metrics, err: TryReserveError) -> Rejection {
+    metrics.observe_oom_rejection();
+    warp::reject::custom(OomError(err))
+}
+
 /// A JSON error message response.
 #[derive(Serialize)]
 struct ErrorMsg<'a> {
     code: u16,
+    error: &'static str,
     message: Cow<'a, str>,
 }
 
-/// Converts the `warp::Rejection` into a JSON response with a status code and error message.
+/// Returns the HTTP status code reported for the container engine error `e`.
+fn status_code(e: &Error) -> StatusCode {
+    match e {
+        Error::NotFound(_) => StatusCode::NOT_FOUND,
+        Error::AlreadyExists(_) | Error::InvalidState(_) => StatusCode::CONFLICT,
+        Error::InvalidSpec(_) => StatusCode::BAD_REQUEST,
+        Error::Untrusted(_) => StatusCode::FORBIDDEN,
+        Error::ImagePullFailed(_) => StatusCode::BAD_GATEWAY,
+        Error::Oom(_) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::RuntimeFailure(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Converts the `warp::Rejection` into a JSON response with a status code, a stable error code
+/// and an error message.
 ///
 /// Returns `Err` if an out-of-memory error occurred during the conversion, or an unhandled
 /// rejection case was encountered.
-async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+async fn handle_rejection(metrics: Arc<Metrics>, err: Rejection) -> Result<impl Reply, Rejection> {
     let code;
+    let error;
     let message;
 
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
-        message = Cow::from("Container not found");
+        error = "not_found";
+        message = Cow::from("Not found");
+    } else if let Some(e) = err.find::<Unauthorized>() {
+        code = StatusCode::UNAUTHORIZED;
+        error = "unauthorized";
+        message = tryformat!(64, "{}", e)
+            .map(Cow::from)
+            .map_err(|e| oom_rejection(&metrics, e))?;
+    } else if let Some(Forbidden { principal, scope }) = err.find::<Forbidden>() {
+        code = StatusCode::FORBIDDEN;
+        error = "forbidden";
+        message = tryformat!(128, "client `{}` lacks scope `{}`", principal, scope)
+            .map(Cow::from)
+            .map_err(|e| oom_rejection(&metrics, e))?;
     } else if let Some(EngineError(e)) = err.find::<EngineError>() {
-        code = StatusCode::INTERNAL_SERVER_ERROR;
+        if let Error::Oom(_) = e {
+            metrics.observe_oom_rejection();
+        }
+
+        code = status_code(e);
+        error = e.code();
         message = tryformat!(64, "{}", e)
             .map(Cow::from)
-            .map_err(|e| warp::reject::custom(OomError(e)))?;
+            .map_err(|e| oom_rejection(&metrics, e))?;
     } else if let Some(e) = err.find::<BodyDeserializeError>() {
         code = StatusCode::BAD_REQUEST;
+        error = "invalid_request";
+        message = tryformat!(256, "{}", e)
+            .map(Cow::from)
+            .map_err(|e| oom_rejection(&metrics, e))?;
+    } else if let Some(InvalidBody(e)) = err.find::<InvalidBody>() {
+        code = StatusCode::BAD_REQUEST;
+        error = "invalid_request";
         message = tryformat!(256, "{}", e)
             .map(Cow::from)
-            .map_err(|e| warp::reject::custom(OomError(e)))?;
+            .map_err(|e| oom_rejection(&metrics, e))?;
+    } else if let Some(e) = err.find::<InvalidQuery>() {
+        code = StatusCode::BAD_REQUEST;
+        error = "invalid_request";
+        message = tryformat!(256, "{}", e)
+            .map(Cow::from)
+            .map_err(|e| oom_rejection(&metrics, e))?;
+    } else if err.find::<MethodNotAllowed>().is_some() {
+        code = StatusCode::METHOD_NOT_ALLOWED;
+        error = "method_not_allowed";
+        message = Cow::from("Method not allowed");
     } else {
         error!("unhandled rejection: {:?}", err);
         code = StatusCode::INTERNAL_SERVER_ERROR;
+        error = "internal";
         message = Cow::from("UNHANDLED_REJECTION");
     }
 
     let json = warp::reply::json(&ErrorMsg {
         code: code.as_u16(),
+        error,
         message,
     });
 
-    Ok(warp::reply::with_status(json, code))
+    let mut response = warp::reply::with_status(json, code).into_response();
+    if code == StatusCode::UNAUTHORIZED {
+        let challenge = HeaderValue::from_static("Bearer");
+        response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::fake_engine;
+
+    #[tokio::test]
+    async fn addresses_images_by_slashed_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = fake_engine(&dir);
+        engine.pull("quay.io/coreos/etcd:v3.4", None).await.unwrap();
+        let api = to_filter(engine);
+
+        let res = warp::test::request()
+            .path("/images/inspect?reference=quay.io/coreos/etcd:v3.4")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let image: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(image["names"][0], "quay.io/coreos/etcd:v3.4");
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/images?reference=quay.io%2Fcoreos%2Fetcd%3Av3.4")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = warp::test::request()
+            .path("/images/inspect?reference=quay.io/coreos/etcd:v3.4")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
 }
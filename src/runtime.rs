@@ -0,0 +1,503 @@
+//! Types for driving OCI runtimes such as `crun` and `runc`.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::container::{State, Status};
+
+/// The default `crun` binary.
+pub const CRUN_BIN: &str = "/usr/bin/crun";
+
+/// The default `runc` binary.
+pub const RUNC_BIN: &str = "/usr/bin/runc";
+
+/// An OCI runtime which creates and controls containers from OCI bundles.
+///
+/// See the [OCI runtime specification] for the semantics of each operation.
+///
+/// [OCI runtime specification]: https://github.com/opencontainers/runtime-spec/blob/master/runtime.md
+#[async_trait]
+pub trait Runtime: Debug + Send + Sync {
+    /// Returns the name by which this runtime is selected, e.g. `crun`.
+    fn name(&self) -> &str;
+
+    /// Returns the path to the runtime binary, for use by container monitors like `conmon`.
+    fn binary(&self) -> &Path;
+
+    /// Creates the container `id` from the OCI bundle at `bundle_dir`, without starting it.
+    ///
+    /// The container PID is written to `pid_file`. If `console_socket` is given, the pseudo
+    /// terminal of the container is sent over it.
+    async fn create(
+        &self,
+        id: &str,
+        bundle_dir: &Path,
+        pid_file: &Path,
+        console_socket: Option<&Path>,
+    ) -> anyhow::Result<()>;
+
+    /// Starts the user process of the created container `id`.
+    async fn start(&self, id: &str) -> anyhow::Result<()>;
+
+    /// Sends `signal`, e.g. `TERM` or `9`, to the init process of the container `id`.
+    async fn kill(&self, id: &str, signal: &str) -> anyhow::Result<()>;
+
+    /// Suspends all processes of the container `id`.
+    async fn pause(&self, id: &str) -> anyhow::Result<()>;
+
+    /// Resumes all processes of the paused container `id`.
+    async fn resume(&self, id: &str) -> anyhow::Result<()>;
+
+    /// Deletes the container `id`, killing it first if `force` is `true`.
+    async fn delete(&self, id: &str, force: bool) -> anyhow::Result<()>;
+
+    /// Forcibly deletes the container `id` synchronously, ignoring any errors.
+    ///
+    /// This is intended for cleanup in [`Drop`] implementations, where awaiting is impossible.
+    fn delete_sync(&self, id: &str);
+
+    /// Retrieves the current state of the container `id`.
+    async fn state(&self, id: &str) -> anyhow::Result<State>;
+
+    /// Updates the resource limits of the container `id` to the OCI `linux.resources` object.
+    async fn update(&self, id: &str, resources: &serde_json::Value) -> anyhow::Result<()>;
+
+    /// Returns a command which executes the arguments appended to it inside the container `id`.
+    fn exec(&self, id: &str) -> Command;
+
+    /// Checkpoints the container `id` with CRIU, writing the images to `image_dir`.
+    async fn checkpoint(
+        &self,
+        id: &str,
+        image_dir: &Path,
+        work_dir: &Path,
+        leave_running: bool,
+    ) -> anyhow::Result<()>;
+}
+
+/// An OCI runtime driven through a `runc`-compatible command-line interface.
+#[derive(Debug)]
+pub struct CliRuntime {
+    name: String,
+    binary: PathBuf,
+}
+
+impl CliRuntime {
+    /// Creates a runtime called `name` which invokes the given `binary`.
+    pub fn new<S: Into<String>, P: Into<PathBuf>>(name: S, binary: P) -> Self {
+        CliRuntime {
+            name: name.into(),
+            binary: binary.into(),
+        }
+    }
+
+    /// Creates a runtime which invokes `crun` at its default location.
+    pub fn crun() -> Self {
+        CliRuntime::new("crun", CRUN_BIN)
+    }
+
+    /// Creates a runtime which invokes `runc` at its default location.
+    pub fn runc() -> Self {
+        CliRuntime::new("runc", RUNC_BIN)
+    }
+
+    fn command(&self) -> Command {
+        Command::new(&self.binary)
+    }
+}
+
+#[async_trait]
+impl Runtime for CliRuntime {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn binary(&self) -> &Path {
+        &self.binary
+    }
+
+    async fn create(
+        &self,
+        id: &str,
+        bundle_dir: &Path,
+        pid_file: &Path,
+        console_socket: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        let mut create_cmd = self.command();
+        create_cmd
+            .arg("create")
+            .arg("--bundle")
+            .arg(bundle_dir)
+            .arg("--pid-file")
+            .arg(pid_file);
+
+        if let Some(socket) = console_socket {
+            create_cmd.arg("--console-socket").arg(socket);
+        }
+
+        exec_command(create_cmd.arg(id)).await?;
+        Ok(())
+    }
+
+    async fn start(&self, id: &str) -> anyhow::Result<()> {
+        exec_command(self.command().args(&["start", id])).await?;
+        Ok(())
+    }
+
+    async fn kill(&self, id: &str, signal: &str) -> anyhow::Result<()> {
+        exec_command(self.command().args(&["kill", id, signal])).await?;
+        Ok(())
+    }
+
+    async fn pause(&self, id: &str) -> anyhow::Result<()> {
+        exec_command(self.command().args(&["pause", id])).await?;
+        Ok(())
+    }
+
+    async fn resume(&self, id: &str) -> anyhow::Result<()> {
+        exec_command(self.command().args(&["resume", id])).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str, force: bool) -> anyhow::Result<()> {
+        let mut delete_cmd = self.command();
+        delete_cmd.arg("delete");
+        if force {
+            delete_cmd.arg("--force");
+        }
+
+        exec_command(delete_cmd.arg(id)).await?;
+        Ok(())
+    }
+
+    fn delete_sync(&self, id: &str) {
+        std::process::Command::new(&self.binary)
+            .args(&["delete", "--force", id])
+            .status()
+            .ok();
+    }
+
+    async fn state(&self, id: &str) -> anyhow::Result<State> {
+        let stdout = exec_command(self.command().args(&["state", id])).await?;
+        Ok(serde_json::from_slice(&stdout)?)
+    }
+
+    async fn update(&self, id: &str, resources: &serde_json::Value) -> anyhow::Result<()> {
+        // Not every runtime reads resources from `stdin`, so pass them as a file instead.
+        let mut resources_file = tempfile::NamedTempFile::new()?;
+        serde_json::to_writer(&mut resources_file, resources)?;
+        resources_file.flush()?;
+
+        let mut update_cmd = self.command();
+        update_cmd
+            .arg("update")
+            .arg("--resources")
+            .arg(resources_file.path());
+
+        exec_command(update_cmd.arg(id)).await?;
+        Ok(())
+    }
+
+    fn exec(&self, id: &str) -> Command {
+        let mut exec_cmd = self.command();
+        exec_cmd.args(&["exec", id]);
+        exec_cmd
+    }
+
+    async fn checkpoint(
+        &self,
+        id: &str,
+        image_dir: &Path,
+        work_dir: &Path,
+        leave_running: bool,
+    ) -> anyhow::Result<()> {
+        let mut checkpoint_cmd = self.command();
+        checkpoint_cmd
+            .arg("checkpoint")
+            .arg("--image-path")
+            .arg(image_dir)
+            .arg("--work-path")
+            .arg(work_dir);
+
+        if leave_running {
+            checkpoint_cmd.arg("--leave-running");
+        }
+
+        exec_command(checkpoint_cmd.arg(id)).await?;
+        Ok(())
+    }
+}
+
+/// Executes the runtime command `cmd`, returning its `stdout` on success.
+///
+/// Returns `Err` if the command could not be spawned or exited with a non-zero status.
+pub(crate) async fn exec_command(cmd: &mut Command) -> anyhow::Result<Vec<u8>> {
+    debug!("executing runtime command: {:?}", cmd);
+
+    let output = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = std::str::from_utf8(&output.stderr)?;
+        return Err(anyhow!("`{:?}` returned: [{}]", cmd, stderr));
+    }
+
+    Ok(output.stdout)
+}
+
+/// An in-memory OCI runtime which only tracks container state transitions.
+///
+/// This is intended for testing engine logic without real containers. Nothing runs inside fake
+/// containers, so commands passed to [`Runtime::exec()`] always fail.
+#[derive(Debug, Default)]
+pub struct FakeRuntime {
+    containers: Mutex<HashMap<String, FakeContainer>>,
+}
+
+#[derive(Debug)]
+struct FakeContainer {
+    bundle: PathBuf,
+    status: Status,
+    resources: Option<serde_json::Value>,
+}
+
+/// The PID reported for every fake container.
+const FAKE_PID: u64 = 1;
+
+impl FakeRuntime {
+    /// Creates a new fake runtime with no containers.
+    pub fn new() -> Self {
+        FakeRuntime::default()
+    }
+
+    /// Returns the resources last passed to [`Runtime::update()`] for the container `id`.
+    pub fn resources(&self, id: &str) -> Option<serde_json::Value> {
+        let containers = self.containers.lock().expect("fake runtime lock poisoned");
+        containers.get(id).and_then(|c| c.resources.clone())
+    }
+
+    fn transition<F>(&self, id: &str, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut FakeContainer) -> anyhow::Result<()>,
+    {
+        let mut containers = self.containers.lock().expect("fake runtime lock poisoned");
+        match containers.get_mut(id) {
+            Some(container) => f(container),
+            None => Err(anyhow!("container `{}` does not exist", id)),
+        }
+    }
+}
+
+#[async_trait]
+impl Runtime for FakeRuntime {
+    fn name(&self) -> &str {
+        "fake"
+    }
+
+    fn binary(&self) -> &Path {
+        Path::new("/bin/false")
+    }
+
+    async fn create(
+        &self,
+        id: &str,
+        bundle_dir: &Path,
+        _pid_file: &Path,
+        _console_socket: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        let mut containers = self.containers.lock().expect("fake runtime lock poisoned");
+        if containers.contains_key(id) {
+            return Err(anyhow!("container `{}` already exists", id));
+        }
+
+        let container = FakeContainer {
+            bundle: bundle_dir.to_owned(),
+            status: Status::Created { pid: FAKE_PID },
+            resources: None,
+        };
+
+        containers.insert(id.to_owned(), container);
+        Ok(())
+    }
+
+    async fn start(&self, id: &str) -> anyhow::Result<()> {
+        self.transition(id, |c| match c.status {
+            Status::Created { pid } => {
+                c.status = Status::Running { pid };
+                Ok(())
+            }
+            ref status => Err(anyhow!("cannot start container in state {:?}", status)),
+        })
+    }
+
+    async fn kill(&self, id: &str, signal: &str) -> anyhow::Result<()> {
+        let signal: i64 = match signal.trim_start_matches("SIG") {
+            "KILL" => libc::SIGKILL.into(),
+            "TERM" => libc::SIGTERM.into(),
+            other => other.parse()?,
+        };
+
+        self.transition(id, |c| match c.status {
+            Status::Stopped { .. } => Err(anyhow!("container is not running")),
+            _ => {
+                c.status = Status::Stopped {
+                    exit_code: 128 + signal,
+                };
+                Ok(())
+            }
+        })
+    }
+
+    async fn pause(&self, id: &str) -> anyhow::Result<()> {
+        self.transition(id, |c| match c.status {
+            Status::Running { pid } | Status::Paused { pid } => {
+                c.status = Status::Paused { pid };
+                Ok(())
+            }
+            ref status => Err(anyhow!("cannot pause container in state {:?}", status)),
+        })
+    }
+
+    async fn resume(&self, id: &str) -> anyhow::Result<()> {
+        self.transition(id, |c| match c.status {
+            Status::Running { pid } | Status::Paused { pid } => {
+                c.status = Status::Running { pid };
+                Ok(())
+            }
+            ref status => Err(anyhow!("cannot resume container in state {:?}", status)),
+        })
+    }
+
+    async fn delete(&self, id: &str, force: bool) -> anyhow::Result<()> {
+        let mut containers = self.containers.lock().expect("fake runtime lock poisoned");
+        match containers.get(id).map(|c| &c.status) {
+            Some(Status::Stopped { .. }) | Some(Status::Created { .. }) => {}
+            Some(_) if force => {}
+            Some(_) => return Err(anyhow!("container `{}` is still running", id)),
+            None => return Err(anyhow!("container `{}` does not exist", id)),
+        }
+
+        containers.remove(id);
+        Ok(())
+    }
+
+    fn delete_sync(&self, id: &str) {
+        let mut containers = self.containers.lock().expect("fake runtime lock poisoned");
+        containers.remove(id);
+    }
+
+    async fn state(&self, id: &str) -> anyhow::Result<State> {
+        let containers = self.containers.lock().expect("fake runtime lock poisoned");
+        let container = containers
+            .get(id)
+            .ok_or_else(|| anyhow!("container `{}` does not exist", id))?;
+
+        Ok(State {
+            id: id.to_owned(),
+            status: container.status.clone(),
+            bundle: container.bundle.clone(),
+            cgroup: None,
+            oom_killed: false,
+            finished_at: None,
+            health: None,
+            image: None,
+        })
+    }
+
+    async fn update(&self, id: &str, resources: &serde_json::Value) -> anyhow::Result<()> {
+        self.transition(id, |c| {
+            c.resources = Some(resources.clone());
+            Ok(())
+        })
+    }
+
+    fn exec(&self, _id: &str) -> Command {
+        Command::new(self.binary())
+    }
+
+    async fn checkpoint(
+        &self,
+        id: &str,
+        _image_dir: &Path,
+        _work_dir: &Path,
+        leave_running: bool,
+    ) -> anyhow::Result<()> {
+        if leave_running {
+            self.transition(id, |_| Ok(()))
+        } else {
+            self.kill(id, "KILL").await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn fake_runtime_follows_lifecycle() {
+        let runtime = FakeRuntime::new();
+        let bundle = Path::new("/tmp/bundle");
+        let pid_file = Path::new("/tmp/bundle/pidfile");
+
+        runtime
+            .create("busybox", bundle, pid_file, None)
+            .await
+            .unwrap();
+        assert!(runtime
+            .create("busybox", bundle, pid_file, None)
+            .await
+            .is_err());
+        assert!(runtime.pause("busybox").await.is_err());
+
+        runtime.start("busybox").await.unwrap();
+        runtime.pause("busybox").await.unwrap();
+        let state = runtime.state("busybox").await.unwrap();
+        assert!(matches!(state.status, Status::Paused { .. }));
+
+        runtime.resume("busybox").await.unwrap();
+        assert!(runtime.delete("busybox", false).await.is_err());
+
+        runtime.kill("busybox", "SIGKILL").await.unwrap();
+        let state = runtime.state("busybox").await.unwrap();
+        assert!(matches!(state.status, Status::Stopped { exit_code: 137 }));
+
+        runtime.delete("busybox", false).await.unwrap();
+        assert!(runtime.state("busybox").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fake_runtime_records_resources() {
+        let runtime = FakeRuntime::new();
+        let bundle = Path::new("/tmp/bundle");
+        runtime
+            .create("busybox", bundle, &bundle.join("pidfile"), None)
+            .await
+            .unwrap();
+
+        let resources = json!({ "memory": { "limit": 1048576 } });
+        runtime.update("busybox", &resources).await.unwrap();
+        assert_eq!(runtime.resources("busybox"), Some(resources));
+    }
+
+    #[tokio::test]
+    async fn fake_runtime_fails_exec() {
+        let runtime = FakeRuntime::new();
+        let output = runtime.exec("busybox").arg("true").output().await.unwrap();
+        assert!(!output.status.success());
+    }
+}
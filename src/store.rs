@@ -0,0 +1,430 @@
+//! A local store of OCI images, from which containers are created without pulling.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use dashmap::DashMap;
+use fallible_collections::tryformat;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::archive::{self, ArchiveStream, TAR_BIN};
+use crate::config::EngineConfig;
+use crate::error::Error;
+use crate::image::{self, ImageBackend, OciBundle, OciImage};
+use crate::platform::Platform;
+use crate::policy::Verification;
+
+/// Name of the storage subdirectory containing stored images.
+pub const IMAGES_DIR: &str = "images";
+
+/// An image kept in the local image store.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Image {
+    /// The digest of the platform-specific image manifest, e.g. `sha256:...`.
+    pub id: String,
+    /// The fully-qualified references pointing at the image, e.g. `docker.io/library/alpine:3.12`.
+    pub names: Vec<String>,
+    /// The digest of the image in the registry it was pulled from, if any.
+    ///
+    /// For multi-architecture images, this refers to the index of all platforms.
+    pub digest: Option<String>,
+    /// The platform the image was built for.
+    pub platform: Platform,
+    /// The total size of the image blobs, in bytes.
+    pub size: u64,
+    /// The outcome of verifying the image against the signature policy when it was last pulled
+    /// or imported, or `None` if it was not verified.
+    #[serde(default)]
+    pub verification: Option<Verification>,
+}
+
+/// When to pull the image of a new container instead of using a stored one.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PullPolicy {
+    /// Pull the image only if it is not stored yet, which is the default.
+    Missing,
+    /// Always pull the image, e.g. to pick up a tag which has moved on.
+    Always,
+    /// Never pull the image, failing if it is not stored.
+    Never,
+}
+
+impl Default for PullPolicy {
+    fn default() -> Self {
+        PullPolicy::Missing
+    }
+}
+
+/// Keeps a stored image from being pruned for as long as it is held, e.g. by the bundles unpacked
+/// from it.
+#[derive(Clone, Debug)]
+pub(crate) struct ImageLease(Arc<()>);
+
+/// A directory of OCI image layouts, each named after the hex digest of its image ID and
+/// described by a JSON file next to it.
+#[derive(Debug)]
+pub(crate) struct ImageStore {
+    dir: PathBuf,
+    images: DashMap<String, Image>,
+    /// The leases handed out for each image, which are in use while they have other holders.
+    leases: DashMap<String, ImageLease>,
+    /// Serializes updates, so concurrent pulls of the same name don't both keep it.
+    lock: Mutex<()>,
+}
+
+impl ImageStore {
+    /// Opens the image store in `dir`, creating it if it doesn't exist yet.
+    ///
+    /// Images with malformed descriptions are skipped, while those left without names by an
+    /// earlier removal are deleted.
+    ///
+    /// Returns `Err` if the directory could not be created or read, or if an unused image could
+    /// not be deleted.
+    pub fn open(dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create image store `{}`", dir.display()))?;
+
+        let images = DashMap::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+
+            let image = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(serde_json::from_slice::<Image>(&bytes)?));
+            match image {
+                Ok(image) if image.names.is_empty() => {
+                    // Removed while in use before, and nothing can use it any more.
+                    std::fs::remove_file(&path)?;
+                    match std::fs::remove_dir_all(path.with_extension("")) {
+                        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                        _ => info!("removed unused image {}", image.id),
+                    }
+                }
+                Ok(image) => {
+                    images.insert(image.id.clone(), image);
+                }
+                Err(e) => warn!("skipping stored image `{}`: {:#}", path.display(), e),
+            }
+        }
+
+        Ok(ImageStore {
+            dir,
+            images,
+            leases: DashMap::new(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Returns all stored images, ordered by ID.
+    pub fn list(&self) -> Vec<Image> {
+        let mut images: Vec<Image> = self.images.iter().map(|i| i.value().clone()).collect();
+        images.sort_by(|a, b| a.id.cmp(&b.id));
+        images
+    }
+
+    /// Returns the image with the given `id`, either `sha256:<hex>` or just `<hex>`.
+    pub fn get(&self, id: &str) -> Option<Image> {
+        let hex = id.strip_prefix("sha256:").unwrap_or(id);
+        if !image::is_sha256_hex(hex) {
+            return None;
+        }
+
+        let image = self.images.get(&format!("sha256:{}", hex))?;
+        Some(image.value().clone())
+    }
+
+    /// Returns the first of `names` pointing at an image for `platform`, along with that image.
+    ///
+    /// An image built for exactly `platform` is preferred over a compatible one, e.g. one without
+    /// a variant. Among equally good images, the one with the lowest ID is returned.
+    pub fn find(&self, names: &[String], platform: &Platform) -> Option<(String, Image)> {
+        names.iter().find_map(|name| {
+            self.images
+                .iter()
+                .filter(|i| i.platform.satisfies(platform) && i.names.contains(name))
+                .min_by(|a, b| {
+                    let inexact = |image: &Image| image.platform != *platform;
+                    inexact(a).cmp(&inexact(b)).then_with(|| a.id.cmp(&b.id))
+                })
+                .map(|i| (name.clone(), i.value().clone()))
+        })
+    }
+
+    /// Moves a fetched `image` into the store under its name, or adds the name to the stored
+    /// image if it is already present.
+    ///
+    /// The name is removed from any other image for the same platform, e.g. when a tag has moved
+    /// on to a newer image. The verification of a stored image is replaced by that of `image`,
+    /// if it has been verified.
+    ///
+    /// Returns `Err` if the image layout or its description could not be written, or if an
+    /// out-of-memory error was encountered.
+    pub async fn insert(&self, image: OciImage) -> Result<Image, Error> {
+        let _guard = self.lock.lock().await;
+        let info = image.info().clone();
+
+        let existing = self.images.get(image.id()).map(|i| i.value().clone());
+        let stored = match existing {
+            Some(mut stored) => {
+                if !stored.names.contains(&info.name) {
+                    stored.names.push(info.name.clone());
+                }
+
+                if let Some(verification) = image.verification() {
+                    stored.verification = Some(verification.clone());
+                }
+                stored
+            }
+            None => {
+                let stored = Image {
+                    id: image.id().to_owned(),
+                    names: vec![info.name.clone()],
+                    digest: image.registry_digest().map(str::to_owned),
+                    platform: info.platform.clone(),
+                    size: image.size().await?,
+                    verification: image.verification().cloned(),
+                };
+
+                let layout_dir = self.layout_dir(&stored.id)?;
+                match tokio::fs::remove_dir_all(&layout_dir).await {
+                    Ok(()) => warn!("replacing undescribed image layout {}", stored.id),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+
+                image.persist(&layout_dir).await?;
+                info!("stored image {} as `{}`", stored.id, info.name);
+                stored
+            }
+        };
+
+        let previous: Vec<Image> = self
+            .images
+            .iter()
+            .filter(|i| i.id != stored.id && i.platform == stored.platform)
+            .filter(|i| i.names.contains(&info.name))
+            .map(|i| i.value().clone())
+            .collect();
+
+        for mut image in previous {
+            image.names.retain(|name| *name != info.name);
+            self.describe(&image).await?;
+        }
+
+        self.describe(&stored).await?;
+        Ok(stored)
+    }
+
+    /// Removes `name` from the stored image with the given `id`, or all of its names if `name` is
+    /// `None`, leaving the image to be pruned once it is no longer in use.
+    ///
+    /// Returns `Err` if the image does not exist, or if its description could not be written.
+    pub async fn untag(&self, id: &str, name: Option<&str>) -> Result<Image, Error> {
+        let _guard = self.lock.lock().await;
+        let mut image = match self.images.get(id) {
+            Some(image) => image.value().clone(),
+            None => return Err(not_found(id)),
+        };
+
+        match name {
+            Some(name) => image.names.retain(|n| n != name),
+            None => image.names.clear(),
+        }
+        self.describe(&image).await?;
+        Ok(image)
+    }
+
+    /// Deletes the stored images which no longer have any names and are not leased.
+    ///
+    /// Returns `Err` if a layout or description could not be removed.
+    pub async fn prune(&self) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        let unnamed: Vec<String> = self
+            .images
+            .iter()
+            .filter(|i| i.names.is_empty())
+            .map(|i| i.id.clone())
+            .collect();
+
+        for id in unnamed {
+            let leased = self
+                .leases
+                .get(&id)
+                .map_or(false, |l| Arc::strong_count(&l.0) > 1);
+            if leased {
+                continue;
+            }
+
+            // Remove the description first, so a failure won't leave an image without a layout.
+            let layout_dir = self.layout_dir(&id)?;
+            tokio::fs::remove_file(layout_dir.with_extension("json")).await?;
+            self.images.remove(&id);
+            self.leases.remove(&id);
+            match tokio::fs::remove_dir_all(&layout_dir).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => info!("removed unused image {}", id),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unpacks the stored image with the given `id` into a new bundle inside the storage
+    /// directory with `backend`, which refers to the stored image layout.
+    ///
+    /// The bundle holds a lease on the image, so its layout is kept for committing until the
+    /// bundle is dropped.
+    ///
+    /// Returns `Err` if the image does not exist, or if unpacking failed.
+    pub async fn unpack(
+        &self,
+        id: &str,
+        backend: &dyn ImageBackend,
+        config: &EngineConfig,
+    ) -> anyhow::Result<OciBundle> {
+        let lease = {
+            let _guard = self.lock.lock().await;
+            if !self.images.contains_key(id) {
+                return Err(not_found(id).into());
+            }
+
+            self.leases
+                .entry(id.to_owned())
+                .or_insert_with(|| ImageLease(Arc::new(())))
+                .clone()
+        };
+
+        let layout_dir = self.layout_dir(id)?;
+        let mut bundle = backend.unpack(&layout_dir, config).await?;
+        bundle.image_dir = Some(layout_dir);
+        bundle.lease = Some(lease);
+        Ok(bundle)
+    }
+
+    /// Archives the OCI image layout of the image with the given `id` as a `tar` stream.
+    ///
+    /// Returns `Err` if the ID is invalid, or if `tar` could not be spawned.
+    pub fn export(&self, id: &str) -> Result<ArchiveStream, Error> {
+        let layout_dir = self.layout_dir(id)?;
+        let cmd = archive::pack_command(TAR_BIN, &layout_dir, Path::new("."));
+        Ok(archive::pack(cmd)?)
+    }
+
+    /// Returns the path of the OCI image layout of the image with the given `id`.
+    ///
+    /// Returns `Err` if the ID is not a SHA-256 digest.
+    fn layout_dir(&self, id: &str) -> Result<PathBuf, Error> {
+        match id.strip_prefix("sha256:") {
+            Some(hex) if image::is_sha256_hex(hex) => Ok(self.dir.join(hex)),
+            _ => Err(Error::InvalidSpec(format!("invalid image ID `{}`", id))),
+        }
+    }
+
+    /// Writes the description of `image` next to its layout and updates the in-memory index.
+    async fn describe(&self, image: &Image) -> Result<(), Error> {
+        let path = self.layout_dir(&image.id)?.with_extension("json");
+        let bytes = serde_json::to_vec_pretty(image).map_err(|e| Error::Internal(e.into()))?;
+        write_atomic(&path, &bytes).await?;
+
+        self.images.insert(image.id.clone(), image.clone());
+        Ok(())
+    }
+}
+
+/// Returns the error for a missing image with the given `id`.
+fn not_found(id: &str) -> Error {
+    match tryformat!(96, "image {} does not exist", id) {
+        Ok(msg) => Error::NotFound(msg),
+        Err(e) => Error::Oom(e),
+    }
+}
+
+/// Replaces the file at `path` with `bytes`, so a failure won't leave it truncated.
+async fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let temp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&temp_path, bytes).await?;
+    tokio::fs::rename(&temp_path, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(id_byte: char, name: &str, platform: &str) -> Image {
+        Image {
+            id: format!("sha256:{}", id_byte.to_string().repeat(64)),
+            names: vec![name.into()],
+            digest: None,
+            platform: platform.parse().unwrap(),
+            size: 0,
+            verification: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_images_by_name_and_platform() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ImageStore::open(dir.path().join(IMAGES_DIR)).unwrap();
+
+        let alpine = "docker.io/library/alpine:3.12".to_string();
+        store
+            .describe(&image('a', &alpine, "linux/amd64"))
+            .await
+            .unwrap();
+        store
+            .describe(&image('b', &alpine, "linux/arm/v7"))
+            .await
+            .unwrap();
+
+        let armv7: Platform = "linux/arm/v7".parse().unwrap();
+        let names = ["registry.local/alpine:3.12".to_string(), alpine.clone()];
+        let (name, found) = store.find(&names, &armv7).unwrap();
+        assert_eq!(name, alpine);
+        assert_eq!(found.id, image('b', &alpine, "linux/arm/v7").id);
+
+        let arm64: Platform = "linux/arm64".parse().unwrap();
+        assert!(store.find(&names, &arm64).is_none());
+
+        assert!(store.get(&"a".repeat(64)).is_some());
+        assert!(store.get(&format!("sha256:{}", "c".repeat(64))).is_none());
+        assert!(store.get("../etc").is_none());
+
+        // Descriptions survive reopening the store.
+        let reopened = ImageStore::open(dir.path().join(IMAGES_DIR)).unwrap();
+        assert_eq!(reopened.list(), store.list());
+        assert_eq!(reopened.list().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn prefers_exact_platform() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ImageStore::open(dir.path().join(IMAGES_DIR)).unwrap();
+
+        let alpine = "docker.io/library/alpine:3.12".to_string();
+        store
+            .describe(&image('c', &alpine, "linux/arm64"))
+            .await
+            .unwrap();
+        store
+            .describe(&image('d', &alpine, "linux/arm64/v8"))
+            .await
+            .unwrap();
+
+        let names = [alpine.clone()];
+        let arm64v8: Platform = "linux/arm64/v8".parse().unwrap();
+        let (_, found) = store.find(&names, &arm64v8).unwrap();
+        assert_eq!(found.id, image('d', &alpine, "linux/arm64/v8").id);
+
+        let arm64: Platform = "linux/arm64".parse().unwrap();
+        let (_, found) = store.find(&names, &arm64).unwrap();
+        assert_eq!(found.id, image('c', &alpine, "linux/arm64").id);
+    }
+}
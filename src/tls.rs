@@ -0,0 +1,294 @@
+//! TLS termination and client certificate authentication for TCP listeners.
+
+use std::convert::Infallible;
+use std::fmt::{self, Debug, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use futures_util::future;
+use futures_util::stream::{Stream, StreamExt};
+use http::{Request, Response};
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn, Service};
+use hyper::{Body, Server};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore,
+    ServerConfig, Session,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::webpki::{DNSNameRef, EndEntityCert};
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+use crate::auth::{Access, Principal};
+
+/// Maximum time allowed for a client to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of TLS handshakes performed concurrently per listener.
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// Name of the principal for clients presenting a valid certificate not listed in the config.
+const UNKNOWN_CLIENT: &str = "<unknown client>";
+
+/// TLS settings for the TCP listeners.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file containing the server certificate chain.
+    pub cert: PathBuf,
+    /// PEM file containing the server private key, in PKCS #8 or PKCS #1 format.
+    pub key: PathBuf,
+    /// PEM file containing the CA certificates which client certificates must be signed by.
+    ///
+    /// If set, clients must authenticate with a certificate, and only those listed in
+    /// [`TlsConfig::clients`] are granted access.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Access levels granted to authenticated clients.
+    #[serde(default)]
+    pub clients: Vec<TlsClient>,
+}
+
+/// An API client authenticated with a certificate.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsClient {
+    /// DNS name which must be present among the subject alternative names of the certificate.
+    pub name: String,
+    /// The access level granted to the client.
+    pub access: Access,
+}
+
+/// Accepts TLS connections and identifies the clients behind them.
+pub(crate) struct TlsServer {
+    acceptor: TlsAcceptor,
+    client_auth: bool,
+    clients: Vec<TlsClient>,
+}
+
+impl Debug for TlsServer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("TlsServer")
+            .field("client_auth", &self.client_auth)
+            .field("clients", &self.clients)
+            .finish()
+    }
+}
+
+impl TlsServer {
+    /// Loads the certificates and keys referenced by `config`.
+    ///
+    /// Returns `Err` if a file could not be read or parsed, or if clients are listed without a
+    /// client CA to authenticate them with.
+    pub fn new(config: &TlsConfig) -> anyhow::Result<Self> {
+        let verifier = match config.client_ca.as_deref() {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                let (added, _) = roots
+                    .add_pem_file(&mut open(path)?)
+                    .map_err(|_| anyhow!("invalid client CA file `{}`", path.display()))?;
+                if added == 0 {
+                    return Err(anyhow!("no certificates in `{}`", path.display()));
+                }
+                AllowAnyAuthenticatedClient::new(roots)
+            }
+            None if !config.clients.is_empty() => {
+                return Err(anyhow!("TLS clients require `client_ca` to be set"));
+            }
+            None => NoClientAuth::new(),
+        };
+
+        let mut server_config = ServerConfig::new(verifier);
+        server_config
+            .set_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+            .context("invalid TLS certificate or key")?;
+
+        for client in &config.clients {
+            DNSNameRef::try_from_ascii_str(&client.name)
+                .map_err(|_| anyhow!("invalid TLS client name `{}`", client.name))?;
+        }
+
+        Ok(TlsServer {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            client_auth: config.client_ca.is_some(),
+            clients: config.clients.clone(),
+        })
+    }
+
+    /// Performs the TLS handshake on `conn`.
+    ///
+    /// Returns `None` if the handshake failed or timed out.
+    async fn handshake(self: Arc<Self>, conn: TcpStream) -> Option<TlsStream<TcpStream>> {
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(conn)).await {
+            Ok(Ok(stream)) => Some(stream),
+            Ok(Err(e)) => {
+                debug!("TLS handshake failed: {}", e);
+                None
+            }
+            Err(_) => {
+                debug!("TLS handshake timed out");
+                None
+            }
+        }
+    }
+
+    /// Returns the principal of the client on the other end of `conn`.
+    ///
+    /// Returns `None` if client authentication is disabled, in which case requests are not
+    /// restricted. Clients with a certificate matching none of the configured names receive a
+    /// principal without any scopes.
+    fn identify(&self, conn: &TlsStream<TcpStream>) -> Option<Principal> {
+        if !self.client_auth {
+            return None;
+        }
+
+        let (_, session) = conn.get_ref();
+        let client = session
+            .get_peer_certificates()
+            .and_then(|certs| certs.into_iter().next())
+            .and_then(|cert| self.find_client(&cert));
+
+        Some(match client {
+            Some(client) => Principal {
+                name: client.name.clone(),
+                scopes: client.access.scopes().to_vec(),
+            },
+            None => Principal {
+                name: UNKNOWN_CLIENT.into(),
+                scopes: Vec::new(),
+            },
+        })
+    }
+
+    /// Returns the first configured client whose name is valid for the end-entity `cert`.
+    fn find_client(&self, cert: &Certificate) -> Option<&TlsClient> {
+        let cert = EndEntityCert::from(&cert.0).ok()?;
+        self.clients.iter().find(|client| {
+            DNSNameRef::try_from_ascii_str(&client.name)
+                .map(|name| cert.verify_is_valid_for_dns_name(name).is_ok())
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// Serves `service` over TLS on the `incoming` TCP connections.
+///
+/// The principal of each authenticated client is attached to the extensions of its requests.
+pub(crate) async fn serve<S, I>(service: S, incoming: I, tls: Arc<TlsServer>) -> hyper::Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    I: Stream<Item = TcpStream> + Send + 'static,
+{
+    let acceptor = tls.clone();
+    let streams = incoming
+        .map(move |conn| acceptor.clone().handshake(conn))
+        .buffer_unordered(MAX_PENDING_HANDSHAKES)
+        .filter_map(|stream| future::ready(stream.map(Ok::<_, io::Error>)));
+
+    let make_service = make_service_fn(move |conn: &TlsStream<TcpStream>| {
+        let principal = tls.identify(conn);
+        let mut service = service.clone();
+        future::ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+            if let Some(principal) = principal.clone() {
+                request.extensions_mut().insert(principal);
+            }
+            service.call(request)
+        }))
+    });
+
+    Server::builder(accept::from_stream(streams))
+        .serve(make_service)
+        .await
+}
+
+/// Opens the PEM file at `path` for reading.
+fn open(path: &Path) -> anyhow::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .with_context(|| format!("failed to open `{}`", path.display()))
+}
+
+/// Loads a PEM certificate chain from `path`.
+fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    match pemfile::certs(&mut open(path)?) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(anyhow!("no certificates in `{}`", path.display())),
+    }
+}
+
+/// Loads the first PKCS #8 or PKCS #1 private key from `path`.
+fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut open(path)?).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut open(path)?).unwrap_or_default();
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| anyhow!("no private key in `{}`", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tls_config() {
+        let config: TlsConfig = toml::from_str(
+            r#"
+            cert = "/etc/light-containerd/server.crt"
+            key = "/etc/light-containerd/server.key"
+            client_ca = "/etc/light-containerd/ca.crt"
+
+            [[clients]]
+            name = "fleet.example.com"
+            access = "full"
+
+            [[clients]]
+            name = "dashboard.example.com"
+            access = "read-only"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.clients.len(), 2);
+        assert_eq!(config.clients[1].access, Access::ReadOnly);
+        assert!(toml::from_str::<TlsConfig>("cert = \"server.crt\"").is_err());
+    }
+
+    #[test]
+    fn requires_client_ca_for_clients() {
+        let config = TlsConfig {
+            clients: vec![TlsClient {
+                name: "fleet.example.com".into(),
+                access: Access::Full,
+            }],
+            ..TlsConfig::default()
+        };
+
+        let e = TlsServer::new(&config).err().unwrap();
+        assert!(e.to_string().contains("client_ca"));
+    }
+
+    #[test]
+    fn rejects_missing_certificate() {
+        let config = TlsConfig {
+            cert: "/nonexistent/server.crt".into(),
+            key: "/nonexistent/server.key".into(),
+            ..TlsConfig::default()
+        };
+
+        assert!(TlsServer::new(&config).is_err());
+    }
+}